@@ -0,0 +1,420 @@
+use std::path::{Component,Path,PathBuf};
+use std::fs;
+use std::io::{BufReader,BufWriter,Read,Write,Error,ErrorKind};
+use std::process::{Command,Stdio};
+use std::collections::HashMap;
+use crate::config::Filter;
+use crate::remote::{self,FrameReader,FrameWriter,STATUS_OK};
+use crate::storage::{self,ObjectKind,ObjectMeta};
+
+// ----- Protocol --------------------------------------------------------------
+
+// Frames are the same as a remote destination's.  The client greets the agent with the
+// path to back up, its filters and the change detection methods it accepts, best first.  The agent
+// picks one, then walks the tree and sends an entry frame for everything not filtered, followed by
+// an empty frame.  The client asks for the contents of each file which changed, which arrive as
+// data frames followed by an empty frame, and finally quits.
+
+/// Bumped whenever a frame changes.
+const VERSION: u32 = 1;
+
+const HELLO: u8 = 0;
+const FETCH: u8 = 1;
+const QUIT:  u8 = 2;
+
+/// Change detection methods the agent knows, best first.
+const METHODS: [&str; 2] = ["checksum", "timestamp"];
+
+/// Suffix of a file being fetched into the mirror.
+const PARTIAL_EXTENSION: &str = ".tsnapshot-partial";
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// A source tree on another machine, read through an agent such as `ssh host tsnapshot --agent`.
+/// The tree is mirrored into `cache_dir`, which keeps it between backups so only changed files are
+/// sent, and is then backed up from there.
+#[derive(Debug)]
+pub struct PullSource {
+    pub command:          std::string::String,
+    pub cache_dir:        PathBuf,
+    /// Preferred change detection method, either `timestamp` or `checksum`.
+    pub change_detection: std::string::String,
+    /// Filters of the root config as JSON, applied by the agent.
+    pub filters:          std::string::String,
+    /// Only log what would change in the mirror, leaving it as it is.
+    pub is_dry_run:       bool,
+}
+
+/// What a `PullSource` did to bring its mirror up to date.
+#[derive(Debug,Default)]
+pub struct PullStats {
+    pub unchanged:     u64,
+    pub fetched:       u64,
+    pub fetched_bytes: u64,
+    pub removed:       u64
+}
+
+impl PullSource {
+    /// Brings the mirror of `subpath`, relative to where the agent runs, up to date.
+    pub fn pull(&self, subpath: &Path) -> Result<PullStats, Error> {
+        log::info!("Starting agent {:?}...", self.command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut client = Client {
+            input:  BufReader::new(child.stdout.take().unwrap()),
+            output: BufWriter::new(child.stdin.take().unwrap()),
+        };
+        let result = self.sync(&mut client, subpath);
+        drop(client);
+        let status = child.wait()?;
+        let stats = result?;
+        if !status.success() {
+            return Err(Error::other(format!("Agent exited with {}.", status)))
+        }
+        log::info!("{} {:?}: {} files fetched ({} bytes), {} unchanged, {} removed.",
+                   if self.is_dry_run { "Would pull" } else { "Pulled" }, subpath, stats.fetched, stats.fetched_bytes, stats.unchanged, stats.removed);
+        Ok(stats)
+    }
+
+    fn sync(&self, client: &mut Client, subpath: &Path) -> Result<PullStats, Error> {
+        let mut methods = vec![self.change_detection.as_str()];
+        if "timestamp" != self.change_detection {
+            methods.push("timestamp");
+        }
+        let hello = methods.iter().fold(
+            FrameWriter::new(HELLO).u32(VERSION).path(subpath).bytes(self.filters.as_bytes()).u32(methods.len() as u32),
+            |hello, method| hello.bytes(method.as_bytes())
+        );
+        client.send(hello)?;
+        let response = client.receive()?;
+        let method = std::string::String::from_utf8_lossy(FrameReader::new(&response).bytes()?).into_owned();
+        log::debug!("Agent detects changes by {}.", method);
+        let mut entries = vec![];
+        loop {
+            let response = client.receive()?;
+            let mut reader = FrameReader::new(&response);
+            if 0 == reader.u8()? {
+                break
+            }
+            let path = checked_path(subpath, reader.path()?)?;
+            let meta = reader.meta()?;
+            let target = if meta.is_symlink() { Some(reader.path()?) } else { None };
+            let checksum = reader.bytes()?.to_vec();
+            entries.push(Entry { path, meta, target, checksum });
+        }
+        let mut stats = PullStats::default();
+        let kinds = entries.iter().map(|entry| (entry.path.clone(), entry.meta.kind)).collect::<HashMap<_, _>>();
+        self.prune(&self.cache_dir.join(subpath), subpath, &kinds, &mut stats)?;
+        let mut directories = vec![];
+        for entry in &entries {
+            let local_path = self.cache_dir.join(&entry.path);
+            match entry.meta.kind {
+                ObjectKind::Directory => {
+                    if self.is_dry_run {
+                        if !local_path.is_dir() {
+                            log::info!("Would create {:?} in the mirror.", entry.path);
+                        }
+                        continue
+                    }
+                    fs::create_dir_all(&local_path)?;
+                    directories.push((local_path, &entry.meta));
+                },
+                ObjectKind::Symlink   => {
+                    let target = entry.target.as_ref().unwrap();
+                    if fs::read_link(&local_path).ok().as_ref() == Some(target) {
+                        continue
+                    }
+                    if self.is_dry_run {
+                        log::info!("Would link {:?} to {:?} in the mirror.", entry.path, target);
+                        continue
+                    }
+                    remove_if_present(&local_path)?;
+                    symlink::symlink_auto(target, &local_path)?;
+                },
+                ObjectKind::File      => {
+                    if !has_changed(&local_path, entry, &method)? {
+                        stats.unchanged += 1;
+                    }
+                    else if self.is_dry_run {
+                        log::info!("Would fetch {:?} ({} bytes).", entry.path, entry.meta.len);
+                        stats.fetched += 1;
+                        stats.fetched_bytes += entry.meta.len;
+                        continue
+                    }
+                    else {
+                        match client.fetch(&entry.path, &local_path) {
+                            Ok(len)  => {
+                                stats.fetched += 1;
+                                stats.fetched_bytes += len;
+                            },
+                            Err(err) if err.kind() == ErrorKind::NotFound => {
+                                log::warn!("{:?} vanished before it could be fetched.", entry.path);
+                                remove_if_present(&local_path)?;
+                                continue
+                            },
+                            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                                log::warn!("{:?} could not be read, so it is skipped.", entry.path);
+                                remove_if_present(&local_path)?;
+                                continue
+                            },
+                            Err(err) => return Err(err)
+                        }
+                    }
+                    if !self.is_dry_run {
+                        storage::set_metadata(&local_path, &entry.meta)?;
+                    }
+                },
+                ObjectKind::Special   => log::warn!("Skipping special file {:?}.", entry.path)
+            }
+        }
+        // Directories are listed before their contents, which change their modification times.
+        for (path, meta) in directories.iter().rev() {
+            storage::set_metadata(path, meta)?;
+        }
+        client.send(FrameWriter::new(QUIT))?;
+        client.receive()?;
+        Ok(stats)
+    }
+
+    /// Removes everything under `path` in the mirror which the agent no longer sent.
+    fn prune(&self, local_path: &Path, path: &Path, kinds: &HashMap<PathBuf, ObjectKind>, stats: &mut PullStats)
+             -> Result<(), Error> {
+        let meta = match local_path.symlink_metadata() {
+            Ok(meta)                                      => ObjectMeta::from(&meta),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err)                                      => return Err(err)
+        };
+        if kinds.get(path) != Some(&meta.kind) {
+            stats.removed += 1;
+            if self.is_dry_run {
+                log::info!("Would remove {:?} from the mirror.", path);
+                return Ok(())
+            }
+            log::debug!("Removing {:?} from the mirror.", path);
+            return if meta.is_dir() { fs::remove_dir_all(local_path) } else { fs::remove_file(local_path) }
+        }
+        if meta.is_dir() {
+            for entry_res in fs::read_dir(local_path)? {
+                let name = entry_res?.file_name();
+                self.prune(&local_path.join(&name), &path.join(&name), kinds, stats)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// ----- Client ----------------------------------------------------------------
+
+/// A file, directory or link the agent found.
+struct Entry {
+    path:     PathBuf,
+    meta:     ObjectMeta,
+    target:   Option<PathBuf>,
+    checksum: Vec<u8>
+}
+
+struct Client {
+    input:  BufReader<std::process::ChildStdout>,
+    output: BufWriter<std::process::ChildStdin>,
+}
+
+impl Client {
+    fn send(&mut self, request: FrameWriter) -> Result<(), Error> {
+        remote::write_frame(&mut self.output, &request.0)
+    }
+
+    fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        remote::check_response(remote::read_frame(&mut self.input)?
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Agent hung up."))?)
+    }
+
+    /// Copies `path` into `local_path`, returning its length.
+    fn fetch(&mut self, path: &Path, local_path: &Path) -> Result<u64, Error> {
+        log::debug!("Fetching {:?}...", path);
+        self.send(FrameWriter::new(FETCH).path(path))?;
+        let mut partial_name = local_path.as_os_str().to_os_string();
+        partial_name.push(PARTIAL_EXTENSION);
+        let partial_path = PathBuf::from(partial_name);
+        let mut file = BufWriter::new(fs::File::create(&partial_path)?);
+        let mut len = 0;
+        let result = loop {
+            let response = match self.receive() {
+                Ok(response) => response,
+                Err(err)     => break Err(err)
+            };
+            let data = FrameReader::new(&response).bytes()?;
+            if data.is_empty() {
+                break file.flush()
+            }
+            len += data.len() as u64;
+            file.write_all(data)?;
+        };
+        drop(file);
+        match result {
+            Ok(())   => fs::rename(&partial_path, local_path).map(|()| len),
+            Err(err) => {
+                let _ = fs::remove_file(&partial_path);
+                Err(err)
+            }
+        }
+    }
+}
+
+fn has_changed(local_path: &Path, entry: &Entry, method: &str) -> Result<bool, Error> {
+    let meta = match local_path.symlink_metadata() {
+        Ok(meta) if meta.file_type().is_file()        => meta,
+        Ok(..)                                        => return Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(err)                                      => return Err(err)
+    };
+    if meta.len() != entry.meta.len {
+        return Ok(true)
+    }
+    match method {
+        "checksum" => Ok(hash_file(local_path)? != entry.checksum),
+        _          => Ok(meta.modified().ok() != entry.meta.modified)
+    }
+}
+
+/// Refuses paths outside of `subpath`, whether the agent sent them for the mirror or the client
+/// asked the agent for them.
+fn checked_path(subpath: &Path, path: PathBuf) -> Result<PathBuf, Error> {
+    if !path.starts_with(subpath) || path.components().any(|component| Component::ParentDir == component) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{:?} is outside of {:?}.", path, subpath)))
+    }
+    Ok(path)
+}
+
+fn remove_if_present(path: &Path) -> Result<(), Error> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_dir()                     => fs::remove_dir_all(path),
+        Ok(..)                                        => fs::remove_file(path),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err)                                      => Err(err)
+    }
+}
+
+fn hash_file(path: &Path) -> Result<Vec<u8>, Error> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+// ----- Agent -----------------------------------------------------------------
+
+/// Answers a `PullSource` on `input` and `output` until it quits or hangs up.
+pub fn serve(input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+    let mut agent = Agent {
+        subpath: None,
+        method:  "timestamp",
+    };
+    while let Some(request) = remote::read_frame(input)? {
+        let is_quit = request.first() == Some(&QUIT);
+        if let Err(err) = agent.handle(&request, output) {
+            remote::write_frame(output, &remote::error_response(&err).0)?;
+        }
+        if is_quit {
+            break
+        }
+    }
+    Ok(())
+}
+
+struct Agent {
+    subpath: Option<PathBuf>,
+    method:  &'static str,
+}
+
+impl Agent {
+    fn handle(&mut self, request: &[u8], output: &mut dyn Write) -> Result<(), Error> {
+        let mut reader = FrameReader::new(request);
+        match reader.u8()? {
+            HELLO => {
+                let version = reader.u32()?;
+                if VERSION != version {
+                    return Err(Error::new(ErrorKind::InvalidInput,
+                                          format!("Protocol version {} is not supported, expected {}.", version, VERSION)))
+                }
+                let subpath = reader.path()?;
+                let filters_str = std::string::String::from_utf8_lossy(reader.bytes()?).into_owned();
+                let filters = json::parse(&filters_str)
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("{:?}", err)))
+                    .and_then(|json| Filter::new_vec(Some(&json))
+                              .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("{:?}", err))))?;
+                let mut wanted = vec![];
+                for _ in 0 .. reader.u32()? {
+                    wanted.push(std::string::String::from_utf8_lossy(reader.bytes()?).into_owned());
+                }
+                self.method = wanted.iter()
+                    .find_map(|method| METHODS.iter().find(|known| *known == method))
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput,
+                                              format!("None of {:?} are supported.", wanted)))?;
+                remote::write_frame(output, &FrameWriter::new(STATUS_OK).bytes(self.method.as_bytes()).0)?;
+                self.walk(&subpath, &subpath, &filters, output)?;
+                self.subpath = Some(subpath);
+                remote::write_frame(output, &FrameWriter::new(STATUS_OK).u8(0).0)
+            },
+            FETCH => {
+                let subpath = self.subpath.as_ref()
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Expected a greeting first."))?;
+                let path = checked_path(subpath, reader.path()?)?;
+                let mut file = fs::File::open(&path)?;
+                let mut buffer = vec![0u8; 1 << 20];
+                loop {
+                    let len = file.read(&mut buffer)?;
+                    remote::write_frame(output, &FrameWriter::new(STATUS_OK).bytes(&buffer[.. len]).0)?;
+                    if 0 == len {
+                        return Ok(())
+                    }
+                }
+            },
+            QUIT  => remote::write_frame(output, &FrameWriter::new(STATUS_OK).0),
+            tag   => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown request {}.", tag)))
+        }
+    }
+
+    /// Sends an entry for `path` and everything under it which no filter matches.  `root` is the
+    /// subpath the walk started at.  Entries which vanish or cannot be read are skipped, since a
+    /// live tree always has some.
+    fn walk(&self, path: &Path, root: &Path, filters: &[Filter], output: &mut dyn Write) -> Result<(), Error> {
+        match self.walk_entry(path, root, filters, output) {
+            Err(err) if ErrorKind::NotFound == err.kind() || ErrorKind::PermissionDenied == err.kind() => {
+                // stdout carries the protocol and the agent has no logger, so this goes
+                // to stderr, which the client shares.
+                eprintln!("Agent skipped {:?}: {}.", path, err);
+                Ok(())
+            },
+            result => result
+        }
+    }
+
+    fn walk_entry(&self, path: &Path, root: &Path, filters: &[Filter], output: &mut dyn Write) -> Result<(), Error> {
+        let meta = path.symlink_metadata()?;
+        if filters.iter().any(|filter| filter.matches(path, root, &meta)) {
+            log::debug!("Skipping {:?} because it matches a filter.", path);
+            return Ok(())
+        }
+        let mut entry = FrameWriter::new(STATUS_OK).u8(1).path(path).meta(&ObjectMeta::from(&meta));
+        if meta.file_type().is_symlink() {
+            entry = entry.path(&fs::read_link(path)?);
+        }
+        entry = if meta.file_type().is_file() && "checksum" == self.method {
+            entry.bytes(&hash_file(path)?)
+        }
+        else {
+            entry.bytes(&[])
+        };
+        // A directory which cannot be read is skipped before it is sent, rather than mirrored empty.
+        let children = if meta.file_type().is_dir() { Some(fs::read_dir(path)?) } else { None };
+        remote::write_frame(output, &entry.0)?;
+        for entry_res in children.into_iter().flatten() {
+            self.walk(&entry_res?.path(), root, filters, output)?;
+        }
+        Ok(())
+    }
+}
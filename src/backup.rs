@@ -1,14 +1,68 @@
 pub mod tar;
 pub mod copy;
 pub mod hardlink;
+pub mod chunk;
+pub mod incremental;
+pub mod filecompress;
+pub mod dedup;
+pub mod delta;
 
 use std::path::*;
+use crate::storage::Storage;
+
+/// Log target of the statistics a space mode reports once it is done, which `tsnapshot --stats`
+/// shows at any verbosity.
+pub const STATS_TARGET: &str = "tsnapshot::stats";
 
 pub trait BackupOutputStream {
     fn append_file(&mut self, src: &Path) -> Result<(), std::io::Error>;
+
+    /// Streams which can be appended to from several threads at once return themselves here.
+    /// Everything else is fed in walk order by a single thread.
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentOutputStream> { None }
+}
+
+pub trait ConcurrentOutputStream: Sync {
+    /// Like `BackupOutputStream::append_file`, but safe to call from several threads at once.
+    /// Directories are always appended before anything inside them.
+    fn append_file_concurrently(&self, src: &Path) -> Result<(), std::io::Error>;
+}
+
+/// Lets a concurrent stream be handed to anything expecting a `BackupOutputStream`.
+pub struct SharedOutputStream<'a>(pub &'a dyn ConcurrentOutputStream);
+
+impl <'a> BackupOutputStream for SharedOutputStream<'a> {
+    fn append_file(&mut self, src: &Path) -> Result<(), std::io::Error> {
+        self.0.append_file_concurrently(src)
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentOutputStream> { Some(self.0) }
+}
+
+/// Every file outside of `snapshot` that something inside it was based on, such as the listing
+/// of an incremental archive or the base of a delta.  Those snapshots are needed to restore this
+/// one.
+pub fn dependencies(storage: &dyn Storage, snapshot: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let meta = match storage.metadata(snapshot)? {
+        Some(meta) => meta,
+        None       => return Ok(vec![])
+    };
+    let mut bases = vec![];
+    if meta.is_dir() {
+        for entry_path in storage.read_dir(snapshot)? {
+            bases.append(&mut dependencies(storage, &entry_path)?);
+        }
+    }
+    else if incremental::is_listing(snapshot) {
+        bases.extend(incremental::SnapshotFile::read_header(storage, snapshot)?.base);
+    }
+    else if delta::is_delta(storage, snapshot) {
+        bases.push(delta::base_of(storage, snapshot)?);
+    }
+    Ok(bases)
 }
 
-fn append_path(dst: &Path, src: &Path) -> PathBuf { return dst.join(as_relative(src)) }
+pub(crate) fn append_path(dst: &Path, src: &Path) -> PathBuf { return dst.join(as_relative(src)) }
 
 fn as_relative(path: &Path) -> PathBuf {
     if path.is_relative() {
@@ -0,0 +1,349 @@
+use std::path::{Path,PathBuf};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead,BufReader,BufWriter,Read,Write,Error,ErrorKind};
+use std::sync::Arc;
+use crate::backup::{self, BackupOutputStream};
+use crate::compression::{Compressor,CompressorSink};
+use crate::storage::Storage;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Name of the chunk store directory inside `destination_dir`.
+pub const STORE_DIR: &str = "chunks";
+/// Extension of the per-snapshot tree index written by the chunked space mode.
+pub const INDEX_EXTENSION: &str = ".chunks";
+
+const INDEX_HEADER: &str = "tsnapshot-chunks 1";
+
+/// Content-addressed store of file chunks, shared by every snapshot in a destination.
+/// Chunks live in `<root>/<first two hex digits>/<sha256>`.
+pub struct ChunkStore {
+    storage: Arc<dyn Storage>,
+    root:    PathBuf
+}
+
+impl ChunkStore {
+    /// The store is kept in `root` within `storage`.
+    pub fn new(storage: Arc<dyn Storage>, root: PathBuf) -> ChunkStore {
+        ChunkStore {
+            storage,
+            root
+        }
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[0..2]).join(hash)
+    }
+
+    /// Stores `data` unless an identical chunk is already present and returns its hash.
+    pub fn insert(&self, data: &[u8]) -> Result<String, Error> {
+        use sha2::Digest;
+        let hash: String = sha2::Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect();
+        let path = self.chunk_path(&hash);
+        if self.storage.exists(&path)? {
+            log::debug!("Chunk {} already stored.", hash);
+        }
+        else {
+            log::debug!("Storing new chunk {} ({} bytes).", hash, data.len());
+            let dir = path.parent().unwrap();
+            self.storage.create_dir_all(dir)?;
+            // Write to a temporary name first so an interrupted backup never leaves a truncated chunk.
+            let tmp_path = dir.join(format!(".{}.tmp", hash));
+            self.storage.write(&tmp_path, data)?;
+            self.storage.rename(&tmp_path, &path)?;
+        }
+        Ok(hash)
+    }
+
+    pub fn read_into(&self, hash: &str, out: &mut dyn Write) -> Result<u64, Error> {
+        if hash.len() < 2 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Invalid chunk hash {:?}.", hash)));
+        }
+        let mut file = self.storage.open(&self.chunk_path(hash))?;
+        std::io::copy(&mut file, out)
+    }
+
+    /// Copies every chunk referenced by an index in `snapshot` which `other` is missing into it.
+    /// Returns the number of chunks copied.
+    pub fn copy_to(&self, snapshot: &Path, other: &ChunkStore) -> Result<u64, Error> {
+        let mut referenced = HashSet::new();
+        collect_references(self.storage.as_ref(), snapshot, &mut referenced)?;
+        let mut copied = 0;
+        for hash in referenced {
+            if other.storage.exists(&other.chunk_path(&hash))? {
+                continue
+            }
+            let mut data = vec![];
+            self.read_into(&hash, &mut data)?;
+            other.insert(&data)?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    /// Removes every chunk not referenced by an index in one of `snapshots`.
+    /// Returns the number of chunks removed.
+    pub fn collect_garbage(&self, snapshots: &[&Path]) -> Result<u64, Error> {
+        let storage = self.storage.as_ref();
+        if !storage.metadata(&self.root)?.is_some_and(|meta| meta.is_dir()) {
+            return Ok(0)
+        }
+        let mut referenced = HashSet::new();
+        for snapshot in snapshots {
+            log::debug!("Collecting chunks referenced by {:?}...", snapshot);
+            collect_references(storage, snapshot, &mut referenced)?;
+        }
+        let mut removed = 0;
+        for prefix in storage.read_dir(&self.root)? {
+            if !storage.metadata(&prefix)?.is_some_and(|meta| meta.is_dir()) {
+                continue
+            }
+            for chunk in storage.read_dir(&prefix)? {
+                let is_referenced = chunk.file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| referenced.contains(name))
+                    .unwrap_or(false);
+                if !is_referenced {
+                    log::debug!("Removing unreferenced chunk {:?}.", chunk);
+                    storage.remove_file(&chunk)?;
+                    removed += 1;
+                }
+            }
+            if storage.read_dir(&prefix)?.is_empty() {
+                storage.remove_dir(&prefix)?;
+            }
+        }
+        log::info!("Removed {} unreferenced chunks from {:?}.", removed, self.root);
+        Ok(removed)
+    }
+}
+
+pub struct ChunkedOutputStream<'a> {
+    store:     &'a ChunkStore,
+    index:     BufWriter<CompressorSink>,
+    min_size:  usize,
+    max_size:  usize,
+    mask:      u64,
+}
+
+impl <'a> ChunkedOutputStream<'a> {
+    /// `avg_size` is rounded up to a power of two.  Chunks are cut between a quarter and four
+    /// times that size.
+    pub fn new(index_file: Box<dyn Compressor>, store: &'a ChunkStore, avg_size: u64)
+               -> Result<ChunkedOutputStream<'a>, Error> {
+        let avg_size = avg_size.max(64).next_power_of_two();
+        let mut index = BufWriter::new(CompressorSink(index_file));
+        writeln!(index, "{}", INDEX_HEADER)?;
+        Ok(ChunkedOutputStream {
+            store,
+            index,
+            min_size:  (avg_size / 4) as usize,
+            max_size:  (avg_size * 4) as usize,
+            mask:      avg_size - 1,
+        })
+    }
+
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.index.flush()?;
+        self.index.get_mut().0.close()
+    }
+
+    fn store_file(&self, src: &Path) -> Result<Vec<String>, Error> {
+        let mut reader = BufReader::new(fs::File::open(src)?);
+        let mut hashes = vec![];
+        let mut chunk = Vec::with_capacity(self.max_size);
+        let mut hash: u64 = 0;
+        let mut buf = [0; 64 * 1024];
+        loop {
+            let len = reader.read(&mut buf)?;
+            if 0 == len {
+                break
+            }
+            for byte in &buf[0..len] {
+                chunk.push(*byte);
+                hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+                if (chunk.len() >= self.min_size && hash & self.mask == 0) || chunk.len() >= self.max_size {
+                    hashes.push(self.store.insert(&chunk)?);
+                    chunk.clear();
+                    hash = 0;
+                }
+            }
+        }
+        if !chunk.is_empty() {
+            hashes.push(self.store.insert(&chunk)?);
+        }
+        Ok(hashes)
+    }
+}
+
+impl <'a> BackupOutputStream for ChunkedOutputStream<'a> {
+    fn append_file(&mut self, src: &Path) -> Result<(), Error> {
+        let meta = src.symlink_metadata()?;
+        let (typ, data) = if meta.file_type().is_symlink() {
+            log::debug!("Indexing symlink {:?}.", src);
+            let target = fs::read_link(src)?;
+            let target_str = index_str(&target)?;
+            if target_str.contains('\t') {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("Cannot index symlink target {:?} containing a tab.", target)))
+            }
+            ('l', target_str.to_string())
+        }
+        else if meta.file_type().is_dir() {
+            log::debug!("Indexing directory {:?}.", src);
+            ('d', std::string::String::new())
+        }
+        else if meta.file_type().is_file() {
+            log::debug!("Chunking file {:?}.", src);
+            ('f', self.store_file(src)?.join(","))
+        }
+        else {
+            // Special files have no representation in the index.
+            log::warn!("Skipping special file {:?}.", src);
+            return Ok(())
+        };
+        let mtime = meta.modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(Error::other)?
+            .as_secs();
+        use std::os::unix::fs::MetadataExt;
+        writeln!(self.index, "{}\t{:o}\t{}\t{}\t{}", typ, meta.mode() & 0o7777, mtime, data,
+                 index_str(&backup::as_relative(src))?)
+    }
+}
+
+/// Rebuilds the tree described by the chunk index `index_path` underneath `dst_path`.
+pub fn restore_index(index_path: &Path, store: &ChunkStore, dst_path: &Path) -> Result<(), Error> {
+    let reader = BufReader::new(store.storage.open(index_path)?);
+    let mut lines = reader.lines();
+    match lines.next() {
+        Some(Ok(header)) if header == INDEX_HEADER => {},
+        Some(Err(err))                             => return Err(err),
+        _                                          => return Err(Error::new(
+            ErrorKind::InvalidData, format!("{:?} is not a chunk index.", index_path)))
+    };
+    // Directory times are applied last since restoring their contents changes them.
+    let mut directories = vec![];
+    for line_res in lines {
+        let line = line_res?;
+        let entry = IndexEntry::parse(&line)?;
+        let output_path = dst_path.join(entry.path);
+        match entry.typ {
+            "d" => {
+                log::info!("Creating {:?}", output_path);
+                fs::create_dir_all(&output_path)?;
+                directories.push((output_path, entry.mode, entry.mtime));
+            },
+            "l" => {
+                log::info!("Linking {:?} to {:?}", output_path, entry.data);
+                symlink::symlink_auto(entry.data, &output_path)?;
+            },
+            "f" => {
+                log::info!("Reassembling {:?}", output_path);
+                let mut out = BufWriter::new(fs::File::create(&output_path)?);
+                for hash in entry.data.split(',').filter(|hash| !hash.is_empty()) {
+                    store.read_into(hash, &mut out)?;
+                }
+                out.flush()?;
+                set_metadata(&output_path, entry.mode, entry.mtime)?;
+            },
+            typ => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown index entry type {:?}.", typ)))
+        }
+    }
+    for (path, mode, mtime) in directories.iter().rev() {
+        set_metadata(path, *mode, *mtime)?;
+    }
+    Ok(())
+}
+
+// ----- Index Parsing Functions -----------------------------------------------
+
+struct IndexEntry<'a> {
+    typ:   &'a str,
+    mode:  u32,
+    mtime: i64,
+    data:  &'a str,
+    path:  &'a str,
+}
+
+impl <'a> IndexEntry<'a> {
+    fn parse(line: &'a str) -> Result<IndexEntry<'a>, Error> {
+        let bad_line = || Error::new(ErrorKind::InvalidData, format!("Malformed index line {:?}.", line));
+        // The path is last so it may contain tabs.
+        let mut fields = line.splitn(5, '\t');
+        let mut next = || fields.next().ok_or_else(bad_line);
+        Ok(IndexEntry {
+            typ:   next()?,
+            mode:  u32::from_str_radix(next()?, 8).map_err(|_| bad_line())?,
+            mtime: next()?.parse().map_err(|_| bad_line())?,
+            data:  next()?,
+            path:  next()?,
+        })
+    }
+}
+
+fn collect_references(storage: &dyn Storage, path: &Path, referenced: &mut HashSet<std::string::String>)
+                      -> Result<(), Error> {
+    let meta = match storage.metadata(path)? {
+        Some(meta) => meta,
+        None       => return Ok(())
+    };
+    if meta.is_dir() {
+        for entry_path in storage.read_dir(path)? {
+            collect_references(storage, &entry_path, referenced)?;
+        }
+    }
+    else if is_index(path) {
+        let mut lines = BufReader::new(storage.open(path)?).lines().skip(1);
+        while let Some(line) = lines.next().transpose()? {
+            let entry = IndexEntry::parse(&line)?;
+            if "f" == entry.typ {
+                referenced.extend(entry.data.split(',').filter(|hash| !hash.is_empty()).map(str::to_string));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `path` names a chunk index written by `ChunkedOutputStream`.
+pub fn is_index(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("tsnapshot-") && name.ends_with(INDEX_EXTENSION))
+        .unwrap_or(false)
+}
+
+fn index_str(path: &Path) -> Result<&str, Error> {
+    let string = path.to_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Filename not representable as str."))?;
+    if string.contains('\n') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Cannot index {:?} containing a newline.", path)))
+    }
+    Ok(string)
+}
+
+fn set_metadata(path: &Path, mode: u32, mtime: i64) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(mtime, 0))
+}
+
+// ----- Chunking Implementation -----------------------------------------------
+
+lazy_static! {
+    /// Random values for the gear rolling hash used to find content-defined chunk boundaries.
+    /// Generated with splitmix64 so boundaries are stable between runs and versions.
+    static ref GEAR: [u64; 256] = {
+        let mut table = [0; 256];
+        let mut state: u64 = 0x7473_6e61_7073_686f;
+        for value in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *value = z ^ (z >> 31);
+        }
+        table
+    };
+}
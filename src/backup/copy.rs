@@ -1,16 +1,19 @@
 use std::path::Path;
 use std::fs;
-use crate::backup::{self,BackupOutputStream};
+use crate::backup::{self,BackupOutputStream,ConcurrentOutputStream};
+use crate::storage::Storage;
 
 // ----- Public Data Structures ------------------------------------------------
 
 pub struct CopyOutputStream<'a> {
+    pub storage:       &'a dyn Storage,
     pub output_dir:    &'a Path,
 }
 
 impl <'a> CopyOutputStream<'a> {
-    pub fn new(output_dir: &'a Path) -> CopyOutputStream<'a> {
+    pub fn new(storage: &'a dyn Storage, output_dir: &'a Path) -> CopyOutputStream<'a> {
         return CopyOutputStream {
+            storage,
             output_dir:    output_dir,
         }
     }
@@ -18,23 +21,35 @@ impl <'a> CopyOutputStream<'a> {
 
 impl <'a> BackupOutputStream for CopyOutputStream<'a> {
     fn append_file(&mut self, src: &Path) -> Result<(), std::io::Error> {
+        self.append_file_concurrently(src)
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentOutputStream> { Some(self) }
+}
+
+impl <'a> ConcurrentOutputStream for CopyOutputStream<'a> {
+    fn append_file_concurrently(&self, src: &Path) -> Result<(), std::io::Error> {
         let meta = src.symlink_metadata()?;
         let output_file = backup::append_path(self.output_dir, src);
         if meta.file_type().is_symlink() {
             log::debug!("Backing up symlink {:?} to {:?}.", src, self.output_dir);
-            symlink::symlink_auto(output_file, fs::read_link(src)?)?;
+            self.storage.symlink(&fs::read_link(src)?, &output_file)?;
         }
         else if meta.file_type().is_dir() {
             log::debug!("Backing up directory {:?} to {:?}.", src, self.output_dir);
-            fs::create_dir(&output_file)?;
+            self.storage.create_dir(&output_file)?;
         }
         else if meta.file_type().is_file() {
             log::debug!("Backing up file {:?} to {:?}.", src, self.output_dir);
-            fs::copy(src, output_file)?;
+            let mut output = self.storage.create(&output_file)?;
+            std::io::copy(&mut fs::File::open(src)?, output.writer())?;
+            output.close()?;
+            use std::os::unix::fs::MetadataExt;
+            self.storage.set_permissions(&output_file, meta.mode() & 0o7777)?;
         }
         else {
-            // [tflucke] 2021-12-24: Unknown type.  Probably added after this was written.
-            unimplemented!("Unknown entry type detected {:?}.", src);
+            // Special files, such as FIFOs and sockets, have no copy.
+            log::warn!("Skipping special file {:?}.", src);
         }
         return Ok(());
     }
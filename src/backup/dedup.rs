@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::io::Error;
+use rayon::prelude::*;
+use crate::storage::Storage;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// What `deduplicate` did to a backup tree.
+#[derive(Debug,Default)]
+pub struct DedupStats {
+    pub linked:      u64,
+    pub bytes_saved: u64
+}
+
+/// Hard links identical regular files under `root` together.  Files are grouped by size and
+/// everything a hard link shares, such as permissions and owner, and only then hashed.  `skip`
+/// is given paths relative to `root`, and skipped paths are not searched.
+pub fn deduplicate(storage: &dyn Storage, root: &Path, skip: &dyn Fn(&Path) -> bool) -> Result<DedupStats, Error> {
+    let mut groups = HashMap::new();
+    collect(storage, root, root, skip, &mut groups)?;
+    let mut stats = DedupStats::default();
+    // Groups which are all one file already have nothing to link.
+    for (key, files) in groups.into_iter().filter(|(_, files)| files.iter().any(|file| file.ino != files[0].ino)) {
+        log::debug!("Hashing {} files of {} bytes...", files.len(), key.size);
+        let hashes = files.par_iter()
+            .map(|file| hash_file(storage, &file.path))
+            .collect::<Result<Vec<String>, Error>>()?;
+        let mut originals: HashMap<String, &Candidate> = HashMap::new();
+        // Links replaced per inode, to know which inodes no longer take up any space.
+        let mut replaced: HashMap<u64, (u64, u64)> = HashMap::new();
+        for (file, hash) in files.iter().zip(hashes) {
+            let original = match originals.get(&hash) {
+                Some(original) => original,
+                None           => {
+                    originals.insert(hash, file);
+                    continue
+                }
+            };
+            if original.ino == file.ino {
+                continue
+            }
+            if let Err(err) = replace_with_link(storage, &original.path, &file.path) {
+                log::warn!("Could not link {:?} to its duplicate {:?}: {:?}", file.path, original.path, err);
+                continue
+            }
+            log::debug!("Linked {:?} to its duplicate {:?}.", file.path, original.path);
+            stats.linked += 1;
+            replaced.entry(file.ino).or_insert((file.nlink, 0)).1 += 1;
+        }
+        stats.bytes_saved += replaced.values().filter(|(nlink, count)| nlink == count).count() as u64 * key.size;
+    }
+    Ok(stats)
+}
+
+// ----- Implementation --------------------------------------------------------
+
+/// Everything files must have in common to be linked together, including their file system.
+#[derive(Debug,Hash,PartialEq,Eq)]
+struct GroupKey {
+    dev:  u64,
+    size: u64,
+    mode: u32,
+    uid:  u32,
+    gid:  u32
+}
+
+#[derive(Debug)]
+struct Candidate {
+    path:  PathBuf,
+    ino:   u64,
+    nlink: u64
+}
+
+fn collect(storage: &dyn Storage, root: &Path, path: &Path, skip: &dyn Fn(&Path) -> bool,
+           groups: &mut HashMap<GroupKey, Vec<Candidate>>) -> Result<(), Error> {
+    for entry_path in storage.read_dir(path)? {
+        if skip(entry_path.strip_prefix(root).unwrap()) {
+            log::debug!("Not deduplicating {:?}.", entry_path);
+            continue
+        }
+        let meta = match storage.metadata(&entry_path)? {
+            Some(meta) => meta,
+            None       => continue
+        };
+        if meta.is_dir() {
+            collect(storage, root, &entry_path, skip, groups)?;
+        }
+        // Empty files have nothing to save.
+        else if meta.is_file() && 0 < meta.len {
+            groups.entry(GroupKey {
+                dev:  meta.dev,
+                size: meta.len,
+                mode: meta.mode,
+                uid:  meta.uid,
+                gid:  meta.gid
+            }).or_default().push(Candidate {
+                path:  entry_path,
+                ino:   meta.ino,
+                nlink: meta.nlink
+            });
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(storage: &dyn Storage, path: &Path) -> Result<String, Error> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut storage.open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Replaces `path` with a hard link to `original` without ever leaving `path` missing.
+fn replace_with_link(storage: &dyn Storage, original: &Path, path: &Path) -> Result<(), Error> {
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tsnapshot-dedup");
+    let tmp_path = PathBuf::from(tmp_name);
+    storage.hard_link(original, &tmp_path)?;
+    storage.rename(&tmp_path, path).inspect_err(|_| {
+        let _ = storage.remove_file(&tmp_path);
+    })
+}
@@ -0,0 +1,551 @@
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::fs;
+use std::io::{BufRead,BufReader,BufWriter,Read,Seek,SeekFrom,Write,Error,ErrorKind};
+use std::sync::atomic::{AtomicU64,Ordering};
+use crate::backup::{self, BackupOutputStream, ConcurrentOutputStream};
+use crate::backup::copy::CopyOutputStream;
+use crate::storage::{Object,ObjectMeta,Storage};
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Appended to the name of a file stored as a delta against its copy in an earlier snapshot.
+pub const EXTENSION: &str = ".tsnapshot-delta";
+
+const HEADER: &str = "tsnapshot-delta 1";
+
+/// Blocks are about the square root of the file size, like rsync, so the signature of a large
+/// file stays small.  Bounds apply when no block size is configured.
+const MIN_BLOCK_SIZE: u64 = 2048;
+const MAX_BLOCK_SIZE: u64 = 128 * 1024;
+
+/// Mirrors the directory tree like `HardLinkOutputStream`, but files which changed since
+/// `ref_dir` are stored as a binary delta against their earlier version.  A delta is found with a
+/// rolling checksum, so data which moved within the file is still matched.
+pub struct DeltaOutputStream<'a> {
+    fallback:      CopyOutputStream<'a>,
+    ref_dir:       Option<&'a Path>,
+    min_size:      u64,
+    block_size:    Option<u64>,
+    full_interval: u64,
+    linked:        AtomicU64,
+    copied:        AtomicU64,
+    deltas:        AtomicU64,
+    written_bytes: AtomicU64
+}
+
+/// What a `DeltaOutputStream` did with the files it was given.
+#[derive(Debug,Default)]
+pub struct DeltaStats {
+    pub linked:        u64,
+    pub copied:        u64,
+    pub deltas:        u64,
+    pub written_bytes: u64
+}
+
+impl <'a> DeltaOutputStream<'a> {
+    /// Files smaller than `min_size` are copied.  A full copy is stored instead of a delta once a
+    /// file's chain of deltas would reach `full_interval`.  Without a `block_size` it is picked
+    /// from the size of each file.
+    pub fn new(storage: &'a dyn Storage, output_dir: &'a Path, ref_dir: Option<&'a Path>, min_size: u64,
+               block_size: Option<u64>, full_interval: u64) -> DeltaOutputStream<'a> {
+        DeltaOutputStream {
+            fallback:      CopyOutputStream::new(storage, output_dir),
+            ref_dir,
+            min_size,
+            block_size,
+            full_interval,
+            linked:        AtomicU64::new(0),
+            copied:        AtomicU64::new(0),
+            deltas:        AtomicU64::new(0),
+            written_bytes: AtomicU64::new(0)
+        }
+    }
+
+    pub fn stats(&self) -> DeltaStats {
+        DeltaStats {
+            linked:        self.linked.load(Ordering::Relaxed),
+            copied:        self.copied.load(Ordering::Relaxed),
+            deltas:        self.deltas.load(Ordering::Relaxed),
+            written_bytes: self.written_bytes.load(Ordering::Relaxed)
+        }
+    }
+
+    /// The earlier version of `src`, either a full copy or a delta, with its size and chain depth.
+    fn reference(&self, src: &Path) -> Result<Option<Reference>, Error> {
+        let ref_path = match self.ref_dir {
+            Some(ref_dir) => backup::append_path(ref_dir, src),
+            None          => return Ok(None)
+        };
+        let storage = self.fallback.storage;
+        if let Some(meta) = storage.metadata(&ref_path)? {
+            if meta.is_file() {
+                return Ok(Some(Reference { size: meta.len, depth: 0, path: ref_path, meta }))
+            }
+            return Ok(None)
+        }
+        let delta_path = with_extension(&ref_path);
+        match storage.metadata(&delta_path)? {
+            Some(meta) if meta.is_file() => {
+                let header = DeltaHeader::read(&mut BufReader::new(storage.open(&delta_path)?))?;
+                Ok(Some(Reference { size: header.size, depth: header.depth, path: delta_path, meta }))
+            },
+            _                            => Ok(None)
+        }
+    }
+
+    fn copy(&self, src: &Path, meta: &fs::Metadata) -> Result<(), Error> {
+        self.copied.fetch_add(1, Ordering::Relaxed);
+        self.written_bytes.fetch_add(meta.len(), Ordering::Relaxed);
+        self.fallback.append_file_concurrently(src)
+    }
+}
+
+impl <'a> BackupOutputStream for DeltaOutputStream<'a> {
+    fn append_file(&mut self, src: &Path) -> Result<(), Error> {
+        self.append_file_concurrently(src)
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentOutputStream> { Some(self) }
+}
+
+impl <'a> ConcurrentOutputStream for DeltaOutputStream<'a> {
+    fn append_file_concurrently(&self, src: &Path) -> Result<(), Error> {
+        let meta = src.symlink_metadata()?;
+        if !meta.is_file() {
+            return self.fallback.append_file_concurrently(src)
+        }
+        let reference = match self.reference(src)? {
+            Some(reference) => reference,
+            None            => return self.copy(src, &meta)
+        };
+        let storage = self.fallback.storage;
+        let output_file = backup::append_path(self.fallback.output_dir, src);
+        // The reference was written after the last change to `src` unless `src` is newer.
+        let is_unchanged = reference.size == meta.len() && match (meta.modified(), reference.meta.modified) {
+            (Ok(mtime), Some(ref_mtime)) => mtime <= ref_mtime,
+            (_,         _)               => false
+        };
+        if is_unchanged {
+            log::debug!("{:?} is unchanged since {:?}.", src, reference.path);
+            self.linked.fetch_add(1, Ordering::Relaxed);
+            return match reference.depth {
+                0 => storage.hard_link(&reference.path, &output_file),
+                _ => storage.hard_link(&reference.path, &with_extension(&output_file))
+            }
+        }
+        if meta.len() < self.min_size || reference.depth + 1 >= self.full_interval {
+            return self.copy(src, &meta)
+        }
+        let block_size = self.block_size.unwrap_or_else(|| {
+            ((reference.size as f64).sqrt() as u64).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+        });
+        let delta_path = with_extension(&output_file);
+        log::debug!("Writing delta of {:?} against {:?} to {:?}...", src, reference.path, delta_path);
+        let literal_bytes = write_delta(storage, src, &reference, block_size, &delta_path)?;
+        // Mostly new data is cheaper to restore from a full copy, which also starts a new chain.
+        if literal_bytes > meta.len() / 2 {
+            log::debug!("{:?} changed too much for a delta.", src);
+            storage.remove_file(&delta_path)?;
+            return self.copy(src, &meta)
+        }
+        storage.set_permissions(&delta_path, ObjectMeta::from(&meta).mode & 0o7777)?;
+        self.deltas.fetch_add(1, Ordering::Relaxed);
+        self.written_bytes.fetch_add(storage.metadata(&delta_path)?.map(|meta| meta.len).unwrap_or(0), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Whether `path` is a file stored as a delta.
+pub fn is_delta(storage: &dyn Storage, path: &Path) -> bool {
+    path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.ends_with(EXTENSION)) &&
+        storage.open(path)
+        .and_then(|file| DeltaHeader::read(&mut BufReader::new(file)))
+        .is_ok()
+}
+
+/// The file the delta at `path` was made against, which is needed to restore it.
+pub fn base_of(storage: &dyn Storage, path: &Path) -> Result<PathBuf, Error> {
+    Ok(DeltaHeader::read(&mut BufReader::new(storage.open(path)?))?.base)
+}
+
+/// Copies the delta at `src_path` to `dst_path` in another storage, where its base is `rebase` of
+/// the original one.
+pub fn copy_rebased(src_storage: &dyn Storage, src_path: &Path, dst_storage: &dyn Storage, dst_path: &Path,
+                    rebase: &dyn Fn(&Path) -> PathBuf) -> Result<(), Error> {
+    let mut input = BufReader::new(src_storage.open(src_path)?);
+    let mut header = DeltaHeader::read(&mut input)?;
+    header.base = rebase(&header.base);
+    let mut output = dst_storage.create(dst_path)?;
+    header.write(output.writer())?;
+    std::io::copy(&mut input, output.writer())?;
+    output.close()
+}
+
+/// Rebuilds the file stored as a delta at `src_path` into `dst_path`.
+pub fn restore_file(storage: &dyn Storage, src_path: &Path, dst_path: &Path) -> Result<(), Error> {
+    let mut source = Source::open(storage, src_path)?;
+    let mut output = BufWriter::new(fs::File::create(dst_path)?);
+    let mut buffer = vec![0u8; 65536];
+    let mut offset = 0;
+    while offset < source.len() {
+        let len = buffer.len().min((source.len() - offset) as usize);
+        source.read_exact_at(offset, &mut buffer[0..len])?;
+        output.write_all(&buffer[0..len])?;
+        offset += len as u64;
+    }
+    output.flush()?;
+    let mode = storage.metadata(src_path)?.map(|meta| meta.mode & 0o7777).unwrap_or(0o644);
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(dst_path, fs::Permissions::from_mode(mode))
+}
+
+// ----- Delta Format ----------------------------------------------------------
+
+// A delta is a short text header followed by operations which rebuild the file in order:
+//   'C' <base offset: u64> <length: u64>   copies bytes from the base
+//   'D' <length: u64> <bytes>              inserts new bytes
+//   'E'                                    ends the delta
+// All numbers are little endian.
+
+struct Reference {
+    path:  PathBuf,
+    meta:  ObjectMeta,
+    size:  u64,
+    depth: u64
+}
+
+struct DeltaHeader {
+    /// Full copy or delta this delta was made against.
+    base:  PathBuf,
+    /// Number of deltas between this file and a full copy.
+    depth: u64,
+    /// Size of the rebuilt file.
+    size:  u64,
+    /// Length of the header in bytes.
+    len:   u64
+}
+
+impl DeltaHeader {
+    fn read(input: &mut dyn BufRead) -> Result<DeltaHeader, Error> {
+        let bad_header = || Error::new(ErrorKind::InvalidData, "Malformed delta header.");
+        let mut len = 0;
+        let mut line = || -> Result<std::string::String, Error> {
+            let mut line = std::string::String::new();
+            len += input.read_line(&mut line)? as u64;
+            line.pop().filter(|c| '\n' == *c).ok_or_else(bad_header)?;
+            Ok(line)
+        };
+        if line()? != HEADER {
+            return Err(bad_header())
+        }
+        let base = PathBuf::from(line()?.strip_prefix("base ").ok_or_else(bad_header)?);
+        let depth = line()?.strip_prefix("depth ").and_then(|depth| depth.parse().ok()).ok_or_else(bad_header)?;
+        let size = line()?.strip_prefix("size ").and_then(|size| size.parse().ok()).ok_or_else(bad_header)?;
+        Ok(DeltaHeader { base, depth, size, len })
+    }
+
+    fn write(&self, output: &mut dyn Write) -> Result<(), Error> {
+        let base = self.base.to_str()
+            .filter(|base| !base.contains('\n'))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("Cannot write base {:?} to a delta.", self.base)))?;
+        write!(output, "{}\nbase {}\ndepth {}\nsize {}\n", HEADER, base, self.depth, self.size)
+    }
+}
+
+enum OpKind {
+    /// Offset in the base.
+    Copy(u64),
+    /// Offset in the delta file.
+    Data(u64)
+}
+
+struct Op {
+    /// Offset in the rebuilt file.
+    target: u64,
+    len:    u64,
+    kind:   OpKind
+}
+
+/// Random access to a file which may be stored as a chain of deltas.
+enum Source {
+    Full(Box<dyn Object>, u64),
+    Delta {
+        file: Box<dyn Object>,
+        ops:  Vec<Op>,
+        base: Box<Source>,
+        size: u64
+    }
+}
+
+impl Source {
+    fn open(storage: &dyn Storage, path: &Path) -> Result<Source, Error> {
+        let mut file = storage.open(path)?;
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+        if !name.ends_with(EXTENSION) {
+            let size = file.seek(SeekFrom::End(0))?;
+            return Ok(Source::Full(file, size))
+        }
+        let mut input = BufReader::new(file);
+        let header = DeltaHeader::read(&mut input)?;
+        let mut offset = header.len;
+        let mut target = 0;
+        let mut ops = vec![];
+        loop {
+            let mut tag = [0u8; 1];
+            input.read_exact(&mut tag)?;
+            offset += 1;
+            let op = match tag[0] {
+                b'C' => {
+                    let base_offset = read_u64(&mut input)?;
+                    let len = read_u64(&mut input)?;
+                    offset += 16;
+                    Op { target, len, kind: OpKind::Copy(base_offset) }
+                },
+                b'D' => {
+                    let len = read_u64(&mut input)?;
+                    offset += 8;
+                    let op = Op { target, len, kind: OpKind::Data(offset) };
+                    std::io::copy(&mut (&mut input).take(len), &mut std::io::sink())?;
+                    offset += len;
+                    op
+                },
+                b'E' => break,
+                _    => return Err(Error::new(ErrorKind::InvalidData, format!("Malformed delta {:?}.", path)))
+            };
+            target += op.len;
+            ops.push(op);
+        }
+        if target != header.size {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Delta {:?} is truncated.", path)))
+        }
+        Ok(Source::Delta {
+            file: input.into_inner(),
+            ops,
+            base: Box::new(Source::open(storage, &header.base)?),
+            size: header.size
+        })
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Source::Full(_, size)        => *size,
+            Source::Delta { size, .. } => *size
+        }
+    }
+
+    /// Reads from `offset` up to the end of the operation it falls in.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Source::Full(file, _)                    => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.read(buf)
+            },
+            Source::Delta { file, ops, base, size } => {
+                if offset >= *size || buf.is_empty() {
+                    return Ok(0)
+                }
+                let op = &ops[ops.partition_point(|op| op.target + op.len <= offset)];
+                let skip = offset - op.target;
+                let len = buf.len().min((op.len - skip) as usize);
+                match op.kind {
+                    OpKind::Copy(base_offset) => base.read_at(base_offset + skip, &mut buf[0..len]),
+                    OpKind::Data(data_offset) => {
+                        file.seek(SeekFrom::Start(data_offset + skip))?;
+                        file.read(&mut buf[0..len])
+                    }
+                }
+            }
+        }
+    }
+
+    fn read_exact_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let len = self.read_at(offset, buf)?;
+            if 0 == len {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Delta base is shorter than expected."))
+            }
+            offset += len as u64;
+            buf = &mut buf[len..];
+        }
+        Ok(())
+    }
+}
+
+fn read_u64(input: &mut dyn Read) -> Result<u64, Error> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn with_extension(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(EXTENSION);
+    PathBuf::from(name)
+}
+
+// ----- Delta Computation -----------------------------------------------------
+
+/// rsync's weak checksum, which can be moved along the file one byte at a time.
+#[derive(Clone,Copy)]
+struct RollingChecksum {
+    a:   u32,
+    b:   u32,
+    len: u32
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> RollingChecksum {
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((block.len() - i) as u32 * *byte as u32);
+        }
+        RollingChecksum { a, b, len: block.len() as u32 }
+    }
+
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 16] {
+    use sha2::Digest;
+    let mut hash = [0u8; 16];
+    hash.copy_from_slice(&sha2::Sha256::digest(block)[0..16]);
+    hash
+}
+
+/// Writes the operations that rebuild a file, merging neighbouring ones.
+struct DeltaWriter<W: Write> {
+    output:        W,
+    copy:          Option<(u64, u64)>,
+    literal_bytes: u64
+}
+
+impl <W: Write> DeltaWriter<W> {
+    fn copy(&mut self, base_offset: u64, len: u64) -> Result<(), Error> {
+        if let Some((start, copy_len)) = &mut self.copy {
+            if *start + *copy_len == base_offset {
+                *copy_len += len;
+                return Ok(())
+            }
+        }
+        self.flush_copy()?;
+        self.copy = Some((base_offset, len));
+        Ok(())
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(())
+        }
+        self.flush_copy()?;
+        self.output.write_all(b"D")?;
+        self.output.write_all(&(data.len() as u64).to_le_bytes())?;
+        self.output.write_all(data)?;
+        self.literal_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    fn flush_copy(&mut self) -> Result<(), Error> {
+        if let Some((start, len)) = self.copy.take() {
+            self.output.write_all(b"C")?;
+            self.output.write_all(&start.to_le_bytes())?;
+            self.output.write_all(&len.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<u64, Error> {
+        self.flush_copy()?;
+        self.output.write_all(b"E")?;
+        self.output.flush()?;
+        Ok(self.literal_bytes)
+    }
+}
+
+/// Literal data is written out once this much has built up.
+const MAX_LITERAL: usize = 1 << 20;
+
+/// Writes a delta of `src` against `reference` to `delta_path`, returning how many bytes of `src`
+/// could not be found in the reference.
+fn write_delta(storage: &dyn Storage, src: &Path, reference: &Reference, block_size: u64, delta_path: &Path)
+               -> Result<u64, Error> {
+    let mut base = Source::open(storage, &reference.path)?;
+    let block_len = block_size as usize;
+    let mut signature: HashMap<u32, Vec<(u64, [u8; 16])>> = HashMap::new();
+    let mut block = vec![0u8; block_len];
+    let mut offset = 0;
+    while offset + block_size <= base.len() {
+        base.read_exact_at(offset, &mut block)?;
+        signature.entry(RollingChecksum::new(&block).value()).or_default().push((offset, strong_hash(&block)));
+        offset += block_size;
+    }
+
+    let mut input = BufReader::new(fs::File::open(src)?);
+    let size = input.get_ref().metadata()?.len();
+    let mut delta_file = storage.create(delta_path)?;
+    let mut output = BufWriter::new(delta_file.writer());
+    DeltaHeader { base: reference.path.clone(), depth: reference.depth + 1, size, len: 0 }.write(&mut output)?;
+    let mut writer = DeltaWriter { output, copy: None, literal_bytes: 0 };
+    // `data[literal..window]` has not been matched, and `data[window..window + block_len]` is
+    // the block being looked up.
+    let mut data: Vec<u8> = Vec::with_capacity(2 * block_len + MAX_LITERAL);
+    let mut literal = 0;
+    let mut window = 0;
+    let mut checksum: Option<RollingChecksum> = None;
+    let mut is_eof = false;
+    loop {
+        while !is_eof && data.len() < window + block_len + 1 {
+            let buffer = input.fill_buf()?;
+            if buffer.is_empty() {
+                is_eof = true;
+                break
+            }
+            let len = buffer.len().min(window + block_len + 1 - data.len());
+            data.extend_from_slice(&buffer[0..len]);
+            input.consume(len);
+        }
+        if data.len() < window + block_len {
+            break
+        }
+        let current = checksum.get_or_insert_with(|| RollingChecksum::new(&data[window..window + block_len]));
+        let matched = signature.get(&current.value()).and_then(|candidates| {
+            let hash = strong_hash(&data[window..window + block_len]);
+            candidates.iter().find(|(_, candidate)| *candidate == hash).map(|(base_offset, _)| *base_offset)
+        });
+        if let Some(base_offset) = matched {
+            writer.data(&data[literal..window])?;
+            writer.copy(base_offset, block_size)?;
+            data.drain(0..window + block_len);
+            literal = 0;
+            window = 0;
+            checksum = None;
+            continue
+        }
+        if data.len() > window + block_len {
+            current.roll(data[window], data[window + block_len]);
+        }
+        else {
+            checksum = None;
+        }
+        window += 1;
+        if window - literal >= MAX_LITERAL {
+            writer.data(&data[literal..window])?;
+            data.drain(0..window);
+            literal = 0;
+            window = 0;
+        }
+    }
+    writer.data(&data[literal..])?;
+    let literal_bytes = writer.finish()?;
+    delta_file.close()?;
+    Ok(literal_bytes)
+}
@@ -0,0 +1,139 @@
+use std::path::{Path,PathBuf};
+use std::fs;
+use std::io::{Error,ErrorKind};
+use std::sync::Mutex;
+use crate::backup::{self, BackupOutputStream, ConcurrentOutputStream};
+use crate::compression::CompressionAlgorithm;
+use crate::storage::{self,ObjectMeta,Storage};
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Name of the marker written into the root of a per-file compressed tree.  It holds the name of
+/// the algorithm, so restore knows which files to decompress.
+pub const MARKER: &str = ".tsnapshot-compressed";
+
+/// Mirrors the directory tree like `CopyOutputStream`, but compresses each regular file on its own
+/// into `<name><file extension>`.  Permissions, modification times and, where allowed, owners are
+/// kept on every entry.
+pub struct FileCompressedOutputStream<'a> {
+    storage:     &'a dyn Storage,
+    output_dir:  &'a Path,
+    algorithm:   &'a CompressionAlgorithm,
+    directories: Mutex<Vec<(PathBuf, ObjectMeta)>>,
+}
+
+impl <'a> FileCompressedOutputStream<'a> {
+    pub fn new(storage: &'a dyn Storage, output_dir: &'a Path, algorithm: &'a CompressionAlgorithm)
+               -> FileCompressedOutputStream<'a> {
+        FileCompressedOutputStream {
+            storage,
+            output_dir,
+            algorithm,
+            directories: Mutex::new(vec![]),
+        }
+    }
+
+    /// Applies directory metadata, which changes as their contents are written.
+    pub fn close(&mut self) -> Result<(), Error> {
+        let mut directories = self.directories.lock().unwrap();
+        // Parents are always appended before their children, so sorting puts them first.
+        directories.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, meta) in directories.iter().rev() {
+            self.storage.set_metadata(path, meta)?;
+        }
+        Ok(())
+    }
+}
+
+impl <'a> BackupOutputStream for FileCompressedOutputStream<'a> {
+    fn append_file(&mut self, src: &Path) -> Result<(), Error> {
+        self.append_file_concurrently(src)
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentOutputStream> { Some(self) }
+}
+
+impl <'a> ConcurrentOutputStream for FileCompressedOutputStream<'a> {
+    fn append_file_concurrently(&self, src: &Path) -> Result<(), Error> {
+        let meta = src.symlink_metadata()?;
+        let output_file = backup::append_path(self.output_dir, src);
+        if meta.file_type().is_symlink() {
+            log::debug!("Backing up symlink {:?} to {:?}.", src, self.output_dir);
+            self.storage.symlink(&fs::read_link(src)?, &output_file)?;
+        }
+        else if meta.file_type().is_dir() {
+            log::debug!("Backing up directory {:?} to {:?}.", src, self.output_dir);
+            self.storage.create_dir(&output_file)?;
+            let mut directories = self.directories.lock().unwrap();
+            if directories.is_empty() {
+                self.storage.write(&output_file.join(MARKER), (self.algorithm.name().to_string() + "\n").as_bytes())?;
+            }
+            directories.push((output_file, ObjectMeta::from(&meta)));
+        }
+        else if meta.file_type().is_file() {
+            let mut compressed_name = output_file.into_os_string();
+            compressed_name.push(self.algorithm.file_extension());
+            let compressed_file = PathBuf::from(compressed_name);
+            log::debug!("Compressing file {:?} to {:?}.", src, compressed_file);
+            // Separate files are compressed in parallel by the walker instead.
+            let mut compressor = self.algorithm.get_writer(self.storage.create(&compressed_file)?, 1)?;
+            std::io::copy(&mut fs::File::open(src)?, compressor.writer())?;
+            compressor.close()?;
+            self.storage.set_metadata(&compressed_file, &ObjectMeta::from(&meta))?;
+        }
+        else {
+            // Special files have nothing to compress.
+            log::warn!("Skipping special file {:?}.", src);
+        }
+        Ok(())
+    }
+}
+
+/// Restores the per-file compressed tree `src_path` into `dst_path`, decompressing every file.
+pub fn restore_tree(storage: &dyn Storage, src_path: &Path, dst_path: &Path) -> Result<(), Error> {
+    let algorithm = storage.read_to_string(&src_path.join(MARKER))?.trim().parse::<CompressionAlgorithm>()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, format!("{:?}", err)))?;
+    restore_entry(storage, src_path, dst_path, &algorithm)
+}
+
+fn restore_entry(storage: &dyn Storage, src_path: &Path, dst_path: &Path, algorithm: &CompressionAlgorithm)
+                 -> Result<(), Error> {
+    let meta = storage.metadata(src_path)?
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{:?} does not exist.", src_path)))?;
+    if meta.is_symlink() {
+        log::info!("Linking {:?}", dst_path);
+        symlink::symlink_auto(storage.read_link(src_path)?, dst_path)?;
+    }
+    else if meta.is_dir() {
+        log::info!("Creating {:?}", dst_path);
+        match fs::create_dir(dst_path) {
+            Ok(_)                                              => (),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err)                                           => return Err(err)
+        };
+        for entry_path in storage.read_dir(src_path)? {
+            let name = entry_path.file_name().unwrap();
+            if name == MARKER {
+                continue
+            }
+            let name_str = name.to_str()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Filename not representable as str."))?;
+            let output_name = if storage.metadata(&entry_path)?.is_some_and(|meta| meta.is_file()) {
+                name_str.strip_suffix(algorithm.file_extension()).ok_or_else(|| Error::new(
+                    ErrorKind::InvalidData, format!("{:?} is not a compressed file.", entry_path)))?
+            }
+            else {
+                name_str
+            };
+            restore_entry(storage, &entry_path, &dst_path.join(output_name), algorithm)?;
+        }
+        storage::set_metadata(dst_path, &meta)?;
+    }
+    else {
+        log::info!("Decompressing {:?} into {:?}", src_path, dst_path);
+        let mut decoder = algorithm.decoder(Box::new(storage.open(src_path)?))?;
+        std::io::copy(&mut decoder, &mut fs::File::create(dst_path)?)?;
+        storage::set_metadata(dst_path, &meta)?;
+    }
+    Ok(())
+}
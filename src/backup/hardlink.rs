@@ -1,61 +1,111 @@
 use std::path::{Path,PathBuf};
-use crate::backup::{self, BackupOutputStream};
+use crate::backup::{self, BackupOutputStream, ConcurrentOutputStream};
 use crate::backup::copy::CopyOutputStream;
+use crate::storage::{ObjectMeta,Storage};
 use std::fs::Metadata;
+use std::sync::atomic::{AtomicU64,Ordering};
 
 // ----- Public Data Structures ------------------------------------------------
 
 pub struct HardLinkOutputStream<'a> {
     fallback:         CopyOutputStream<'a>,
-    ref_dir:          &'a Path,
+    ref_dirs:         &'a [&'a Path],
     max_link_count:   u64,
-    detection_method: &'a ChangeDetectionMethod
+    detection_method: &'a ChangeDetectionMethod,
+    linked:           AtomicU64,
+    copied:           AtomicU64,
+    copied_bytes:     AtomicU64
+}
+
+/// What a `HardLinkOutputStream` did with the files it was given.
+#[derive(Debug,Default)]
+pub struct LinkStats {
+    pub linked:       u64,
+    pub copied:       u64,
+    pub copied_bytes: u64
 }
 
 impl <'a> HardLinkOutputStream<'a> {
-    pub fn new(output_dir: &'a Path,
-               ref_dir: &'a Path,
+    /// Files are linked to the first unchanged copy in `ref_dirs`, which are searched in order.
+    pub fn new(storage: &'a dyn Storage,
+               output_dir: &'a Path,
+               ref_dirs: &'a [&'a Path],
                max_link_count: u64,
                detection_method: &'a ChangeDetectionMethod
     ) -> HardLinkOutputStream<'a> {
         return HardLinkOutputStream {
-            fallback:         CopyOutputStream::new(output_dir),
-            ref_dir:          ref_dir,
+            fallback:         CopyOutputStream::new(storage, output_dir),
+            ref_dirs:         ref_dirs,
             max_link_count:   max_link_count,
-            detection_method: detection_method
+            detection_method: detection_method,
+            linked:           AtomicU64::new(0),
+            copied:           AtomicU64::new(0),
+            copied_bytes:     AtomicU64::new(0)
+        }
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            linked:       self.linked.load(Ordering::Relaxed),
+            copied:       self.copied.load(Ordering::Relaxed),
+            copied_bytes: self.copied_bytes.load(Ordering::Relaxed)
         }
     }
     
     fn equivalent_path(&self, src: &Path, meta: &Metadata) -> Result<Option<PathBuf>, std::io::Error> {
-        let other_src = backup::append_path(self.ref_dir, src);
-        if !other_src.exists() {
-            return Ok(None);
-        }
-        let other_meta = other_src.metadata()?;
-        if self.detection_method.has_changed(src, meta, &other_src, &other_meta) {
-            return Ok(None);
-        }
-        if self.max_link_count < u64::MAX {
-            use std::os::unix::fs::MetadataExt;
-            if other_meta.nlink() >= self.max_link_count {
-                return Ok(None);
+        for ref_dir in self.ref_dirs {
+            let other_src = backup::append_path(ref_dir, src);
+            let other_meta = match self.fallback.storage.metadata(&other_src)? {
+                Some(other_meta) => other_meta,
+                None             => continue
+            };
+            if other_meta.kind != ObjectMeta::from(meta).kind {
+                continue;
+            }
+            if self.detection_method.has_changed(src, meta, self.fallback.storage, &other_src, &other_meta)? {
+                // Older snapshots only hold older copies, so by timestamp they have all changed too.
+                if let ChangeDetectionMethod::Timestamp = self.detection_method {
+                    break;
+                }
+                continue;
             }
+            if other_meta.nlink >= self.max_link_count {
+                continue;
+            }
+            log::debug!("{:?} is unchanged since {:?}.", src, ref_dir);
+            return Ok(Some(other_src));
         }
-        Ok(Some(other_src))
+        Ok(None)
     }
 }
 
 impl <'a> BackupOutputStream for HardLinkOutputStream<'a> {
     fn append_file(&mut self, src: &Path) -> Result<(), std::io::Error> {
+        self.append_file_concurrently(src)
+    }
+
+    fn as_concurrent(&self) -> Option<&dyn ConcurrentOutputStream> { Some(self) }
+}
+
+impl <'a> ConcurrentOutputStream for HardLinkOutputStream<'a> {
+    fn append_file_concurrently(&self, src: &Path) -> Result<(), std::io::Error> {
         let meta = src.symlink_metadata()?;
         let output_file = backup::append_path(self.fallback.output_dir, src);
-        if meta.file_type().is_dir() {
-            return self.fallback.append_file(src)
+        // Directories are always created anew, and special files are skipped by the copy.
+        if meta.file_type().is_dir() || !(meta.file_type().is_file() || meta.file_type().is_symlink()) {
+            return self.fallback.append_file_concurrently(src)
         }
         else {
             match self.equivalent_path(src, &meta)? {
-                Some(path) => std::fs::hard_link(path, output_file),
-                None       => return self.fallback.append_file(src)
+                Some(path) => {
+                    self.linked.fetch_add(1, Ordering::Relaxed);
+                    self.fallback.storage.hard_link(&path, &output_file)
+                },
+                None       => {
+                    self.copied.fetch_add(1, Ordering::Relaxed);
+                    self.copied_bytes.fetch_add(meta.len(), Ordering::Relaxed);
+                    return self.fallback.append_file_concurrently(src)
+                }
             }
         }
     }
@@ -70,15 +120,44 @@ pub enum ChangeDetectionMethod {
 // ----- Implementation --------------------------------------------------------
 
 impl ChangeDetectionMethod {
-    fn has_changed(&self, _src: &Path, meta: &Metadata, _other_src: &Path, other_meta: &Metadata) -> bool {
+    fn has_changed(&self, src: &Path, meta: &Metadata, storage: &dyn Storage, other_src: &Path,
+                   other_meta: &ObjectMeta) -> Result<bool, std::io::Error> {
+        if meta.len() != other_meta.len {
+            return Ok(true)
+        }
         match self {
-            ChangeDetectionMethod::Timestamp   => return match (meta.modified(), other_meta.modified()) {
-                (Ok(mtime), Ok(other_mtime)) => mtime < other_mtime,
+            // The copy was made after the last change to `src` unless `src` is newer.
+            ChangeDetectionMethod::Timestamp   => return Ok(match (meta.modified(), other_meta.modified) {
+                (Ok(mtime), Some(other_mtime)) => mtime > other_mtime,
                 // [tflucke] 2021-12-30: Failed to get one or more modification times.  Assume it changed.
                 // Maybe fall back on fullcompare?
-                (_,          _)              => true
-            },
-            ChangeDetectionMethod::FullCompare => todo!()
+                (_,          _)                => true
+            }),
+            ChangeDetectionMethod::FullCompare => {
+                if meta.file_type().is_symlink() {
+                    return Ok(std::fs::read_link(src)? != storage.read_link(other_src)?)
+                }
+                files_differ(src, storage, other_src)
+            }
+        }
+    }
+}
+
+/// Compares two files of the same size byte by byte.
+fn files_differ(path: &Path, storage: &dyn Storage, other_path: &Path) -> Result<bool, std::io::Error> {
+    use std::io::Read;
+    let mut file = std::fs::File::open(path)?;
+    let mut other_file = storage.open(other_path)?;
+    let mut buffer = [0u8; 65536];
+    let mut other_buffer = [0u8; 65536];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if 0 == len {
+            return Ok(false)
+        }
+        other_file.read_exact(&mut other_buffer[0..len])?;
+        if buffer[0..len] != other_buffer[0..len] {
+            return Ok(true)
         }
     }
 }
@@ -0,0 +1,309 @@
+use std::path::{Path,PathBuf};
+use std::collections::{HashMap,HashSet};
+use std::fs;
+use std::io::{BufRead,BufReader,BufWriter,Read,Write,Error,ErrorKind};
+use crate::backup::{self, BackupOutputStream};
+use crate::compression::{self,CompressionAlgorithm,Compressor,CompressorSink};
+use crate::encryption::{Encryption,KeyRing};
+use crate::storage::Storage;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Extension of the listing written next to every incremental or differential archive.
+/// Like GNU tar's listed-incremental snapshot file, it holds the state of the tree at the
+/// time of the backup so the next run can tell which files changed.
+pub const SNAR_EXTENSION: &str = ".snar";
+
+const SNAR_HEADER: &str = "tsnapshot-snar 1";
+/// Header of a listing whose entries are encrypted.  The rest of the header stays readable, so the
+/// chain of bases can be followed and rebased without a key.
+const ENCRYPTED_SNAR_HEADER: &str = "tsnapshot-snar 1 age";
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ArchiveMode {
+    /// Every run writes a complete archive.
+    Full,
+    /// Archive files changed since the previous run.
+    Incremental,
+    /// Archive files changed since the last full archive.
+    Differential
+}
+
+/// Contents of a `.snar` listing.
+#[derive(Debug)]
+pub struct SnapshotFile {
+    /// File name of the archive this listing describes, in the same directory.
+    pub archive: std::string::String,
+    /// Listing the archive was made against, or `None` for a full archive.
+    pub base:    Option<PathBuf>,
+    /// Number of runs since the last full archive.
+    pub runs:    u64,
+    state:       HashMap<std::string::String, StateEntry>,
+    deleted:     Vec<std::string::String>,
+}
+
+impl SnapshotFile {
+    /// Reads the whole listing, decrypting its entries with `keys` if they are encrypted.
+    pub fn read(storage: &dyn Storage, path: &Path, keys: &KeyRing) -> Result<SnapshotFile, Error> {
+        SnapshotFile::parse(storage, path, Some(keys))
+    }
+
+    /// Reads only the header, which is never encrypted.  `base` and `runs` are filled in, but
+    /// none of the entries are.
+    pub fn read_header(storage: &dyn Storage, path: &Path) -> Result<SnapshotFile, Error> {
+        SnapshotFile::parse(storage, path, None)
+    }
+
+    fn parse(storage: &dyn Storage, path: &Path, keys: Option<&KeyRing>) -> Result<SnapshotFile, Error> {
+        let bad_line = |line: &str| Error::new(ErrorKind::InvalidData,
+                                               format!("Malformed line {:?} in {:?}.", line, path));
+        let mut input = BufReader::new(storage.open(path)?);
+        let mut header = || {
+            let mut line = std::string::String::new();
+            match input.read_line(&mut line)? {
+                0 => Err(bad_line("")),
+                _ => Ok(line.trim_end_matches('\n').to_string())
+            }
+        };
+        let is_encrypted = match header()?.as_str() {
+            SNAR_HEADER           => false,
+            ENCRYPTED_SNAR_HEADER => true,
+            _                     => return Err(Error::new(ErrorKind::InvalidData,
+                                                           format!("{:?} is not a snapshot listing.", path)))
+        };
+        let archive = header()?.strip_prefix("archive ").ok_or_else(|| bad_line("archive"))?.to_string();
+        let base = match header()?.strip_prefix("base ").ok_or_else(|| bad_line("base"))? {
+            ""   => None,
+            base => Some(PathBuf::from(base))
+        };
+        let runs_line = header()?;
+        let runs = runs_line.strip_prefix("runs ").and_then(|runs| runs.parse().ok())
+            .ok_or_else(|| bad_line(&runs_line))?;
+        let mut state = HashMap::new();
+        let mut deleted = vec![];
+        if let Some(keys) = keys {
+            let entries: Box<dyn Read> = if is_encrypted { keys.decrypt(Box::new(input))? } else { Box::new(input) };
+            for line_res in BufReader::new(entries).lines() {
+                let line = line_res?;
+                let mut fields = line.splitn(4, '\t');
+                match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                    (Some("-"), _, _, Some(path))                => deleted.push(path.to_string()),
+                    (Some(typ), Some(mtime), Some(size), Some(path)) => {
+                        state.insert(path.to_string(), StateEntry {
+                            typ:   typ.chars().next().ok_or_else(|| bad_line(&line))?,
+                            mtime: mtime.parse().map_err(|_| bad_line(&line))?,
+                            size:  size.parse().map_err(|_| bad_line(&line))?,
+                        });
+                    },
+                    _                                            => return Err(bad_line(&line))
+                }
+            }
+        }
+        Ok(SnapshotFile {
+            archive,
+            base,
+            runs,
+            state,
+            deleted
+        })
+    }
+
+    /// Path of the listing for `archive_name` inside `dir`.
+    pub fn path_for(dir: &Path, archive_name: &str) -> PathBuf {
+        let plain_name = archive_name.strip_suffix(crate::encryption::EXTENSION).unwrap_or(archive_name);
+        let stem = CompressionAlgorithm::from_extension(plain_name)
+            .and_then(|algorithm| plain_name.strip_suffix(algorithm.extension()))
+            .unwrap_or(plain_name);
+        dir.join(stem.to_string() + SNAR_EXTENSION)
+    }
+
+    /// Picks the listing a new archive should be made against, given the listing written by the
+    /// previous run, along with the new archive's run count.  Returns `None` when a full archive
+    /// is due.  `keys` decrypt the listing picked, if it is encrypted.
+    pub fn find_base(storage: &dyn Storage, last: &Path, mode: ArchiveMode, full_interval: u64, keys: &KeyRing)
+                     -> Result<Option<(PathBuf, SnapshotFile, u64)>, Error> {
+        if ArchiveMode::Full == mode || !storage.exists(last)? {
+            return Ok(None)
+        }
+        let last_file = SnapshotFile::read_header(storage, last)?;
+        if last_file.runs + 1 >= full_interval {
+            log::info!("{} runs since the last full archive.  Making a full archive.", last_file.runs + 1);
+            return Ok(None)
+        }
+        let runs = last_file.runs + 1;
+        let mut base = (last.to_path_buf(), last_file);
+        if ArchiveMode::Differential == mode {
+            while let Some(next) = base.1.base.clone() {
+                let next_file = SnapshotFile::read_header(storage, &next)?;
+                base = (next, next_file);
+            }
+        }
+        Ok(Some((base.0.clone(), SnapshotFile::read(storage, &base.0, keys)?, runs)))
+    }
+}
+
+/// Records every entry in a `.snar` listing and only forwards regular files that changed since
+/// the base listing.  Directories and symlinks are always forwarded.
+pub struct IncrementalOutputStream<'a> {
+    output: &'a mut dyn BackupOutputStream,
+    base:   Option<SnapshotFile>,
+    snar:   BufWriter<CompressorSink>,
+    seen:   HashSet<std::string::String>,
+}
+
+impl <'a> IncrementalOutputStream<'a> {
+    /// With `encryption`, the entries of the listing are encrypted like the archive, since they
+    /// name every file backed up.
+    pub fn new(mut snar_file: Box<dyn Compressor>,
+               output: &'a mut dyn BackupOutputStream,
+               archive_name: &str,
+               base: Option<(PathBuf, SnapshotFile, u64)>,
+               encryption: Option<&Encryption>
+    ) -> Result<IncrementalOutputStream<'a>, Error> {
+        let header = snar_file.writer();
+        writeln!(header, "{}", if encryption.is_some() { ENCRYPTED_SNAR_HEADER } else { SNAR_HEADER })?;
+        writeln!(header, "archive {}", archive_name)?;
+        let base = match base {
+            Some((path, file, runs)) => {
+                writeln!(header, "base {}", listing_str(&path)?)?;
+                writeln!(header, "runs {}", runs)?;
+                Some(file)
+            },
+            None                     => {
+                writeln!(header, "base ")?;
+                writeln!(header, "runs 0")?;
+                None
+            }
+        };
+        let snar_file = match encryption {
+            Some(encryption) => encryption.get_writer(snar_file)?,
+            None             => snar_file
+        };
+        Ok(IncrementalOutputStream {
+            output,
+            base,
+            snar: BufWriter::new(CompressorSink(snar_file)),
+            seen: HashSet::new()
+        })
+    }
+
+    /// Writes out the entries deleted since the base listing.
+    pub fn close(&mut self) -> Result<(), Error> {
+        if let Some(base) = &self.base {
+            let mut deleted = base.state.keys().filter(|path| !self.seen.contains(*path)).collect::<Vec<_>>();
+            deleted.sort();
+            for path in deleted {
+                log::debug!("{:?} was deleted since the base archive.", path);
+                writeln!(self.snar, "-\t\t\t{}", path)?;
+            }
+        }
+        self.snar.flush()?;
+        self.snar.get_mut().0.close()
+    }
+}
+
+impl <'a> BackupOutputStream for IncrementalOutputStream<'a> {
+    fn append_file(&mut self, src: &Path) -> Result<(), Error> {
+        let meta = src.symlink_metadata()?;
+        let entry = StateEntry {
+            typ:   if meta.is_dir() { 'd' } else if meta.file_type().is_symlink() { 'l' } else { 'f' },
+            mtime: meta.modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(Error::other)?
+                .as_nanos(),
+            size:  meta.len()
+        };
+        let name = listing_str(&backup::as_relative(src))?.to_string();
+        writeln!(self.snar, "{}\t{}\t{}\t{}", entry.typ, entry.mtime, entry.size, name)?;
+        let is_unchanged = 'f' == entry.typ && self.base.as_ref()
+            .and_then(|base| base.state.get(&name))
+            .map(|base_entry| *base_entry == entry)
+            .unwrap_or(false);
+        self.seen.insert(name);
+        if is_unchanged {
+            log::debug!("{:?} is unchanged since the base archive.  Skipping...", src);
+            Ok(())
+        }
+        else {
+            self.output.append_file(src)
+        }
+    }
+}
+
+/// Restores the archive described by the listing `snar_path` into `dst_path`, first replaying
+/// every archive it was based on.
+pub fn restore_chain(storage: &dyn Storage, snar_path: &Path, dst_path: &Path, keys: &KeyRing) -> Result<(), Error> {
+    let snar = SnapshotFile::read(storage, snar_path, keys)?;
+    if let Some(base) = &snar.base {
+        log::info!("{:?} is based on {:?}.  Restoring that first...", snar_path, base);
+        restore_chain(storage, base, dst_path, keys)?;
+    }
+    let archive_path = snar_path.with_file_name(&snar.archive);
+    log::info!("Extracting {:?} into {:?}", archive_path, dst_path);
+    compression::decode_archive(storage, &archive_path, dst_path, keys)?;
+    for path in &snar.deleted {
+        let output_path = dst_path.join(path);
+        log::info!("Removing {:?}, which was deleted before this backup.", output_path);
+        let result = match output_path.symlink_metadata() {
+            Ok(meta) if meta.is_dir() => fs::remove_dir_all(&output_path),
+            Ok(..)                    => fs::remove_file(&output_path),
+            Err(err)                  => Err(err)
+        };
+        match result {
+            Ok(())                                        => (),
+            // Removing a directory already removes everything deleted inside it.
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err)                                      => return Err(err)
+        };
+    }
+    Ok(())
+}
+
+/// Copies the listing at `src_path` to `dst_path` in another storage, where its base is `rebase` of
+/// the original one.
+pub fn copy_rebased(src_storage: &dyn Storage, src_path: &Path, dst_storage: &dyn Storage, dst_path: &Path,
+                    rebase: &dyn Fn(&Path) -> PathBuf) -> Result<(), Error> {
+    let listing = SnapshotFile::read_header(src_storage, src_path)?;
+    let mut input = BufReader::new(src_storage.open(src_path)?);
+    let mut output = dst_storage.create(dst_path)?;
+    // The header is the first four lines.  Only the base changes, and the entries, which may be
+    // encrypted, are copied as they are.
+    for _ in 0 .. 4 {
+        let mut line = std::string::String::new();
+        input.read_line(&mut line)?;
+        if line.starts_with("base ") {
+            if let Some(base) = &listing.base {
+                line = format!("base {}\n", listing_str(&rebase(base))?);
+            }
+        }
+        output.writer().write_all(line.as_bytes())?;
+    }
+    std::io::copy(&mut input, output.writer())?;
+    output.close()
+}
+
+/// Whether `path` names a listing written by `IncrementalOutputStream`.
+pub fn is_listing(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("tsnapshot-") && name.ends_with(SNAR_EXTENSION))
+        .unwrap_or(false)
+}
+
+// ----- Listing Implementation ------------------------------------------------
+
+#[derive(Debug,PartialEq,Eq)]
+struct StateEntry {
+    typ:   char,
+    mtime: u128,
+    size:  u64
+}
+
+fn listing_str(path: &Path) -> Result<&str, Error> {
+    let string = path.to_str()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Filename not representable as str."))?;
+    if string.contains('\n') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Cannot list {:?} containing a newline.", path)))
+    }
+    Ok(string)
+}
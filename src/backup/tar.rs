@@ -1,17 +1,28 @@
 use std::path::Path;
 use std::io::{Write,Error,ErrorKind};
 use crate::backup::{self, BackupOutputStream};
+use crate::compression::{Compressor,IncompressibleDetector};
 
 // ----- Public Data Structures ------------------------------------------------
 
 pub struct TarOutputStream<'a> {
-    output: &'a mut dyn Write,
+    output:   &'a mut dyn Compressor,
+    detector: Option<&'a IncompressibleDetector>,
 }
 
 impl <'a> TarOutputStream<'a> {
-    pub fn new(output: &'a mut dyn Write) -> TarOutputStream<'a> {
+    pub fn new(output: &'a mut dyn Compressor) -> TarOutputStream<'a> {
         return TarOutputStream {
-            output: output
+            output:   output,
+            detector: None
+        }
+    }
+
+    /// Contents of files `detector` finds incompressible are only stored, in a stream of their own.
+    pub fn with_detector(output: &'a mut dyn Compressor, detector: &'a IncompressibleDetector) -> TarOutputStream<'a> {
+        TarOutputStream {
+            output,
+            detector: Some(detector)
         }
     }
 }
@@ -23,18 +34,21 @@ impl <'a> BackupOutputStream for TarOutputStream<'a> {
         log::debug!("Outputting header for {:?} to destination...", path);
         let pointer = &header as *const Header as *const u8;
         assert_eq_size!(Header, [u8; 512]);
-        self.output.write_all(unsafe {std::slice::from_raw_parts(pointer, 512)})?;
+        self.output.writer().write_all(unsafe {std::slice::from_raw_parts(pointer, 512)})?;
+        if let Some(detector) = self.detector {
+            self.output.set_storing(detector.is_incompressible(path, &path.symlink_metadata()?))?;
+        }
         if path.is_file() {
             log::debug!("{:?} is a file.  Writing contents to destination...", path);
             log::debug!("Opening for reading...");
             let mut file = std::fs::OpenOptions::new().read(true).write(false).open(path)?;
             log::debug!("Copying contents to output...");
-            std::io::copy(&mut file, self.output)?;
-            // Pad out to 512 bytes with 0s.
+            std::io::copy(&mut file, self.output.writer())?;
+            // Pad out to 512 bytes with 0s.  A whole block of padding would read as the end of the archive.
             let file_size = path.symlink_metadata()?.len();
-            let padding_size = 512 - (file_size as usize % 512);
+            let padding_size = (512 - (file_size as usize % 512)) % 512;
             log::debug!("Writing out {:?} bytes of 0 padding", padding_size);
-            self.output.write_all(&[0; 512][0..padding_size])?;
+            self.output.writer().write_all(&[0; 512][0..padding_size])?;
         }
         return Ok(())
     }
@@ -43,7 +57,7 @@ impl <'a> BackupOutputStream for TarOutputStream<'a> {
 impl <'a> Drop for TarOutputStream<'a> {
     fn drop(&mut self) {
         // Write out two empty 512 byte blocks.
-        match self.output.write_all(&[0; 1024]) {
+        match self.output.writer().write_all(&[0; 1024]) {
             Ok(..)   => {},
             Err(err) => log::error!("Failed to write closing bytes to file: {:?}", err)
         };
@@ -131,8 +145,9 @@ struct Header {
 }
 
 impl Header {
-    fn new(path: &std::path::Path) -> Result<Header, Error> {
-        let path = backup::as_relative(path);
+    fn new(src: &std::path::Path) -> Result<Header, Error> {
+        // Archived names are relative, but the file is read where it is.
+        let path = backup::as_relative(src);
         let path_string = path.to_str()
             .ok_or(Error::new(ErrorKind::InvalidInput, "Filename not representable as str.")
             )?.to_string();
@@ -143,7 +158,7 @@ impl Header {
             let (a, b) = path_string.split_at(path_string.len() - 100);
             (a, b.to_string())
         };
-        let meta = path.symlink_metadata()?;
+        let meta = src.symlink_metadata()?;
         let (output_name, size) = if meta.is_dir() {
             (if name.ends_with("/") { name } else { name + "/" }, 0)
         }
@@ -185,7 +200,7 @@ impl Header {
                           .as_secs()
         ).as_bytes(), &mut res.mtime);        
         res.typ[0] = FileType::from_metadata(&meta).as_char();
-        match std::fs::read_link(path).map(|x| byte_copy(x.to_str().unwrap().as_bytes(), &mut res.link)) {
+        match std::fs::read_link(src).map(|x| byte_copy(x.to_str().unwrap().as_bytes(), &mut res.link)) {
             Ok(..) => {}
             Err(..) => {/* Ignore Errors */}
         };
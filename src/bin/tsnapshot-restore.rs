@@ -2,10 +2,19 @@
 
 extern crate argparse;
 
-use std::path::Path;
+use argparse::{ArgumentParser,Collect,IncrBy,Store,StoreOption,StoreTrue};
+use tsnapshot::config::{Configuration,Override,log_level_from_u8};
+use std::path::{Path,PathBuf};
 use std::fs;
 use tsnapshot::compression::*;
-use std::fs::OpenOptions;
+use tsnapshot::backup::chunk::{self,ChunkStore};
+use tsnapshot::backup::incremental::{self,SnapshotFile};
+use tsnapshot::backup::filecompress;
+use tsnapshot::backup::delta;
+use tsnapshot::encryption::{self,KeyRing};
+use tsnapshot::volume;
+use tsnapshot::storage::Storage;
+use std::sync::Arc;
 
 // ----- Logging Data Structures -----------------------------------------------
 
@@ -37,21 +46,70 @@ impl log::Log for MyLogger {
 // ----- Main ------------------------------------------------------------------
 
 fn real_main(args: Vec<String>) -> i32 {
-    let config = match fs::read_to_string(&args[1]) {
-        Ok(file_contents) => {
-            match tsnapshot::config::Configuration::new(file_contents.as_str()) {
-                Ok(res) => res,
-                Err(parse_err) => {
-                    println!("{:?}", parse_err);
-                    return 1
-                }
-            }
+    let mut config_file = String::new();
+    let mut target = String::new();
+    let mut snapshot_name: Option<String> = None;
+    let mut verbose = 0u8;
+    let mut is_quiet = false;
+    let mut destination: Option<String> = None;
+    let mut name_format: Option<String> = None;
+    let mut is_dry_run = false;
+    let mut key_file: Option<String> = None;
+    let mut properties: Vec<String> = vec![];
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Restores a snapshot taken as described by a config file.");
+        parser.refer(&mut config_file)
+            .add_argument("config", Store, "Config file describing the backup").required();
+        parser.refer(&mut target)
+            .add_argument("target", Store, "Directory to restore into").required();
+        parser.refer(&mut snapshot_name)
+            .add_argument("snapshot", StoreOption, "Name of an older snapshot to restore instead of the most recent");
+        parser.refer(&mut verbose)
+            .add_option(&["-v", "--verbose"], IncrBy(1u8), "Log more.  Repeat for debugging output");
+        parser.refer(&mut is_quiet)
+            .add_option(&["-q", "--quiet"], StoreTrue, "Only log errors");
+        parser.refer(&mut destination)
+            .add_option(&["--destination"], StoreOption, "Overrides destination_dir");
+        parser.refer(&mut name_format)
+            .add_option(&["--name-format"], StoreOption, "Overrides name_format");
+        parser.refer(&mut is_dry_run)
+            .add_option(&["--dry-run"], StoreTrue, "Log what would be restored without writing anything");
+        parser.refer(&mut key_file)
+            .add_option(&["--key-file"], StoreOption, "Key file for encrypted archives.  Overrides the config's identity_file");
+        parser.refer(&mut properties)
+            .add_option(&["--set"], Collect, "Overrides a config property, as in root_dir_config.space_mode=linked");
+        if let Err(code) = parser.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code
+        }
+    }
+    // Named options are applied last, so they win over --set.
+    let mut overrides = match properties.iter().map(|property| property.parse::<Override>()).collect::<Result<Vec<Override>, _>>() {
+        Ok(overrides) => overrides,
+        Err(err)      => {
+            println!("{}", err);
+            return 2
         }
-        Err(io_err) => {
-            println!("{:?}", io_err);
+    };
+    overrides.extend(destination.map(|destination| Override::new("destination_dir", &destination)));
+    overrides.extend(name_format.map(|name_format| Override::new("name_format", &name_format)));
+    let mut config = match Configuration::load(Path::new(&config_file), &overrides) {
+        Ok(res)        => res,
+        Err(parse_err) => {
+            println!("{}", parse_err);
             return 1
         }
     };
+    if is_quiet {
+        config.verbosity = log::LevelFilter::Error;
+    }
+    else if 0 < verbose {
+        config.verbosity = log_level_from_u8(verbose);
+    }
+    if is_dry_run {
+        // A dry run is only good for what it logs.
+        config.verbosity = config.verbosity.max(log::LevelFilter::Info);
+    }
     match log::set_logger(&MY_LOGGER).map(|()| log::set_max_level(config.verbosity)) {
         Ok(())   => (),
         Err(err) => {
@@ -59,50 +117,84 @@ fn real_main(args: Vec<String>) -> i32 {
             return 1
         }
     };
-    let catalog_file_name = config.destination_dir.join("catalog.txt");
-    let catalog = {
-        if let Some(catalog_file) = match OpenOptions::new()
-            .read(true)
-            .open(&catalog_file_name) {
-                Ok(file)                                               => Some(file),
-                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
-                Err(err)                                               => {
-                    log::error!("Failed to open catalog file {:?} for reading: {:?}.",
-                             catalog_file_name, err);
-                    return 1
-                }
-            }
-        {
-            match tsnapshot::catalog::BackupCatalog::new(catalog_file) {
-                Ok(catalog) => catalog,
-                Err(err) => {
-                    log::error!("Failed to parse catalog file {:?}: {:?}.", catalog_file_name, err);
-                    return 1
-                }
-            }
-        }
-        else {
-            tsnapshot::catalog::BackupCatalog::empty()
+    let catalog = match tsnapshot::catalog::BackupCatalog::load(config.storage.as_ref(), &config.name_format) {
+        Ok(catalog) => catalog,
+        Err(err)    => {
+            log::error!("Failed to read catalog in {:?}: {:?}.", config.storage.root(), err);
+            return 1
         }
     };
-    catalog.most_recent().map(
-        |src| match extract(&src, &src, Path::new(&args[2])) {
+    let context = ExtractionContext {
+        storage:     config.storage.clone(),
+        chunk_store: ChunkStore::new(config.storage.clone(), config.storage.root().join(chunk::STORE_DIR)),
+        keys:        KeyRing::new(key_file.map(PathBuf::from).or_else(|| config.identity_file().map(Path::to_path_buf))),
+        is_dry_run
+    };
+    // An optional snapshot name picks an older backup instead of the most recent one.
+    let snapshot = match &snapshot_name {
+        Some(name) => match catalog.find(name) {
+            Some(snapshot) => Some(snapshot),
+            None           => {
+                log::error!("No backup named {:?} in the catalog in {:?}.", name, config.storage.root());
+                return 1
+            }
+        },
+        None       => catalog.most_recent()
+    };
+    if let Some(src) = snapshot {
+        match extract(src, src, Path::new(&target), &context) {
             Ok(_) => (),
-            Err(err) => log::error!("Failed to restore backup: {:?}", err)
+            Err(err) => {
+                log::error!("Failed to restore backup: {:?}", err);
+                return 1
+            }
         }
-    );
+    }
     return 0
 }
 
 // ----- Extraction Logic ------------------------------------------------------
 
-fn extract(src_path: &Path, rel_path: &Path, dst_path: &Path) -> Result<(), std::io::Error> {
+/// Everything outside of the backup directory needed to restore it.
+struct ExtractionContext {
+    storage:     Arc<dyn Storage>,
+    chunk_store: ChunkStore,
+    keys:        KeyRing,
+    /// Only log what would be restored.
+    is_dry_run:  bool
+}
+
+fn extract(src_path: &Path, rel_path: &Path, dst_path: &Path, context: &ExtractionContext) -> Result<(), std::io::Error> {
     if let Some(name_os) = src_path.file_name() {
         if let Some(name_str) = name_os.to_str() {
             if name_str.starts_with("tsnapshot-") {
-                if let Some(compression) = CompressionAlgorithm::from_extension(name_str) {
-                    log::info!("Extracting {:?} into {:?}", src_path, dst_path);
-                    return compression.decode_file(src_path, dst_path)
+                if chunk::is_index(src_path) {
+                    log::info!("Restoring chunk index {:?} into {:?}", src_path, dst_path);
+                    if context.is_dry_run {
+                        return Ok(())
+                    }
+                    return chunk::restore_index(src_path, &context.chunk_store, dst_path)
+                }
+                else if incremental::is_listing(src_path) {
+                    log::debug!("{:?} is restored along with its archive", src_path);
+                    return Ok(())
+                }
+                else if let Some(archive_name) = volume::split_count_name(name_str).filter(|name| is_archive(name)) {
+                    return extract_archive(&src_path.with_file_name(archive_name), dst_path, context)
+                }
+                else if let Some((archive_name, _)) = volume::split_volume_name(name_str)
+                    .filter(|(archive_name, _)| is_archive(archive_name)) {
+                    let count_path = volume::count_path(&src_path.with_file_name(archive_name));
+                    if !context.storage.exists(&count_path)? {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                                       format!("{:?} is missing, so the volumes of {:?} may be incomplete.",
+                                                               count_path, archive_name)))
+                    }
+                    log::debug!("{:?} is restored along with its volume count", src_path);
+                    return Ok(())
+                }
+                else if is_archive(name_str) {
+                    return extract_archive(src_path, dst_path, context)
                 }
                 else {
                     log::debug!("{:?} is not a recognized extension", name_str);
@@ -114,46 +206,81 @@ fn extract(src_path: &Path, rel_path: &Path, dst_path: &Path) -> Result<(), std:
         log::debug!("{:?} does not have an extension", src_path);
     }
     log::debug!("{:?} {:?} {:?}", src_path, rel_path, dst_path);
-    let src_meta = metadata(src_path)?;
     let extraction_dst = dst_path.join(src_path.strip_prefix(rel_path).unwrap());
-    if src_meta.is_dir() {
+    let storage = context.storage.as_ref();
+    let src_meta = storage.metadata(src_path)?
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{:?} does not exist.", src_path)))?;
+    // The target may be stored under another name, such as a delta, so links are not followed.
+    if src_meta.is_symlink() {
+        let target = storage.read_link(src_path)?;
+        log::info!("Linking {:?} to {:?}", extraction_dst, target);
+        if context.is_dry_run {
+            return Ok(())
+        }
+        return symlink::symlink_auto(target, extraction_dst)
+    }
+    if src_meta.is_dir() && storage.exists(&src_path.join(filecompress::MARKER))? {
+        log::info!("Decompressing {:?} into {:?}", src_path, extraction_dst);
+        if context.is_dry_run {
+            return Ok(())
+        }
+        filecompress::restore_tree(storage, src_path, &extraction_dst)
+    }
+    else if src_meta.is_dir() {
         log::info!("Creating {:?}", extraction_dst);
-        match fs::create_dir(extraction_dst) {
-            Ok(_)                                                       => (),
-            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
-            Err(err)                                                    => return Err(err)
-        };
-        for entry_res in fs::read_dir(src_path)? {
-            let entry_path = &entry_res?.path();
-            extract(entry_path, rel_path, dst_path)?;
+        if !context.is_dry_run {
+            match fs::create_dir(extraction_dst) {
+                Ok(_)                                                       => (),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => (),
+                Err(err)                                                    => return Err(err)
+            };
+        }
+        for entry_path in storage.read_dir(src_path)? {
+            extract(&entry_path, rel_path, dst_path, context)?;
         };
         Ok(())
     }
+    else if delta::is_delta(storage, src_path) {
+        let restored_dst = extraction_dst.with_file_name(
+            extraction_dst.file_name().unwrap().to_str().unwrap().strip_suffix(delta::EXTENSION).unwrap());
+        log::info!("Rebuilding {:?} into {:?}", src_path, restored_dst);
+        if context.is_dry_run {
+            return Ok(())
+        }
+        delta::restore_file(storage, src_path, &restored_dst)
+    }
     else {
         log::info!("Copying {:?} into {:?}", src_path, extraction_dst);
-        fs::copy(src_path, extraction_dst)?;
-        Ok(())
+        if context.is_dry_run {
+            return Ok(())
+        }
+        std::io::copy(&mut storage.open(src_path)?, &mut fs::File::create(&extraction_dst)?)?;
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(extraction_dst, fs::Permissions::from_mode(src_meta.mode & 0o7777))
     }
 }
 
-// ----- Temporary Utility Functions -------------------------------------------
-
-/* [tflucke] 2022-01-16: Rust uses statx to get path metadata, but fakechroot
- * cannot wrap it yet.  Until that is supported, we need to open the file
- * to get any metadata during a test.
- */
-#[cfg(debug_assertions)]
-#[inline]
-fn metadata(path: &Path) -> Result<fs::Metadata, std::io::Error> {
-    return OpenOptions::new().read(true).open(path)?.metadata();
+fn is_archive(name: &str) -> bool {
+    CompressionAlgorithm::from_extension(name.strip_suffix(encryption::EXTENSION).unwrap_or(name)).is_some()
 }
 
-#[cfg(not(debug_assertions))]
-#[inline]
-fn metadata(path: &Path) -> Result<fs::Metadata, std::io::Error> {
-    return path.metadata();
+fn extract_archive(archive_path: &Path, dst_path: &Path, context: &ExtractionContext) -> Result<(), std::io::Error> {
+    let archive_name = archive_path.file_name().unwrap().to_str().unwrap();
+    let listing = SnapshotFile::path_for(archive_path.parent().unwrap(), archive_name);
+    if context.storage.exists(&listing)? {
+        log::info!("Replaying archive chain of {:?} into {:?}", archive_path, dst_path);
+        if context.is_dry_run {
+            return Ok(())
+        }
+        return incremental::restore_chain(context.storage.as_ref(), &listing, dst_path, &context.keys)
+    }
+    log::info!("Extracting {:?} into {:?}", archive_path, dst_path);
+    if context.is_dry_run {
+        return Ok(())
+    }
+    decode_archive(context.storage.as_ref(), archive_path, dst_path, &context.keys)
 }
-    
+
 // ----- Entry Point -----------------------------------------------------------
 
 fn main() {
@@ -1,13 +1,36 @@
+// ----- Set up crate ----------------------------------------------------------
+
+extern crate argparse;
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
+use argparse::{ArgumentParser,Collect,IncrBy,Store,StoreOption,StoreTrue};
+use tsnapshot::backup::chunk::{self,ChunkStore};
+use tsnapshot::catalog::ReplicaStatus;
+use tsnapshot::config::{Configuration,Override,log_level_from_u8};
+use tsnapshot::storage::DryRunStorage;
+
 // ----- Logging Data Structures -----------------------------------------------
 
 static MY_LOGGER: MyLogger = MyLogger;
+/// The configured verbosity, as a `log::LevelFilter`.
+static VERBOSITY: AtomicUsize = AtomicUsize::new(log::LevelFilter::Warn as usize);
+/// Whether statistics are logged whatever the verbosity.
+static SHOW_STATS: AtomicBool = AtomicBool::new(false);
 
 struct MyLogger;
 
 impl log::Log for MyLogger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool { true }
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() as usize <= VERBOSITY.load(Ordering::Relaxed) ||
+            (metadata.target() == tsnapshot::backup::STATS_TARGET && SHOW_STATS.load(Ordering::Relaxed))
+    }
 
     fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
         let now = chrono::Local::now();
         if record.level() >= log::Level::Debug {
             println!("[{}] {} <{}:{}>: {}",
@@ -28,88 +51,244 @@ impl log::Log for MyLogger {
 // ----- Main ------------------------------------------------------------------
 
 fn real_main(args: Vec<String>) -> i32 {
-    let config = match std::fs::read_to_string(&args[1]) {
-        Ok(file_contents) => {
-            match tsnapshot::config::Configuration::new(file_contents.as_str()) {
-                Ok(res) => res,
-                Err(parse_err) => {
-                    println!("{:?}", parse_err);
-                    return 1
-                }
+    if args.get(1).map(String::as_str) == Some("check-config") {
+        return check_config(args[1..].to_vec())
+    }
+    let mut config_file = String::new();
+    let mut verbose = 0u8;
+    let mut is_quiet = false;
+    let mut destination: Option<String> = None;
+    let mut name_format: Option<String> = None;
+    let mut is_dry_run = false;
+    let mut show_stats = false;
+    let mut properties: Vec<String> = vec![];
+    let mut is_server = false;
+    let mut is_agent = false;
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Takes a new snapshot as described by a config file.  \
+                                `tsnapshot check-config <config>` looks the config over instead.");
+        parser.refer(&mut config_file)
+            .add_argument("config", Store, "Config file describing the backup");
+        parser.refer(&mut verbose)
+            .add_option(&["-v", "--verbose"], IncrBy(1u8), "Log more.  Repeat for debugging output");
+        parser.refer(&mut is_quiet)
+            .add_option(&["-q", "--quiet"], StoreTrue, "Only log errors");
+        parser.refer(&mut destination)
+            .add_option(&["--destination"], StoreOption, "Overrides destination_dir");
+        parser.refer(&mut name_format)
+            .add_option(&["--name-format"], StoreOption, "Overrides name_format");
+        parser.refer(&mut is_dry_run)
+            .add_option(&["--dry-run"], StoreTrue, "Log what would be written without writing anything");
+        parser.refer(&mut show_stats)
+            .add_option(&["--stats"], StoreTrue, "Log what each directory config linked, copied or saved, even when quiet");
+        parser.refer(&mut properties)
+            .add_option(&["--set"], Collect, "Overrides a config property, as in root_dir_config.space_mode=linked");
+        parser.refer(&mut is_server)
+            .add_option(&["--server"], StoreTrue, "Serve a remote destination over stdin and stdout");
+        parser.refer(&mut is_agent)
+            .add_option(&["--agent"], StoreTrue, "Serve a pulled source over stdin and stdout");
+        if let Err(code) = parser.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code
+        }
+    }
+    if is_server {
+        // stdout carries the protocol, so nothing may be logged to it.
+        return match tsnapshot::remote::serve(&mut std::io::stdin().lock(), &mut std::io::stdout().lock()) {
+            Ok(())   => 0,
+            Err(err) => {
+                eprintln!("Remote destination failed: {:?}.", err);
+                1
+            }
+        }
+    }
+    if is_agent {
+        return match tsnapshot::agent::serve(&mut std::io::stdin().lock(), &mut std::io::stdout().lock()) {
+            Ok(())   => 0,
+            Err(err) => {
+                eprintln!("Agent failed: {:?}.", err);
+                1
             }
         }
-        Err(io_err) => {
-            println!("{:?}", io_err);
+    }
+    if config_file.is_empty() {
+        eprintln!("A config file is required.");
+        return 2
+    }
+    // Named options are applied last, so they win over --set.
+    let mut overrides = match properties.iter().map(|property| property.parse::<Override>()).collect::<Result<Vec<Override>, _>>() {
+        Ok(overrides) => overrides,
+        Err(err)      => {
+            println!("{}", err);
+            return 2
+        }
+    };
+    overrides.extend(destination.map(|destination| Override::new("destination_dir", &destination)));
+    overrides.extend(name_format.map(|name_format| Override::new("name_format", &name_format)));
+    let mut config = match Configuration::load(Path::new(&config_file), &overrides) {
+        Ok(res)        => res,
+        Err(parse_err) => {
+            println!("{}", parse_err);
             return 1
         }
     };
-    match log::set_logger(&MY_LOGGER).map(|()| log::set_max_level(config.verbosity)) {
+    if is_quiet {
+        config.verbosity = log::LevelFilter::Error;
+    }
+    else if 0 < verbose {
+        config.verbosity = log_level_from_u8(verbose);
+    }
+    if is_dry_run {
+        // A dry run is only good for what it logs.
+        config.verbosity = config.verbosity.max(log::LevelFilter::Info);
+        config.storage = Arc::new(DryRunStorage::new(config.storage.clone()));
+        tsnapshot::hook::set_dry_run(true);
+        if let Some(source) = &mut config.source {
+            source.is_dry_run = true;
+        }
+        for replica in &mut config.replicas {
+            replica.storage = Arc::new(DryRunStorage::new(replica.storage.clone()));
+        }
+    }
+    VERBOSITY.store(config.verbosity as usize, Ordering::Relaxed);
+    SHOW_STATS.store(show_stats, Ordering::Relaxed);
+    let max_level = if show_stats { config.verbosity.max(log::LevelFilter::Info) } else { config.verbosity };
+    match log::set_logger(&MY_LOGGER).map(|()| log::set_max_level(max_level)) {
         Ok(())   => (),
         Err(err) => {
             println!("Logging failed to initialize: {:?}.", err);
             return 1
         }
     };
-    let catalog_file_name = config.destination_dir.join("catalog.txt");
-    let mut catalog = {
-        if let Some(catalog_file) = match std::fs::OpenOptions::new()
-            .read(true)
-            .open(&catalog_file_name) {
-                Ok(file)                                               => Some(file),
-                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
-                Err(err)                                               => {
-                    log::error!("Failed to open catalog file {:?} for reading: {:?}.",
-                             catalog_file_name, err);
-                    return 1
-                }
-            }
-        {
-            match tsnapshot::catalog::BackupCatalog::new(catalog_file) {
-                Ok(catalog) => catalog,
-                Err(err) => {
-                    log::error!("Failed to parse catalog file {:?}: {:?}.", catalog_file_name, err);
-                    return 1
-                }
-            }
-        }
-        else {
-            tsnapshot::catalog::BackupCatalog::empty()
+    let mut catalog = match tsnapshot::catalog::BackupCatalog::load(config.storage.as_ref(), &config.name_format) {
+        Ok(catalog) => catalog,
+        Err(err)    => {
+            log::error!("Failed to read catalog in {:?}: {:?}.", config.storage.root(), err);
+            return 1
         }
     };
-    let most_recent = catalog.most_recent();
-    match config.backup(most_recent) {
-        Ok(new_dir)   => catalog.push(&new_dir),
+    let history = catalog.paths();
+    let (new_dir, post_hook_result) = match config.backup(&history) {
+        Ok(backup)    => backup,
         Err(err)      => {
             log::error!("Failed to backup due to error: {:?}.", err);
             return 1
         }
-    }
-    match catalog.clean(config.keep_limit) {
+    };
+    // The snapshot is complete, so it is catalogued even though the run fails.
+    let mut result = match post_hook_result {
+        Ok(())   => 0,
+        Err(err) => {
+            log::error!("Post-hook failed after taking {:?}: {:?}.", new_dir, err);
+            1
+        }
+    };
+    catalog.push(&new_dir);
+    match catalog.clean(config.storage.as_ref(), &config.keep_limit) {
         Ok(_) => (),
         Err(err) => {
             log::error!("Failed to clean backups: {:?}.", err);
             return 1
         }
     };
-    let catalog_file = match std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(&catalog_file_name) {
-            Ok(file) => file,
+    let chunk_store = ChunkStore::new(config.storage.clone(), config.storage.root().join(chunk::STORE_DIR));
+    match chunk_store.collect_garbage(&catalog.paths()) {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!("Failed to remove unreferenced chunks: {:?}.", err);
+            return 1
+        }
+    };
+    match catalog.save(config.storage.as_ref()) {
+        Ok(_) => (),
+        Err(err) => {
+            log::error!("Failed to save new catalog file: {:?}.", err);
+            return 1
+        }
+    };
+    if let Some(pool) = &config.pool {
+        log::info!("Backed up to disk {:?}.", pool.current_disk().name);
+        let result = if is_dry_run { Ok(()) } else { pool.record_refresh() };
+        match result.and_then(|()| pool.warn_stale()) {
+            Ok(())   => (),
             Err(err) => {
-                log::error!("Failed to open catalog file {:?} for writing: {:?}.",
-                         catalog_file_name, err);
+                log::error!("Failed to update pool state {:?}: {:?}.", pool.state_file, err);
                 return 1
             }
+        }
+    }
+    if config.replicas.is_empty() {
+        return result
+    }
+    // The snapshot is safe in the primary catalog before any replica is touched, so a
+    // failed replica only costs the second copy.
+    for replica in &config.replicas {
+        let status = match replica.replicate(&config.storage, &catalog, &new_dir, &config.name_format) {
+            Ok(stats) => {
+                log::info!("Replicated {} snapshots to {:?}: {} files hard linked, {} files copied ({} bytes).",
+                           stats.snapshots, replica.name, stats.linked, stats.copied, stats.copied_bytes);
+                ReplicaStatus::Replicated
+            },
+            Err(err)  => {
+                log::error!("Failed to replicate {:?} to {:?}: {:?}.", new_dir, replica.name, err);
+                result = 1;
+                ReplicaStatus::Failed
+            }
         };
-    match catalog.save_to(catalog_file) {
+        catalog.set_replica_status(&new_dir, &replica.name, status);
+    }
+    match catalog.save(config.storage.as_ref()) {
         Ok(_) => (),
         Err(err) => {
-            log::error!("Failed to save new catalog file: {:?}.", err);
+            log::error!("Failed to save replication status: {:?}.", err);
             return 1
         }
     };
-    return 0
+    return result
+}
+
+/// Reports everything wrong with a config, and everything which looks like a mistake, without
+/// taking a snapshot.  Destinations are still opened, just as a backup would.
+fn check_config(args: Vec<String>) -> i32 {
+    let mut config_file = String::new();
+    let mut properties: Vec<String> = vec![];
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Checks a config file for errors and likely mistakes.");
+        parser.refer(&mut config_file)
+            .add_argument("config", Store, "Config file to check")
+            .required();
+        parser.refer(&mut properties)
+            .add_option(&["--set"], Collect, "Overrides a config property, as in root_dir_config.space_mode=linked");
+        if let Err(code) = parser.parse(args, &mut std::io::stdout(), &mut std::io::stderr()) {
+            return code
+        }
+    }
+    let result = properties.iter()
+        .map(|property| property.parse::<Override>())
+        .collect::<Result<Vec<Override>, _>>()
+        .and_then(|overrides| Configuration::load_json(Path::new(&config_file), &overrides));
+    let json = match result {
+        Ok(json) => json,
+        Err(err) => {
+            println!("error: {}", err);
+            return 1
+        }
+    };
+    let warnings = tsnapshot::check::check(&json);
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    match Configuration::check_json(json) {
+        Ok(())   => {
+            println!("{} is valid, with {} warnings.", config_file, warnings.len());
+            0
+        },
+        Err(err) => {
+            println!("error: {}", err);
+            1
+        }
+    }
 }
 
 // ----- Entry Point -----------------------------------------------------------
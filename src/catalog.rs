@@ -1,18 +1,77 @@
 use crate::config::{ParseError, KeepLimit};
+use crate::storage::Storage;
 
 // ----- Public Data Structures ------------------------------------------------
 
+/// Name of the catalog file in the root of a destination.
+pub const CATALOG_FILE: &str = "catalog.txt";
+
 #[derive(Debug)]
 pub struct BackupCatalog {
     entries: Vec<Entry>
 }
 
+/// Whether a snapshot was copied to a replica, as recorded in the primary catalog.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ReplicaStatus {
+    Replicated,
+    Failed
+}
+
 impl BackupCatalog {
-    pub fn new(file: std::fs::File) -> Result<BackupCatalog,Error> {
+    /// Reads the catalog of `storage`.  Without one, such as before the first backup, it is rebuilt
+    /// from the snapshots named by `name_format`.
+    pub fn load(storage: &dyn Storage, name_format: &str) -> Result<BackupCatalog, Error> {
+        let path = storage.root().join(CATALOG_FILE);
+        match storage.open(&path) {
+            Ok(file)                                               => BackupCatalog::new(file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BackupCatalog::rebuild(storage, name_format),
+            Err(err)                                               => Err(ParseError::IoError(path, err))
+        }
+    }
+
+    /// Lists the snapshots in `storage` whose names parse as `name_format`.
+    pub fn rebuild(storage: &dyn Storage, name_format: &str) -> Result<BackupCatalog, Error> {
+        let root = storage.root();
+        let paths = match storage.read_dir(root) {
+            Ok(paths)                                              => paths,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err)                                               => return Err(ParseError::IoError(root.to_path_buf(), err))
+        };
+        let mut entries = vec![];
+        for path in paths {
+            let timestamp = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| chrono::NaiveDateTime::parse_from_str(name, name_format).ok())
+                .and_then(|time| time.and_local_timezone(chrono::Local).earliest());
+            let is_dir = storage.metadata(&path)
+                .map_err(|err| ParseError::IoError(path.clone(), err))?
+                .is_some_and(|meta| meta.is_dir());
+            if let (Some(time), true) = (timestamp, is_dir) {
+                entries.push(Entry {
+                    path,
+                    timestamp: time.timestamp(),
+                    replicas:  vec![]
+                });
+            }
+        }
+        if !entries.is_empty() {
+            log::warn!("No catalog in {:?}.  Rebuilt it from {} snapshots.", root, entries.len());
+        }
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp));
+        Ok(BackupCatalog {
+            entries
+        })
+    }
+
+    pub fn new(file: impl std::io::Read) -> Result<BackupCatalog,Error> {
         use std::io::BufRead;
         let reader = std::io::BufReader::new(file);
+        let mut entries = reader.lines().map(Entry::new).collect::<Result<Vec<Entry>, Error>>()?;
+        // Entries are kept newest first.
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.timestamp));
         Ok(BackupCatalog {
-            entries: reader.lines().map(Entry::new).collect::<Result<Vec<Entry>, Error>>()?
+            entries
         })
     }
     
@@ -22,21 +81,44 @@ impl BackupCatalog {
         }
     }
     
-    pub fn save_to(&self, file: std::fs::File) -> Result<(), std::io::Error>{
-        use std::io::Write;
-        let mut writer = std::io::BufWriter::new(file);
+    /// Replaces the catalog of `storage` with this one.
+    pub fn save(&self, storage: &dyn Storage) -> Result<(), std::io::Error> {
+        let mut contents = std::string::String::new();
         for entry in &self.entries {
-            writeln!(writer, "{} {}", entry.path.to_str().unwrap(), entry.timestamp)?;
+            contents += &(entry.to_string() + "\n");
         }
-        Ok(())
+        storage.write(&storage.root().join(CATALOG_FILE), contents.as_bytes())
     }
     
     pub fn push(&mut self, path: &std::path::Path) {
-        self.entries.push(Entry {
+        self.entries.insert(0, Entry {
             path: path.to_path_buf(),
-            timestamp: chrono::offset::Utc::now().timestamp()
+            timestamp: chrono::offset::Utc::now().timestamp(),
+            replicas: vec![]
         })
     }
+
+    /// Adds a backup taken at `timestamp`, such as one copied from another destination.
+    pub fn insert(&mut self, path: &std::path::Path, timestamp: i64) {
+        let index = self.entries.iter().position(|entry| entry.timestamp <= timestamp).unwrap_or(self.entries.len());
+        self.entries.insert(index, Entry {
+            path: path.to_path_buf(),
+            timestamp,
+            replicas: vec![]
+        })
+    }
+
+    pub fn timestamp(&self, path: &std::path::Path) -> Option<i64> {
+        self.entries.iter().find(|entry| entry.path == path).map(|entry| entry.timestamp)
+    }
+
+    /// Records whether the backup at `path` reached the replica named `replica`.
+    pub fn set_replica_status(&mut self, path: &std::path::Path, replica: &str, status: ReplicaStatus) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.path == path) {
+            entry.replicas.retain(|(name, _)| name != replica);
+            entry.replicas.push((replica.to_string(), status));
+        }
+    }
     
     pub fn most_recent(&self) -> Option<&std::path::Path> {
         if self.entries.len() > 0 {
@@ -47,36 +129,93 @@ impl BackupCatalog {
         }
     }
 
-    pub fn clean(&mut self, keep_limits: Vec<KeepLimit>) -> Result<(), std::io::Error> {
-        if 0 == self.entries.len() {
+    /// Finds the backup whose path or directory name is `name`.
+    pub fn find(&self, name: &str) -> Option<&std::path::Path> {
+        self.entries.iter()
+            .map(|entry| entry.path.as_path())
+            .find(|path| path.as_os_str() == name || path.file_name().map(|file| file == name).unwrap_or(false))
+    }
+
+    pub fn paths(&self) -> Vec<&std::path::Path> {
+        self.entries.iter().map(|entry| entry.path.as_path()).collect()
+    }
+
+    /// Removes every backup not needed to satisfy `keep_limits`, deleting it from disk.
+    /// The most recent backup is always kept.  Without any keep limits, nothing is removed.
+    pub fn clean(&mut self, storage: &dyn Storage, keep_limits: &[KeepLimit]) -> Result<(), std::io::Error> {
+        if self.entries.is_empty() || keep_limits.is_empty() {
             return Ok(())
         }
-        let mut iter = self.entries.iter();
-        let mut last_backup = match iter.next() {
-            Some(backup) => backup.timestamp,
-            None         => return Ok(())
-        };
-        for limit in keep_limits {
+        let mut keep = vec![false; self.entries.len()];
+        keep[0] = true;
+        let mut last_backup = self.entries[0].timestamp;
+        let mut index = 1;
+        'limits: for limit in keep_limits {
             for _ in 0 .. limit.count {
-                match BackupCatalog::clean_to_timestamp(&mut iter, last_backup - limit.timespan as i64) {
-                    Some(next_backup) => last_backup = next_backup.timestamp,
+                let start = index;
+                match BackupCatalog::clean_to_timestamp(&self.entries, &mut index, last_backup - limit.timespan as i64) {
+                    Some(next_backup) => {
+                        keep[next_backup] = true;
+                        last_backup = self.entries[next_backup].timestamp;
+                    },
                     // [tflucke] 2021-12-30: Nothing left to clean up
-                    None              => return Ok(())
+                    None              => {
+                        // No backup is old enough for this limit yet, so the rest wait until one is.
+                        keep[start..].iter_mut().for_each(|is_kept| *is_kept = true);
+                        break 'limits
+                    }
                 }
             }
         }
-        BackupCatalog::clean_to_timestamp(&mut iter, 0);
+        // Files in a kept backup may be based on files in otherwise expired ones.
+        let mut is_changed = true;
+        while is_changed {
+            is_changed = false;
+            for index in 0 .. self.entries.len() {
+                if !keep[index] {
+                    continue
+                }
+                for base in crate::backup::dependencies(storage, &self.entries[index].path)? {
+                    if let Some(base_index) = self.entries.iter().position(|entry| base.starts_with(&entry.path)) {
+                        if !keep[base_index] {
+                            log::debug!("Keeping {:?} since {:?} depends on it.",
+                                        self.entries[base_index].path, self.entries[index].path);
+                            keep[base_index] = true;
+                            is_changed = true;
+                        }
+                    }
+                }
+            }
+        }
+        let mut keep_iter = keep.iter();
+        let mut removed = vec![];
+        self.entries.retain(|entry| {
+            let is_kept = *keep_iter.next().unwrap();
+            if !is_kept {
+                removed.push(entry.path.clone());
+            }
+            is_kept
+        });
+        for path in removed {
+            log::info!("Removing expired backup {:?}...", path);
+            match storage.remove_dir_all(&path) {
+                Ok(())                                                 => (),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => log::warn!("Backup {:?} already removed.", path),
+                Err(err)                                               => return Err(err)
+            }
+        }
         Ok(())
     }
 
-    fn clean_to_timestamp<'a>(iter: &'a mut std::slice::Iter<Entry>, timestamp: i64) -> Option<&'a Entry> {
-        for entry in iter {
-            if entry.timestamp > timestamp {
-                // [tflucke] 2021-12-30: next_backup is too recent.  Remove it.
-            }
-            else {
-                return Some(entry)
+    /// Advances `index` past every entry more recent than `timestamp`, returning the first older one.
+    fn clean_to_timestamp(entries: &[Entry], index: &mut usize, timestamp: i64) -> Option<usize> {
+        while *index < entries.len() {
+            let current = *index;
+            *index += 1;
+            if entries[current].timestamp <= timestamp {
+                return Some(current)
             }
+            // [tflucke] 2021-12-30: next_backup is too recent.  Remove it.
         }
         return None
     }
@@ -86,17 +225,20 @@ type Error = ParseError;
 
 // ----- Entry Parsing Functions -----------------------------------------------
 
+/// A line of the catalog: the backup's path, when it was taken and, after those, a `<name>=ok` or
+/// `<name>=failed` column for each replica it was copied to.
 #[derive(Debug)]
 struct Entry {
     path: std::path::PathBuf,
-    timestamp: i64
+    timestamp: i64,
+    replicas: Vec<(std::string::String, ReplicaStatus)>
 }
 
 impl Entry {
     fn new(res: Result<std::string::String, std::io::Error>) -> Result<Entry, Error> {
         use regex::Regex;
         lazy_static! {
-            static ref R: Regex = Regex::new(r"^(.+)\s+(\d+)$").unwrap();
+            static ref R: Regex = Regex::new(r"^(.+?)\s+(\d+)((?:\s+[\w.-]+=\w+)*)$").unwrap();
         }
         let string = res.map_err(|err| ParseError::IoError(std::path::PathBuf::new(), err))?;
         log::debug!("Entry line: {}", string);
@@ -106,12 +248,26 @@ impl Entry {
             path: std::path::Path::new(caps.get(1).unwrap().as_str()).to_path_buf(),
             timestamp: caps.get(2).unwrap().as_str().parse()
                 .map_err(|_| ParseError::PatternError("No a valid epoch.", string.clone()))?,
+            replicas: caps.get(3).unwrap().as_str().split_whitespace()
+                .map(|column| match column.split_once('=') {
+                    Some((name, "ok"))     => Ok((name.to_string(), ReplicaStatus::Replicated)),
+                    Some((name, "failed")) => Ok((name.to_string(), ReplicaStatus::Failed)),
+                    _                      => Err(ParseError::PatternError("Not a replica status.", string.clone()))
+                })
+                .collect::<Result<Vec<(std::string::String, ReplicaStatus)>, Error>>()?
         })
     }
 }
 
-impl ToString for Entry {
-    fn to_string(&self) -> std::string::String {
-        return format!("{} {}", self.path.to_str().unwrap(), self.timestamp);
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}", self.path.to_str().unwrap(), self.timestamp)?;
+        for (name, status) in &self.replicas {
+            write!(f, " {}={}", name, match status {
+                ReplicaStatus::Replicated => "ok",
+                ReplicaStatus::Failed     => "failed"
+            })?;
+        }
+        Ok(())
     }
 }
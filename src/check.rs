@@ -0,0 +1,383 @@
+use std::borrow::Cow;
+use std::path::{Component,Path,PathBuf};
+use json::JsonValue;
+use json::object::Object;
+use crate::config::append_key;
+use crate::include;
+use crate::interpolate::interpolate;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Something in a config which is valid, but is unlikely to do what was meant.
+#[derive(Debug)]
+pub struct Warning {
+    /// JSON path of the value, as in `root_dir_config.subconfigs[3]`.
+    pub path:    std::string::String,
+    pub message: std::string::String
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.message)
+        }
+        else {
+            write!(f, "{}: {}", self.path, self.message)
+        }
+    }
+}
+
+/// Looks through a loaded config for properties nothing reads, subconfigs which are never used and
+/// filters which can never match.  Values of the wrong type are left for `Configuration` to reject.
+pub fn check(json: &JsonValue) -> Vec<Warning> {
+    let mut checker = Checker { warnings: vec![] };
+    if let JsonValue::Object(obj) = json {
+        checker.check_top(obj);
+    }
+    checker.warnings
+}
+
+// ----- Known Properties ------------------------------------------------------
+
+const TOP_KEYS: &[&str] = &[
+    "root_dir_config", "root_dir_configs", "destination_dir", "destination_command", "destination_s3",
+    "destination_pool", "verbosity", "name_format", "keep limit", "replicas", "threads", "pre_exec", "post_exec"
+];
+const DIRECTORY_KEYS: &[&str] = &[
+    "subpath", "space_mode", "subconfigs", "filters", "ignore_files", "deduplicate", "pre_exec", "post_exec"
+];
+const COMPRESS_KEYS: &[&str] = &[
+    "algorithm", "archive_mode", "full_interval", "encryption", "volume_size", "threads", "store_incompressible"
+];
+const COMPRESS_FILES_KEYS: &[&str] = &["algorithm"];
+const LINKED_KEYS: &[&str] = &["max_link_count", "change_detection", "link_history"];
+const CHUNKED_KEYS: &[&str] = &["chunk_size"];
+const DELTA_KEYS: &[&str] = &["min_size", "block_size", "full_interval"];
+const SOURCE_KEYS: &[&str] = &["command", "cache_dir", "change_detection"];
+const ENCRYPTION_KEYS: &[&str] = &["recipients_file", "recipient", "identity_file"];
+const INCOMPRESSIBLE_KEYS: &[&str] = &["mime", "extensions", "entropy", "sample_size", "min_size"];
+const KEEP_LIMIT_KEYS: &[&str] = &["count", "timespan"];
+const TIMESPAN_KEYS: &[&str] = &["seconds", "minutes", "hours", "days", "months", "years"];
+const S3_KEYS: &[&str] = &["endpoint", "bucket", "region", "access_key", "secret_key", "part_size"];
+const REPLICA_KEYS: &[&str] = &["name", "destination_dir", "destination_command", "destination_s3", "keep limit"];
+const POOL_KEYS: &[&str] = &["disks", "state_file", "max_age"];
+const DISK_KEYS: &[&str] = &["name", "destination_dir", "marker", "uuid"];
+const HOOK_KEYS: &[&str] = &["command", "timeout", "on_failure"];
+
+/// Properties of a filter, by what it filters on.
+fn filter_keys(on: &str) -> &'static [&'static str] {
+    match on {
+        "name" | "mime" => &["on", "pattern"],
+        "glob"          => &["on", "pattern", "patterns"],
+        "size"          => &["on", "min", "max"],
+        "not"           => &["on", "filter"],
+        "and" | "or"    => &["on", "filters"],
+        _               => &["on"]
+    }
+}
+
+/// Properties of a directory config besides `DIRECTORY_KEYS`, by space mode.
+fn space_mode_keys(space_mode: &str) -> &'static [&'static str] {
+    match space_mode {
+        "compress"       => COMPRESS_KEYS,
+        "compress_files" => COMPRESS_FILES_KEYS,
+        "linked"         => LINKED_KEYS,
+        "chunked"        => CHUNKED_KEYS,
+        "delta"          => DELTA_KEYS,
+        _                => &[]
+    }
+}
+
+// ----- Implementation --------------------------------------------------------
+
+struct Checker {
+    warnings: Vec<Warning>
+}
+
+impl Checker {
+    fn warn(&mut self, path: &str, message: std::string::String) {
+        self.warnings.push(Warning { path: path.to_string(), message });
+    }
+
+    fn check_top(&mut self, obj: &Object) {
+        self.check_keys("", obj, &[TOP_KEYS]);
+        if let Some(root) = obj.get("root_dir_config") {
+            self.check_directory("root_dir_config", root, None);
+        }
+        if let Some(JsonValue::Array(roots)) = obj.get("root_dir_configs") {
+            for (index, root) in roots.iter().enumerate() {
+                self.check_directory(&format!("root_dir_configs[{}]", index), root, None);
+            }
+        }
+        self.check_destination("", obj);
+        self.check_keep_limits("", obj);
+        self.check_hooks("", obj);
+        if let Some(JsonValue::Object(pool)) = obj.get("destination_pool") {
+            self.check_keys("destination_pool", pool, &[POOL_KEYS]);
+            for_each_object(pool.get("disks"), "destination_pool.disks", |path, disk| self.check_keys(path, disk, &[DISK_KEYS]));
+            if let Some(JsonValue::Object(max_age)) = pool.get("max_age") {
+                self.check_keys("destination_pool.max_age", max_age, &[TIMESPAN_KEYS]);
+            }
+        }
+        for_each_object(obj.get("replicas"), "replicas", |path, replica| {
+            self.check_keys(path, replica, &[REPLICA_KEYS]);
+            self.check_destination(path, replica);
+            self.check_keep_limits(path, replica);
+        });
+    }
+
+    fn check_destination(&mut self, path: &str, obj: &Object) {
+        if let Some(JsonValue::Object(s3)) = obj.get("destination_s3") {
+            self.check_keys(&join(path, "destination_s3"), s3, &[S3_KEYS]);
+        }
+    }
+
+    fn check_keep_limits(&mut self, path: &str, obj: &Object) {
+        for_each_object(obj.get("keep limit"), &join(path, "keep limit"), |path, limit| {
+            self.check_keys(path, limit, &[KEEP_LIMIT_KEYS]);
+            if let Some(JsonValue::Object(timespan)) = limit.get("timespan") {
+                self.check_keys(&join(path, "timespan"), timespan, &[TIMESPAN_KEYS]);
+            }
+        });
+    }
+
+    fn check_hooks(&mut self, path: &str, obj: &Object) {
+        for prop in ["pre_exec", "post_exec"] {
+            for_each_object(obj.get(prop), &join(path, prop), |path, hook| {
+                self.check_keys(path, hook, &[HOOK_KEYS]);
+                if let Some(JsonValue::Object(timeout)) = hook.get("timeout") {
+                    self.check_keys(&join(path, "timeout"), timeout, &[TIMESPAN_KEYS]);
+                }
+            });
+        }
+    }
+
+    /// `parent` is where the directory containing this one is backed up from, or `None` for a root.
+    fn check_directory(&mut self, path: &str, json: &JsonValue, parent: Option<&Path>) {
+        let obj = match json {
+            JsonValue::Object(obj) => obj,
+            _                      => return
+        };
+        let space_mode = obj.get("space_mode").and_then(JsonValue::as_str).unwrap_or("none");
+        let root_keys: &[&str] = if parent.is_none() { &["source"] } else { &[] };
+        self.check_keys(path, obj, &[DIRECTORY_KEYS, space_mode_keys(space_mode), root_keys]);
+        self.check_hooks(path, obj);
+        if let Some(JsonValue::Object(source)) = obj.get("source") {
+            self.check_keys(&join(path, "source"), source, &[SOURCE_KEYS]);
+        }
+        if let Some(JsonValue::Object(encryption)) = obj.get("encryption") {
+            self.check_keys(&join(path, "encryption"), encryption, &[ENCRYPTION_KEYS]);
+        }
+        if let Some(JsonValue::Object(detector)) = obj.get("store_incompressible") {
+            self.check_keys(&join(path, "store_incompressible"), detector, &[INCOMPRESSIBLE_KEYS]);
+        }
+        let subpath = match subpath_of(json) {
+            Some(subpath) => subpath,
+            None          => return
+        };
+        let dir = match parent {
+            Some(parent) => parent.join(&subpath),
+            None         => subpath.clone()
+        };
+        if let Some(JsonValue::Array(filters)) = obj.get("filters") {
+            for (index, filter) in filters.iter().enumerate() {
+                let filter_path = format!("{}.filters[{}]", path, index);
+                self.check_filter(&filter_path, filter);
+                if never_matches(filter, &dir) {
+                    self.warn(&filter_path, "can never match, so it filters nothing".to_string());
+                }
+                else if always_matches(filter) {
+                    self.warn(&filter_path, format!("matches everything, so nothing in {:?} is backed up", dir));
+                }
+            }
+        }
+        if let Some(JsonValue::Array(subconfigs)) = obj.get("subconfigs") {
+            let subpaths = subconfigs.iter()
+                .map(subpath_of)
+                .collect::<Vec<Option<PathBuf>>>();
+            for (index, subconfig) in subconfigs.iter().enumerate() {
+                let subconfig_path = format!("{}.subconfigs[{}]", path, index);
+                self.check_directory(&subconfig_path, subconfig, Some(&dir));
+                let subpath = match &subpaths[index] {
+                    Some(subpath) => subpath,
+                    None          => continue
+                };
+                // Subconfigs are looked up by comparing their subpath with the path
+                // walked to, which never has anything but plain names in it.
+                if subpath.as_os_str().is_empty() || subpath.components().any(|part| !matches!(part, Component::Normal(_))) {
+                    self.warn(&subconfig_path, format!("subpath {:?} is not a plain path inside {:?}, so this subconfig is never used",
+                                                      subpath, dir));
+                    continue
+                }
+                // Walking stops at whichever subconfig is reached first, which is the outermost.
+                let outer = subpaths.iter().enumerate().find(|(other_index, other)| {
+                    *other_index != index && other.as_ref().is_some_and(|other| {
+                        subpath.starts_with(other) && (subpath != other || *other_index < index)
+                    })
+                });
+                if let Some((outer_index, outer)) = outer {
+                    let relation = if outer.as_ref() == Some(subpath) { "has the same subpath as" } else { "is inside" };
+                    self.warn(&subconfig_path, format!("{} subconfigs[{}], which {:?} is backed up with instead, so this subconfig is never used",
+                                                      relation, outer_index, dir.join(subpath)));
+                }
+            }
+        }
+    }
+
+    fn check_filter(&mut self, path: &str, json: &JsonValue) {
+        let obj = match json {
+            JsonValue::Object(obj) => obj,
+            _                      => return
+        };
+        let on = obj.get("on").and_then(JsonValue::as_str).unwrap_or("");
+        self.check_keys(path, obj, &[filter_keys(on)]);
+        if let Some(filter) = obj.get("filter") {
+            self.check_filter(&join(path, "filter"), filter);
+        }
+        if let Some(JsonValue::Array(filters)) = obj.get("filters") {
+            for (index, filter) in filters.iter().enumerate() {
+                self.check_filter(&format!("{}.filters[{}]", path, index), filter);
+            }
+        }
+    }
+
+    /// Warns about every property of `obj` not in one of `known`.
+    fn check_keys(&mut self, path: &str, obj: &Object, known: &[&[&str]]) {
+        let is_known = |key: &str| known.iter().any(|keys| keys.contains(&key)) ||
+            include::is_internal(key) || include::EXTENDS_KEYS.contains(&key) || include::REPLACE_KEY == key;
+        for (key, _) in obj.iter() {
+            if is_known(key) {
+                continue
+            }
+            let message = match closest(key, known.iter().flat_map(|keys| keys.iter())) {
+                Some(other) => format!("unknown property, which is ignored.  Did you mean {:?}?", other),
+                None        => "unknown property, which is ignored".to_string()
+            };
+            self.warn(&join(path, key), message);
+        }
+    }
+}
+
+/// Calls `f` with the path of each object in the array `json`, if it is one.
+fn for_each_object<F: FnMut(&str, &Object)>(json: Option<&JsonValue>, path: &str, mut f: F) {
+    if let Some(JsonValue::Array(vec)) = json {
+        for (index, value) in vec.iter().enumerate() {
+            if let JsonValue::Object(obj) = value {
+                f(&format!("{}[{}]", path, index), obj);
+            }
+        }
+    }
+}
+
+fn join(path: &str, key: &str) -> std::string::String {
+    let mut joined = path.to_string();
+    append_key(&mut joined, key);
+    joined
+}
+
+/// The subpath of a directory config, with its variables filled in if they can be.
+fn subpath_of(json: &JsonValue) -> Option<PathBuf> {
+    let subpath = json["subpath"].as_str()?;
+    Some(PathBuf::from(interpolate(subpath).unwrap_or(Cow::Borrowed(subpath)).into_owned()))
+}
+
+/// A filter which no path under `dir` can match.  Only obvious cases are found.
+fn never_matches(json: &JsonValue, dir: &Path) -> bool {
+    let filters = || match &json["filters"] {
+        JsonValue::Array(filters) => filters.as_slice(),
+        _                         => &[]
+    };
+    match json["on"].as_str() {
+        Some("name") => json["pattern"].as_str().is_some_and(|pattern| !can_match_under(pattern, dir)),
+        Some("size") => match (bound(&json["min"], 0), bound(&json["max"], u64::MAX)) {
+            (Some(min), Some(max)) => max < min,
+            _                      => false
+        },
+        Some("not")  => always_matches(&json["filter"]),
+        Some("and")  => filters().iter().any(|filter| never_matches(filter, dir)),
+        Some("or")   => filters().iter().all(|filter| never_matches(filter, dir)),
+        _            => false
+    }
+}
+
+/// A filter which every path matches.  Only obvious cases are found.
+fn always_matches(json: &JsonValue) -> bool {
+    let filters = || match &json["filters"] {
+        JsonValue::Array(filters) => filters.as_slice(),
+        _                         => &[]
+    };
+    match json["on"].as_str() {
+        Some("size") => bound(&json["min"], 0) == Some(0) && bound(&json["max"], u64::MAX) == Some(u64::MAX),
+        Some("and")  => filters().iter().all(always_matches),
+        Some("or")   => filters().iter().any(always_matches),
+        _            => false
+    }
+}
+
+/// A size limit, or `default` if it is left out.
+fn bound(json: &JsonValue, default: u64) -> Option<u64> {
+    match json {
+        JsonValue::Null => Some(default),
+        _               => json.as_u64()
+    }
+}
+
+/// Whether a name `pattern` anchored with `^` can match a path under `dir`.  Paths are matched
+/// whole, so `^\.git$` never matches anything but a root named `.git`.
+fn can_match_under(pattern: &str, dir: &Path) -> bool {
+    let rest = match pattern.strip_prefix('^') {
+        Some(rest) => rest,
+        None       => return true
+    };
+    let dir = match dir.to_str() {
+        Some(dir) => dir,
+        None      => return true
+    };
+    // Everything up to the first special character must appear as it is.
+    let mut literal = std::string::String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        if "?*{".contains(c) {
+            // The last character may be left out or repeated.
+            literal.pop();
+            break
+        }
+        if '\\' == c {
+            // Escaped punctuation stands for itself, but `\d` and the like do not.
+            match chars.next() {
+                Some(escaped) if escaped.is_ascii_punctuation() => literal.push(escaped),
+                _                                                => break
+            }
+            continue
+        }
+        if ".+()|[]^$".contains(c) {
+            break
+        }
+        literal.push(c);
+    }
+    dir.starts_with(&literal) || literal.starts_with(&(dir.trim_end_matches('/').to_string() + "/"))
+}
+
+/// The known property closest to `key`, if it is close enough to be a typo.
+fn closest<'a, I: Iterator<Item = &'a &'a str>>(key: &str, known: I) -> Option<&'a str> {
+    known
+        .map(|other| (edit_distance(key, other), *other))
+        .filter(|(distance, _)| *distance <= 2 && *distance < key.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, other)| other)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut row = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = diagonal + if a_char == *b_char { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
@@ -1,9 +1,12 @@
 use std::path::Path;
+use crate::encryption::KeyRing;
+use crate::storage::Storage;
 
 #[derive(Debug)]
 pub enum CompressionAlgorithm {
     Bzip2(CompressionLevel),
     Gzip(CompressionLevel),
+    Zstd(CompressionLevel),
     Zip
 }
 
@@ -15,6 +18,9 @@ impl CompressionAlgorithm {
         else if string.ends_with(".tar.gz") {
             Some(CompressionAlgorithm::Gzip(CompressionLevel::Fast))
         }
+        else if string.ends_with(".tar.zst") {
+            Some(CompressionAlgorithm::Zstd(CompressionLevel::Fast))
+        }
         else if string.ends_with(".zip") {
             Some(CompressionAlgorithm::Zip)
         }
@@ -27,84 +33,536 @@ impl CompressionAlgorithm {
         match self {
             CompressionAlgorithm::Bzip2(..) => ".tar.bz2",
             CompressionAlgorithm::Gzip(..)  => ".tar.gz",
+            CompressionAlgorithm::Zstd(..)  => ".tar.zst",
             CompressionAlgorithm::Zip       => ".zip"
         }
     }
-    
-    pub fn get_writer(&self, out_writer: std::fs::File) -> Box<dyn Compressor> {
+
+    /// Name of the algorithm as given in the configuration.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Bzip2(..) => "bzip2",
+            CompressionAlgorithm::Gzip(..)  => "gzip",
+            CompressionAlgorithm::Zstd(..)  => "zstd",
+            CompressionAlgorithm::Zip       => "zip"
+        }
+    }
+
+    /// Extension of a single file compressed on its own, outside of a tar archive.
+    pub fn file_extension(&self) -> &'static str {
         match self {
+            CompressionAlgorithm::Bzip2(..) => ".bz2",
+            CompressionAlgorithm::Gzip(..)  => ".gz",
+            CompressionAlgorithm::Zstd(..)  => ".zst",
+            CompressionAlgorithm::Zip       => ".zip"
+        }
+    }
+
+    /// Wraps `out_writer` with this algorithm's encoder.  `out_writer` may itself be a compressor,
+    /// such as an encryption layer, which is closed after the encoder finishes.
+    ///
+    /// With more than one thread, bzip2 and gzip input is split into blocks which are compressed
+    /// as separate streams, and zstd uses its own worker threads.  0 uses one thread per CPU.
+    pub fn get_writer(&self, out_writer: Box<dyn Compressor>, threads: usize) -> Result<Box<dyn Compressor>, std::io::Error> {
+        let threads = match threads {
+            0       => std::thread::available_parallelism().map(|threads| threads.get()).unwrap_or(1),
+            threads => threads
+        };
+        let out_writer = CompressorSink(out_writer);
+        match self {
+            CompressionAlgorithm::Bzip2(level) if threads > 1 => {
+                Ok(Box::new(BlockCompressor::new(out_writer, threads, *level, |block, level| match level {
+                    CompressionLevel::Store => zstd::stream::encode_all(block, 1),
+                    level                   => {
+                        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2_level(level));
+                        std::io::Write::write_all(&mut encoder, block)?;
+                        encoder.finish()
+                    }
+                })))
+            },
+            CompressionAlgorithm::Gzip(level) if threads > 1 => {
+                Ok(Box::new(BlockCompressor::new(out_writer, threads, *level, |block, level| {
+                    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), gzip_level(level));
+                    std::io::Write::write_all(&mut encoder, block)?;
+                    encoder.finish()
+                })))
+            },
             CompressionAlgorithm::Bzip2(level) => {
                 use bzip2::write::*;
 
+                /// bzip2 costs about as much at every level, so stored data goes in a zstd frame.
+                enum Bzip2Encoder {
+                    Bzip2(BzEncoder<CompressorSink>),
+                    Stored(zstd::stream::write::Encoder<'static, CompressorSink>)
+                }
+                impl Bzip2Encoder {
+                    fn new(sink: CompressorSink, level: &CompressionLevel) -> Result<Bzip2Encoder, std::io::Error> {
+                        match level {
+                            CompressionLevel::Store => Ok(Bzip2Encoder::Stored(zstd::stream::write::Encoder::new(sink, 1)?)),
+                            level                   => Ok(Bzip2Encoder::Bzip2(BzEncoder::new(sink, bzip2_level(level))))
+                        }
+                    }
+                    fn finish(self) -> Result<CompressorSink, std::io::Error> {
+                        match self {
+                            Bzip2Encoder::Bzip2(encoder)  => encoder.finish(),
+                            Bzip2Encoder::Stored(encoder) => encoder.finish()
+                        }
+                    }
+                }
+
                 struct Bzip2Compressor {
-                    encoder: BzEncoder<std::fs::File>
+                    encoder:    Option<Bzip2Encoder>,
+                    level:      CompressionLevel,
+                    is_storing: bool
                 }
                 impl Compressor for Bzip2Compressor {
                     fn writer(&mut self) -> &mut dyn std::io::Write {
-                        &mut self.encoder
+                        match self.encoder.as_mut().expect("Compressed stream used after being closed.") {
+                            Bzip2Encoder::Bzip2(encoder)  => encoder,
+                            Bzip2Encoder::Stored(encoder) => encoder
+                        }
                     }
                     fn close(&mut self) -> Result<(), std::io::Error> {
-                        self.encoder.try_finish()
+                        match self.encoder.take() {
+                            Some(encoder) => encoder.finish()?.0.close(),
+                            None          => Ok(())
+                        }
+                    }
+                    fn set_storing(&mut self, is_storing: bool) -> Result<(), std::io::Error> {
+                        if is_storing != self.is_storing {
+                            let sink = self.encoder.take().expect("Compressed stream used after being closed.").finish()?;
+                            let level = if is_storing { CompressionLevel::Store } else { self.level };
+                            self.encoder = Some(Bzip2Encoder::new(sink, &level)?);
+                            self.is_storing = is_storing;
+                        }
+                        Ok(())
                     }
                 }
-                Box::new(Bzip2Compressor {
-                    encoder: BzEncoder::new(out_writer, match level {
-                        CompressionLevel::Best       => bzip2::Compression::best(),
-                        CompressionLevel::Fast       => bzip2::Compression::fast(),
-                        CompressionLevel::Level(lvl) => bzip2::Compression::new((*lvl).into())
-                    }),
-                })
+                Ok(Box::new(Bzip2Compressor {
+                    encoder:    Some(Bzip2Encoder::new(out_writer, level)?),
+                    level:      *level,
+                    is_storing: false
+                }))
             },
             CompressionAlgorithm::Gzip(level)  => {
                 use flate2::write::*;
 
                 struct GzipCompressor {
-                    encoder: GzEncoder<std::fs::File>
+                    encoder:    Option<GzEncoder<CompressorSink>>,
+                    level:      CompressionLevel,
+                    is_storing: bool
                 }
                 impl Compressor for GzipCompressor {
                     fn writer(&mut self) -> &mut dyn std::io::Write {
-                        &mut self.encoder
+                        self.encoder.as_mut().expect("Compressed stream used after being closed.")
+                    }
+                    fn close(&mut self) -> Result<(), std::io::Error> {
+                        match self.encoder.take() {
+                            Some(encoder) => encoder.finish()?.0.close(),
+                            None          => Ok(())
+                        }
+                    }
+                    fn set_storing(&mut self, is_storing: bool) -> Result<(), std::io::Error> {
+                        if is_storing != self.is_storing {
+                            let sink = self.encoder.take().expect("Compressed stream used after being closed.").finish()?;
+                            let level = if is_storing { CompressionLevel::Store } else { self.level };
+                            self.encoder = Some(GzEncoder::new(sink, gzip_level(&level)));
+                            self.is_storing = is_storing;
+                        }
+                        Ok(())
+                    }
+                }
+                Ok(Box::new(GzipCompressor {
+                    encoder:    Some(GzEncoder::new(out_writer, gzip_level(level))),
+                    level:      *level,
+                    is_storing: false
+                }))
+            },
+            CompressionAlgorithm::Zstd(level)  => {
+                use zstd::stream::write::*;
+
+                struct ZstdCompressor {
+                    encoder:    Option<Encoder<'static, CompressorSink>>,
+                    level:      CompressionLevel,
+                    threads:    usize,
+                    is_storing: bool
+                }
+                impl ZstdCompressor {
+                    fn encoder(sink: CompressorSink, level: &CompressionLevel, threads: usize)
+                               -> Result<Encoder<'static, CompressorSink>, std::io::Error> {
+                        let mut encoder = Encoder::new(sink, match level {
+                            CompressionLevel::Best       => 19,
+                            CompressionLevel::Fast       => 1,
+                            CompressionLevel::Store      => 1,
+                            CompressionLevel::Level(lvl) => (*lvl).into()
+                        })?;
+                        if threads > 1 {
+                            encoder.multithread(threads as u32)?;
+                        }
+                        Ok(encoder)
+                    }
+                }
+                impl Compressor for ZstdCompressor {
+                    fn writer(&mut self) -> &mut dyn std::io::Write {
+                        self.encoder.as_mut().expect("Compressed stream used after being closed.")
                     }
                     fn close(&mut self) -> Result<(), std::io::Error> {
-                        self.encoder.try_finish()
+                        match self.encoder.take() {
+                            Some(encoder) => encoder.finish()?.0.close(),
+                            None          => Ok(())
+                        }
+                    }
+                    fn set_storing(&mut self, is_storing: bool) -> Result<(), std::io::Error> {
+                        if is_storing != self.is_storing {
+                            let sink = self.encoder.take().expect("Compressed stream used after being closed.").finish()?;
+                            let level = if is_storing { CompressionLevel::Store } else { self.level };
+                            self.encoder = Some(ZstdCompressor::encoder(sink, &level, self.threads)?);
+                            self.is_storing = is_storing;
+                        }
+                        Ok(())
                     }
                 }
-                Box::new(GzipCompressor {
-                    encoder: GzEncoder::new(out_writer, match level {
-                        CompressionLevel::Best       => flate2::Compression::best(),
-                        CompressionLevel::Fast       => flate2::Compression::fast(),
-                        CompressionLevel::Level(lvl) => flate2::Compression::new((*lvl).into())
-                    }),
-                })
+                Ok(Box::new(ZstdCompressor {
+                    encoder:    Some(ZstdCompressor::encoder(out_writer, level, threads)?),
+                    level:      *level,
+                    threads,
+                    is_storing: false
+                }))
             },
             CompressionAlgorithm::Zip  => todo!(),
         }
     }
     
     pub fn decode_file(&self, src_path: &Path, dst_path: &Path) -> Result<(), std::io::Error> {
-        let file = std::fs::File::open(src_path)?;
-        use tar::Archive;
+        self.decode_reader(Box::new(std::fs::File::open(src_path)?), dst_path)
+    }
+
+    pub fn decode_reader(&self, file: Box<dyn std::io::Read>, dst_path: &Path) -> Result<(), std::io::Error> {
+        let mut archive = tar::Archive::new(self.decoder(file)?);
+        archive.unpack(dst_path)
+    }
+
+    /// Wraps `file` in a reader which decompresses it.
+    pub fn decoder(&self, file: Box<dyn std::io::Read>) -> Result<Box<dyn std::io::Read>, std::io::Error> {
         match self {
-            CompressionAlgorithm::Bzip2(..) => {
-                use bzip2::read::*;
-                let tar = BzDecoder::new(file);
-                let mut archive = Archive::new(tar);
-                archive.unpack(dst_path)
-            },
-            CompressionAlgorithm::Gzip(..)  => {
-                use flate2::read::*;
-                let tar = GzDecoder::new(file);
-                let mut archive = Archive::new(tar);
-                archive.unpack(dst_path)
-            },
+            CompressionAlgorithm::Bzip2(..) => Ok(Box::new(Bzip2Decoder(Some(Bzip2Stream::Between(std::io::BufReader::new(file)))))),
+            CompressionAlgorithm::Gzip(..)  => Ok(Box::new(flate2::read::MultiGzDecoder::new(file))),
+            CompressionAlgorithm::Zstd(..)  => Ok(Box::new(zstd::stream::read::Decoder::new(file)?)),
             CompressionAlgorithm::Zip       => todo!()
         }
     }
 }
 
+/// bzip2 has no way to store data as is.  Stored data is written as zstd frames instead.
+fn bzip2_level(level: &CompressionLevel) -> bzip2::Compression {
+    match level {
+        CompressionLevel::Best       => bzip2::Compression::best(),
+        CompressionLevel::Fast       => bzip2::Compression::fast(),
+        CompressionLevel::Store      => bzip2::Compression::fast(),
+        CompressionLevel::Level(lvl) => bzip2::Compression::new((*lvl).into())
+    }
+}
+
+/// Reads a bzip2 archive, along with the zstd frames its stored members were written to.
+struct Bzip2Decoder(Option<Bzip2Stream>);
+
+enum Bzip2Stream {
+    Between(std::io::BufReader<Box<dyn std::io::Read>>),
+    Bzip2(bzip2::bufread::BzDecoder<std::io::BufReader<Box<dyn std::io::Read>>>),
+    Stored(zstd::stream::read::Decoder<'static, std::io::BufReader<Box<dyn std::io::Read>>>)
+}
+
+impl std::io::Read for Bzip2Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        use std::io::BufRead;
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            let stream = match self.0.take().expect("Decompressed stream used after failing.") {
+                Bzip2Stream::Between(mut input) => match input.fill_buf()?.first() {
+                    None       => {
+                        self.0 = Some(Bzip2Stream::Between(input));
+                        return Ok(0)
+                    },
+                    // Every bzip2 stream starts with "BZh".
+                    Some(b'B') => Bzip2Stream::Bzip2(bzip2::bufread::BzDecoder::new(input)),
+                    Some(_)    => Bzip2Stream::Stored(zstd::stream::read::Decoder::with_buffer(input)?.single_frame())
+                },
+                Bzip2Stream::Bzip2(mut decoder)  => match decoder.read(buf)? {
+                    0   => Bzip2Stream::Between(decoder.into_inner()),
+                    len => {
+                        self.0 = Some(Bzip2Stream::Bzip2(decoder));
+                        return Ok(len)
+                    }
+                },
+                Bzip2Stream::Stored(mut decoder) => match decoder.read(buf)? {
+                    0   => Bzip2Stream::Between(decoder.finish()),
+                    len => {
+                        self.0 = Some(Bzip2Stream::Stored(decoder));
+                        return Ok(len)
+                    }
+                }
+            };
+            self.0 = Some(stream);
+        }
+    }
+}
+
+fn gzip_level(level: &CompressionLevel) -> flate2::Compression {
+    match level {
+        CompressionLevel::Best       => flate2::Compression::best(),
+        CompressionLevel::Fast       => flate2::Compression::fast(),
+        CompressionLevel::Store      => flate2::Compression::none(),
+        CompressionLevel::Level(lvl) => flate2::Compression::new((*lvl).into())
+    }
+}
+
 pub trait Compressor {
     fn writer(&mut self) -> &mut dyn std::io::Write;
     fn close(&mut self) -> Result<(), std::io::Error>;
+
+    /// Ends the current stream and continues in a new one, which either stores data with as little
+    /// effort as possible or compresses it as before.  Decompressors read both back as one stream,
+    /// except that bzip2 stores data in zstd frames, which only `decoder` reads.
+    /// Layers which do not compress ignore this.
+    fn set_storing(&mut self, _is_storing: bool) -> Result<(), std::io::Error> { Ok(()) }
+}
+
+/// The innermost layer of a compressor stack.
+impl Compressor for std::fs::File {
+    fn writer(&mut self) -> &mut dyn std::io::Write {
+        self
+    }
+    fn close(&mut self) -> Result<(), std::io::Error> {
+        std::io::Write::flush(self)
+    }
+}
+
+/// Discards everything, such as files a dry run would have written.
+impl Compressor for std::io::Sink {
+    fn writer(&mut self) -> &mut dyn std::io::Write {
+        self
+    }
+    fn close(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+/// Adapts the next compressor in a stack so an encoder can write into it.
+pub(crate) struct CompressorSink(pub(crate) Box<dyn Compressor>);
+
+impl std::io::Write for CompressorSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.0.writer().write(buf)
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.0.writer().flush()
+    }
+}
+
+// ----- Block Compression -----------------------------------------------------
+
+/// Size of the blocks compressed on their own by `BlockCompressor`.
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// Compresses its input in blocks, one thread per block, in the style of pigz and pbzip2.  Each
+/// block becomes a complete stream of its own, and standard decompressors read concatenated
+/// streams as one, so the output needs nothing special to be read back.
+struct BlockCompressor<F> {
+    compress:   F,
+    threads:    usize,
+    level:      CompressionLevel,
+    is_storing: bool,
+    blocks:     Vec<Vec<u8>>,
+    is_empty:   bool,
+    output:     CompressorSink
+}
+
+impl <F> BlockCompressor<F> where F: Fn(&[u8], &CompressionLevel) -> Result<Vec<u8>, std::io::Error> + Sync {
+    fn new(output: CompressorSink, threads: usize, level: CompressionLevel, compress: F) -> BlockCompressor<F> {
+        BlockCompressor {
+            compress,
+            threads,
+            level,
+            is_storing: false,
+            blocks:     vec![Vec::with_capacity(BLOCK_SIZE)],
+            is_empty:   true,
+            output
+        }
+    }
+
+    /// Compresses every buffered block in parallel and writes them out in order.
+    fn write_blocks(&mut self) -> Result<(), std::io::Error> {
+        let compress = &self.compress;
+        let level = if self.is_storing { &CompressionLevel::Store } else { &self.level };
+        let compressed = std::thread::scope(|scope| {
+            self.blocks.iter()
+                .map(|block| scope.spawn(move || compress(block, level)))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().expect("Compression thread panicked."))
+                .collect::<Result<Vec<Vec<u8>>, std::io::Error>>()
+        })?;
+        for block in compressed {
+            std::io::Write::write_all(&mut self.output, &block)?;
+        }
+        self.blocks.clear();
+        self.blocks.push(Vec::with_capacity(BLOCK_SIZE));
+        Ok(())
+    }
+}
+
+impl <F> std::io::Write for BlockCompressor<F> where F: Fn(&[u8], &CompressionLevel) -> Result<Vec<u8>, std::io::Error> + Sync {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        let block = self.blocks.last_mut().unwrap();
+        let len = buf.len().min(BLOCK_SIZE - block.len());
+        block.extend_from_slice(&buf[0..len]);
+        self.is_empty &= 0 == len;
+        if BLOCK_SIZE == block.len() {
+            if self.blocks.len() >= self.threads {
+                self.write_blocks()?;
+            }
+            else {
+                self.blocks.push(Vec::with_capacity(BLOCK_SIZE));
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.output.flush()
+    }
+}
+
+impl <F> Compressor for BlockCompressor<F> where F: Fn(&[u8], &CompressionLevel) -> Result<Vec<u8>, std::io::Error> + Sync {
+    fn writer(&mut self) -> &mut dyn std::io::Write {
+        self
+    }
+    fn close(&mut self) -> Result<(), std::io::Error> {
+        // Even empty input needs one stream to be a valid file.
+        if !self.is_empty && self.blocks.last().is_some_and(Vec::is_empty) {
+            self.blocks.pop();
+        }
+        if !self.blocks.is_empty() {
+            self.write_blocks()?;
+        }
+        self.blocks.clear();
+        self.output.0.close()
+    }
+    fn set_storing(&mut self, is_storing: bool) -> Result<(), std::io::Error> {
+        if is_storing != self.is_storing {
+            if self.blocks.iter().any(|block| !block.is_empty()) {
+                self.blocks.retain(|block| !block.is_empty());
+                self.write_blocks()?;
+            }
+            self.is_storing = is_storing;
+        }
+        Ok(())
+    }
+}
+
+// ----- Incompressible Content ------------------------------------------------
+
+/// Mime types of formats which are compressed already.
+pub const DEFAULT_INCOMPRESSIBLE_MIME: &str =
+    "^(image/(jpeg|png|gif|webp)|video/.*|audio/(mpeg|mp4|ogg|aac|flac)|application/(zip|gzip|x-bzip2|x-xz|x-7z-compressed|zstd|vnd\\.rar|x-rar-compressed))$";
+
+/// Decides which files are not worth compressing.  A file is incompressible if its extension is
+/// listed, its guessed mime type matches, or a sample from its start looks random enough.
+#[derive(Debug)]
+pub struct IncompressibleDetector {
+    mime_types:  Option<regex::Regex>,
+    extensions:  Vec<std::string::String>,
+    entropy:     Option<f64>,
+    sample_size: u64,
+    min_size:    u64
+}
+
+impl IncompressibleDetector {
+    /// `entropy` is in bits per byte, from 0 for a constant sample up to 8 for random data.  Files
+    /// smaller than `min_size` are always compressed, since a new stream costs a few bytes.
+    pub fn new(mime_types: Option<regex::Regex>, extensions: Vec<std::string::String>, entropy: Option<f64>,
+               sample_size: u64, min_size: u64) -> IncompressibleDetector {
+        IncompressibleDetector {
+            mime_types,
+            extensions,
+            entropy,
+            sample_size,
+            min_size
+        }
+    }
+
+    pub fn is_incompressible(&self, path: &Path, meta: &std::fs::Metadata) -> bool {
+        if !meta.is_file() || meta.len() < self.min_size {
+            return false
+        }
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if extension.is_some_and(|extension| self.extensions.iter().any(|known| known.eq_ignore_ascii_case(extension))) {
+            log::debug!("{:?} has an incompressible extension.", path);
+            return true
+        }
+        if let Some(regex) = &self.mime_types {
+            if mime_guess::from_path(path).iter().any(|mime| regex.is_match(mime.essence_str())) {
+                log::debug!("{:?} has an incompressible mime type.", path);
+                return true
+            }
+        }
+        match self.entropy {
+            Some(threshold) => match sample_entropy(path, self.sample_size) {
+                Ok(entropy) => {
+                    log::debug!("{:?} has an entropy of {:.2} bits per byte.", path, entropy);
+                    entropy >= threshold
+                },
+                Err(err)    => {
+                    log::debug!("Could not sample {:?}: {:?}", path, err);
+                    false
+                }
+            },
+            None            => false
+        }
+    }
+}
+
+/// Shannon entropy, in bits per byte, of the first `sample_size` bytes of `path`.
+fn sample_entropy(path: &Path, sample_size: u64) -> Result<f64, std::io::Error> {
+    use std::io::Read;
+    let mut sample = Vec::new();
+    std::fs::File::open(path)?.take(sample_size).read_to_end(&mut sample)?;
+    let mut counts = [0u64; 256];
+    for byte in &sample {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    Ok(counts.iter()
+       .filter(|count| **count > 0)
+       .map(|count| {
+           let probability = *count as f64 / len;
+           -probability * probability.log2()
+       })
+       .sum())
+}
+
+/// Extracts the archive at `src_path` into `dst_path`, picking the algorithm from its extension
+/// and decrypting it first if it is encrypted.  Split archives are read from their volumes.
+pub fn decode_archive(storage: &dyn Storage, src_path: &Path, dst_path: &Path, keys: &KeyRing)
+                      -> Result<(), std::io::Error> {
+    let name = src_path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    let (plain_name, is_encrypted) = match name.strip_suffix(crate::encryption::EXTENSION) {
+        Some(plain_name) => (plain_name, true),
+        None             => (name, false)
+    };
+    let algorithm = CompressionAlgorithm::from_extension(plain_name)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                                           format!("{:?} is not a recognized archive.", src_path)))?;
+    let file = crate::volume::open_archive(storage, src_path)?;
+    if is_encrypted {
+        log::debug!("Decrypting {:?}...", src_path);
+        algorithm.decode_reader(keys.decrypt(file)?, dst_path)
+    }
+    else {
+        algorithm.decode_reader(file, dst_path)
+    }
 }
 
 impl std::str::FromStr for CompressionAlgorithm {
@@ -114,16 +572,19 @@ impl std::str::FromStr for CompressionAlgorithm {
         match s.to_lowercase().as_str() {
             "bzip2"   => Ok(CompressionAlgorithm::Bzip2(CompressionLevel::Fast)),
             "gzip"    => Ok(CompressionAlgorithm::Gzip(CompressionLevel::Fast)),
+            "zstd"    => Ok(CompressionAlgorithm::Zstd(CompressionLevel::Fast)),
             "zip"     => Ok(CompressionAlgorithm::Zip),
             algorithm => Err(crate::config::ParseError::UnknownOption(algorithm.to_string())),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy)]
 pub enum CompressionLevel {
     Fast,
     Best,
+    /// As little effort as the format allows, for data which will not get any smaller.
+    Store,
     Level(u8)
 }
 
@@ -132,12 +593,13 @@ impl std::str::FromStr for CompressionLevel {
 
     fn from_str(s: &str) -> Result<CompressionLevel, crate::config::Error> {
         match s.to_lowercase().as_str() {
-            "best" => Ok(CompressionLevel::Best),
-            "fast" => Ok(CompressionLevel::Fast),
+            "best"  => Ok(CompressionLevel::Best),
+            "fast"  => Ok(CompressionLevel::Fast),
+            "store" => Ok(CompressionLevel::Store),
             level_str if level_str.len() == 1 && level_str.chars().nth(0).unwrap().is_numeric() => {
                 Ok(CompressionLevel::Level(level_str.parse::<u8>().unwrap()))
             },
-            level  => Err(crate::config::ParseError::UnknownOption(level.to_string())),
+            level   => Err(crate::config::ParseError::UnknownOption(level.to_string())),
         }
     }
 }
@@ -1,66 +1,204 @@
 use std::vec::Vec;
+use std::borrow::Cow;
 use std::path::{Path,PathBuf};
 use regex::Regex;
 use json::JsonValue;
 use std::fs;
-use crate::backup::BackupOutputStream;
+use std::sync::{Arc,Mutex};
+use rayon::prelude::*;
+use crate::backup::{dedup,BackupOutputStream,ConcurrentOutputStream,SharedOutputStream};
 use crate::backup::copy::CopyOutputStream;
 use crate::backup::tar::TarOutputStream;
 use crate::backup::hardlink::{HardLinkOutputStream,ChangeDetectionMethod};
+use crate::backup::chunk::{self,ChunkStore,ChunkedOutputStream};
+use crate::backup::filecompress::FileCompressedOutputStream;
+use crate::backup::delta::DeltaOutputStream;
+use crate::backup::incremental::{ArchiveMode,IncrementalOutputStream,SnapshotFile};
 use crate::compression::*;
+use crate::encryption::{Encryption,KeyRing};
+use crate::volume::VolumeWriter;
+use crate::storage::{LocalStorage,Storage};
+use crate::remote::RemoteStorage;
+use crate::agent::PullSource;
+use crate::s3::{self,S3Location,S3Storage};
+use crate::replica::Replica;
+use crate::pool::{DestinationPool,DiskIdentity,PoolDisk};
+use crate::include;
+use crate::interpolate::interpolate;
+use crate::hook::{FailurePolicy,Hook,HookEnv,Hooks};
+use crate::format::Format;
+use crate::exclude::{self,IgnoreStack};
+use ignore::gitignore::Gitignore;
 
 // ----- Public Data Structures ------------------------------------------------
 
 #[derive(Debug)]
 pub struct Configuration {
-    pub root_dir_config: Box<dyn DirectoryConfig>,
+    /// Every tree backed up into each snapshot.  Relative subpaths are found in the working
+    /// directory.
+    pub root_dir_configs: Vec<Box<dyn DirectoryConfig>>,
+    /// Where the only tree in `root_dir_configs` is pulled from, if it is on another machine.
+    pub source:          Option<PullSource>,
     pub destination_dir: PathBuf,
+    /// Disks taking turns as `destination_dir`.
+    pub pool:            Option<DestinationPool>,
+    /// Where `destination_dir` is kept.
+    pub storage:         Arc<dyn Storage>,
     pub verbosity:       log::LevelFilter,
     pub name_format:     std::string::String,
     pub keep_limit:      Vec<KeepLimit>,
-    // TODO: pre/post exec commands
-    // [tflucke] 2021-12-30: Need to parse everything first,
-    // then construct a std::process::Command object in `backup`,
-    // then execute it.
-    // TODO: Remote sync
+    /// Destinations each new snapshot is copied to once it is taken.
+    pub replicas:        Vec<Replica>,
+    /// Number of threads walking and copying the source tree.  0 uses one per CPU.
+    pub threads:         u64,
+    /// Run around the whole backup, including pulling the source.
+    pub hooks:           Hooks
 }
 
 impl Configuration {
-    pub fn new(filename: &str) -> Result<Configuration, Error> {
-        match json::parse(filename) {
-            Ok(json)      => match json {
-                json::JsonValue::Object(obj) => Ok(Configuration {
-                    root_dir_config: <dyn DirectoryConfig>::new(
-                        obj.get("root_dir_config").ok_or(ParseError::RequiredPropMissing("root_dir_config"))?
-                    )?.0,
-                    destination_dir: PathBuf::from(str_from_json_prop(&obj, "destination_dir")?),
-                    verbosity:       log_level_from_str(str_from_opt_json_prop(&obj, "verbosity", "warning")?)?,
-                    name_format:     str_from_opt_json_prop(&obj, "name_format", "%Y-%m-%d_%H-%M-%S")?.to_string(),
-                    keep_limit:      KeepLimit::new_vec(obj.get("keep limit"))?
-                }),
-                _                            => Err(ParseError::NotAnObject(""))
-            },
-            Err(json_err) => Err(ParseError::JsonError(json_err))
+    /// Reads a config written in `format`.  Every format is read into the same tree of values,
+    /// which is all the rest of the config sees.
+    pub fn new(contents: &str, format: Format) -> Result<Configuration, Error> {
+        Configuration::with_overrides(contents, format, &[])
+    }
+
+    /// Like `new`, but first replaces the properties named by `overrides`, so they are checked like
+    /// everything else.
+    pub fn with_overrides(contents: &str, format: Format, overrides: &[Override]) -> Result<Configuration, Error> {
+        let mut json = include::resolve(format.parse(contents)?)?;
+        for property in overrides {
+            property.apply(&mut json)?;
+        }
+        Configuration::from_json(json)
+    }
+
+    /// Reads the config in `path`, along with every file it extends, then applies `overrides`.
+    pub fn load(path: &Path, overrides: &[Override]) -> Result<Configuration, Error> {
+        Configuration::from_json(Configuration::load_json(path, overrides)?)
+    }
+
+    /// Like `load`, but stops at the merged JSON, such as to look it over with `check::check`
+    /// first.
+    pub fn load_json(path: &Path, overrides: &[Override]) -> Result<JsonValue, Error> {
+        let mut json = include::load(path)?;
+        for property in overrides {
+            property.apply(&mut json)?;
         }
+        Ok(json)
     }
 
-    pub fn backup(&self, ref_path: Option<&Path>) -> Result<PathBuf, std::io::Error> {
+    pub fn from_json(json: JsonValue) -> Result<Configuration, Error> {
+        match json {
+            json::JsonValue::Object(obj) => ParsedConfiguration::from_object(&obj)
+                .and_then(ParsedConfiguration::open)
+                .map_err(|err| include::attribute(&obj, err)),
+            _                            => Err(ParseError::NotAnObject(""))
+        }
+    }
+
+    /// Parses `json` like `from_json`, but without starting destination commands or looking for
+    /// the disks of a pool, so a config can be checked without side effects.
+    pub fn check_json(json: JsonValue) -> Result<(), Error> {
+        match json {
+            json::JsonValue::Object(obj) => ParsedConfiguration::from_object(&obj)
+                .map(drop)
+                .map_err(|err| include::attribute(&obj, err)),
+            _                            => Err(ParseError::NotAnObject(""))
+        }
+    }
+
+    /// Takes a new snapshot.  `history` holds the earlier snapshots, newest first.  Once taken,
+    /// the snapshot is returned even if a post-hook then fails, along with that failure.
+    pub fn backup(&self, history: &[&Path]) -> Result<(PathBuf, Result<(), std::io::Error>), std::io::Error> {
         let format = self.name_format.as_str();
         log::info!("output directory format: {:?}", format);
         let now = chrono::Local::now();
-        let dst = self.destination_dir.join(Path::new(&now.format(format).to_string()));
-        fs::create_dir(&dst)?;
-        let mut out = CopyOutputStream::new(&dst);
-        self.root_dir_config.backup(self.root_dir_config.get_subpath(), &dst, &mut out, ref_path)?;
-        return Ok(dst)
+        let dst = self.storage.root().join(Path::new(&now.format(format).to_string()));
+        let env = HookEnv { snapshot: &dst, destination: self.storage.root(), source: None };
+        self.hooks.around_keeping(&env, || self.pull_and_backup(&dst, history))
+            .map(|((), post_result)| (dst, post_result))
+    }
+
+    /// The key file for reading encrypted archives back, if any directory config names one.
+    pub fn identity_file(&self) -> Option<&Path> {
+        self.root_dir_configs.iter().find_map(|config| config.get_identity_file())
+    }
+
+    fn pull_and_backup(&self, dst: &Path, history: &[&Path]) -> Result<(), std::io::Error> {
+        let previous_dir = match &self.source {
+            Some(source) => {
+                // A pulled source is always the only root.
+                source.pull(self.root_dir_configs[0].get_subpath())?;
+                if source.is_dry_run && !source.cache_dir.is_dir() {
+                    log::info!("Nothing has been pulled into {:?} yet, so there is nothing to back up.",
+                               source.cache_dir);
+                    return Ok(())
+                }
+                // The mirror is laid out like the agent's working directory, so backing
+                // up from inside it names everything the same as a local backup would.
+                let previous_dir = std::env::current_dir()?;
+                std::env::set_current_dir(&source.cache_dir)?;
+                Some(previous_dir)
+            },
+            None         => None
+        };
+        let result = self.backup_to(dst, history);
+        if let Some(previous_dir) = previous_dir {
+            std::env::set_current_dir(previous_dir)?;
+        }
+        result
+    }
+
+    fn backup_to(&self, dst: &Path, history: &[&Path]) -> Result<(), std::io::Error> {
+        self.storage.create_dir(dst)?;
+        let mut out = CopyOutputStream::new(self.storage.as_ref(), dst);
+        let mut backup_roots = || -> Result<(), std::io::Error> {
+            for root in &self.root_dir_configs {
+                log::info!("Backing up {:?}...", root.get_subpath());
+                let root_dir = crate::backup::append_path(dst, root.get_subpath());
+                // Parents of an absolute root are created without their metadata, like
+                // the parents of a relative one always were.
+                if let Some(parent) = root_dir.parent().filter(|parent| *parent != dst) {
+                    self.storage.create_dir_all(parent)?;
+                }
+                root.backup_with_hooks(root.get_subpath(), &IgnoreStack::default(), dst, &self.storage, &mut out, history)?;
+            }
+            Ok(())
+        };
+        if 1 == self.threads {
+            backup_roots()?;
+        }
+        else {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(self.threads as usize)
+                .build()
+                .map_err(std::io::Error::other)?;
+            log::info!("Backing up with {} threads...", pool.current_num_threads());
+            pool.install(backup_roots)?;
+        }
+        return Ok(())
     }
 }
 
-pub trait DirectoryConfig: std::fmt::Debug {
-    fn backup(&self, src: &Path, dst: &Path, out: &mut dyn BackupOutputStream, last: Option<&Path>)
-              -> Result<(), std::io::Error>;
+pub trait DirectoryConfig: std::fmt::Debug + Sync {
+    /// Backs up `src` into `dst`.  `ignores` holds the ignore files of the directories above
+    /// `src`, which apply inside it as well.
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error>;
     fn get_subpath(&self) -> &Path;
     fn get_subconfig(&self, path: &Path) -> Option<&dyn DirectoryConfig>;
+    fn get_hooks(&self) -> &Hooks;
+    /// The first key file given for decrypting archives in this tree.
+    fn get_identity_file(&self) -> Option<&Path>;
+}
+
+/// A property set on the command line, such as `root_dir_config.space_mode=linked`.  Each part of
+/// the path is a key, or an index into an array.  The value is read as JSON, or failing that, taken
+/// as a string.
+#[derive(Debug)]
+pub struct Override {
+    path:  Vec<std::string::String>,
+    value: JsonValue
 }
 
 #[derive(Debug,Eq,PartialEq)]
@@ -74,26 +212,221 @@ pub type Error = ParseError;
 #[derive(Debug)]
 pub enum ParseError {
     JsonError(json::Error),
+    TomlError(toml::de::Error),
+    YamlError(yaml_rust2::ScanError),
+    /// A value, such as a YAML alias, which has no equivalent in JSON.
+    UnsupportedValue(std::string::String),
+    /// A `${NAME}` with no default names a variable which is not set.
+    UndefinedVariable(std::string::String),
+    /// A `${` is not closed, or does not hold a variable name.
+    BadInterpolation(std::string::String),
     NotAnObject(&'static str),
     NotAString(&'static str),
     NotAnArray(&'static str),
     NotAnUnsignedInt(&'static str),
+    NotANumber(&'static str),
     UnknownOption(std::string::String),
     RequiredPropMissing(&'static str),
     BadRegex(std::string::String, regex::Error),
+    BadGlob(ignore::Error),
+    BadKey(std::string::String),
     CannotCompressNonbasic,
+    CannotCompressFiles(std::string::String),
+    CannotChunkNonbasic,
+    CannotDeduplicate,
+    /// Encrypted listings of incremental archives cannot be read back without a key.
+    NoIdentityFile,
+    PullSubpathNotRelative,
+    ConflictingDestinations,
+    PartTooSmall(u64),
+    BadReplicaName(std::string::String),
+    ConflictingIdentities,
+    NoDiskPresent,
+    BadOverride(std::string::String),
+    IncludeCycle(PathBuf),
+    ConflictingRoots,
+    OverlappingRoots(PathBuf, PathBuf),
+    PullSeveralRoots,
+    /// The value an error is about was defined in this file.
+    InFile(PathBuf, Box<ParseError>),
+    /// The error is about something inside this property, or this element of an array, as in
+    /// `subconfigs[3]`.
+    At(std::string::String, Box<ParseError>),
+    NotABool(&'static str),
     IoError(PathBuf, std::io::Error),
     PatternError(&'static str, std::string::String),
 }
 
+impl ParseError {
+    /// Wraps errors about the contents of `key` so they name where they are.
+    fn at<K: std::fmt::Display>(key: K) -> impl FnOnce(ParseError) -> ParseError {
+        move |err| ParseError::At(key.to_string(), Box::new(err))
+    }
+
+    /// The property the innermost error is about, if it names one.
+    pub(crate) fn prop(&self) -> Option<&'static str> {
+        match self {
+            ParseError::NotAnObject(prop)         |
+            ParseError::NotAString(prop)          |
+            ParseError::NotAnArray(prop)          |
+            ParseError::NotAnUnsignedInt(prop)    |
+            ParseError::NotANumber(prop)          |
+            ParseError::NotABool(prop)            |
+            ParseError::RequiredPropMissing(prop) => Some(*prop).filter(|prop| !prop.is_empty()),
+            _                                     => None
+        }
+    }
+}
+
+/// Names the file and JSON path of the value an error is about, as in
+/// `tsnapshot.json: root_dir_config.subconfigs[3].filters[1].pattern: expected a string`.
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut file = None;
+        let mut path = std::string::String::new();
+        let mut err = self;
+        loop {
+            match err {
+                ParseError::InFile(inner_file, inner) => {
+                    // The innermost file is the one which defined the value.
+                    file = Some(inner_file);
+                    err = inner;
+                },
+                ParseError::At(key, inner)            => {
+                    append_key(&mut path, key);
+                    err = inner;
+                },
+                _                                     => break
+            }
+        }
+        if let Some(prop) = err.prop() {
+            append_key(&mut path, prop);
+        }
+        if let Some(file) = file {
+            write!(f, "{}: ", file.display())?;
+        }
+        if !path.is_empty() {
+            write!(f, "{}: ", path)?;
+        }
+        match err {
+            ParseError::JsonError(err)            => write!(f, "invalid JSON: {}", err),
+            ParseError::TomlError(err)            => write!(f, "invalid TOML: {}", err),
+            ParseError::YamlError(err)            => write!(f, "invalid YAML: {}", err),
+            ParseError::UnsupportedValue(what)    => write!(f, "{} cannot be used in a config", what),
+            ParseError::UndefinedVariable(name)   => write!(f, "${{{}}} is not set, and has no default as in ${{{}:-default}}", name, name),
+            ParseError::BadInterpolation(value)   => write!(f, "bad ${{...}} in {:?}", value),
+            ParseError::NotAnObject(_)            => write!(f, "expected an object"),
+            ParseError::NotAString(_)             => write!(f, "expected a string"),
+            ParseError::NotAnArray(_)             => write!(f, "expected an array"),
+            ParseError::NotAnUnsignedInt(_)       => write!(f, "expected a non-negative integer"),
+            ParseError::NotANumber(_)             => write!(f, "expected a number"),
+            ParseError::NotABool(_)               => write!(f, "expected true or false"),
+            ParseError::UnknownOption(option)     => write!(f, "unknown option {:?}", option),
+            ParseError::RequiredPropMissing(_)    => write!(f, "required property is missing"),
+            ParseError::BadRegex(regex, err)      => write!(f, "bad regular expression {:?}: {}", regex, err),
+            ParseError::BadGlob(err)              => write!(f, "bad glob pattern: {}", err),
+            ParseError::BadKey(key)               => write!(f, "bad encryption recipient {:?}", key),
+            ParseError::CannotCompressNonbasic    => write!(f, "subconfigs of a compressed directory cannot set a space_mode"),
+            ParseError::CannotCompressFiles(alg)  => write!(f, "compress_files cannot compress single files with {}", alg),
+            ParseError::CannotChunkNonbasic       => write!(f, "subconfigs of a chunked directory cannot set a space_mode"),
+            ParseError::CannotDeduplicate         => write!(f, "deduplicate only works with the none and linked space modes"),
+            ParseError::NoIdentityFile            => write!(f, "incremental and differential archives need an identity_file to read the last encrypted listing"),
+            ParseError::PullSubpathNotRelative    => write!(f, "the subpath of a pulled root must be relative"),
+            ParseError::ConflictingDestinations   => write!(f, "destination_pool cannot be combined with another destination, nor destination_command with destination_s3"),
+            ParseError::PartTooSmall(size)        => write!(f, "part_size {} is below the minimum of {} bytes", size, s3::MIN_PART_SIZE),
+            ParseError::BadReplicaName(name)      => write!(f, "replica name {:?} may only hold letters, digits, '_', '.' and '-'", name),
+            ParseError::ConflictingIdentities     => write!(f, "a disk is identified by a marker or a uuid, not both"),
+            ParseError::NoDiskPresent             => write!(f, "none of the disks in destination_pool is present"),
+            ParseError::BadOverride(property)     => write!(f, "bad override {:?}, expected key.path=value", property),
+            ParseError::IncludeCycle(file)        => write!(f, "{} extends itself", file.display()),
+            ParseError::ConflictingRoots          => write!(f, "root_dir_config and root_dir_configs cannot both be given"),
+            ParseError::OverlappingRoots(a, b)    => write!(f, "roots {} and {} overlap", a.display(), b.display()),
+            ParseError::PullSeveralRoots          => write!(f, "only a lone root_dir_config can be pulled through an agent"),
+            ParseError::IoError(file, err)        => write!(f, "{}: {}", file.display(), err),
+            ParseError::PatternError(what, line)  => write!(f, "{} {:?}", what, line),
+            ParseError::InFile(..)                |
+            ParseError::At(..)                    => unreachable!()
+        }
+    }
+}
+
+/// Appends `key` to a JSON path like `root_dir_config.subconfigs[3]`.
+pub(crate) fn append_key(path: &mut std::string::String, key: &str) {
+    if !path.is_empty() {
+        path.push('.');
+    }
+    path.push_str(key);
+}
+
 // ----- Json Parsing Functions ------------------------------------------------
 
+impl Override {
+    /// Sets the property at `path` to the string `value`.
+    pub fn new(path: &str, value: &str) -> Override {
+        Override {
+            path:  path.split('.').map(str::to_string).collect(),
+            value: JsonValue::from(value)
+        }
+    }
+
+    fn apply(&self, json: &mut JsonValue) -> Result<(), Error> {
+        let bad_path = || ParseError::BadOverride(self.path.join("."));
+        let (last, parents) = self.path.split_last().ok_or_else(bad_path)?;
+        let mut current = json;
+        for key in parents {
+            current = match current {
+                JsonValue::Object(obj) => {
+                    if obj.get(key).is_none() {
+                        obj.insert(key, JsonValue::new_object());
+                    }
+                    obj.get_mut(key).unwrap()
+                },
+                JsonValue::Array(vec)  => {
+                    let index = key.parse::<usize>().ok().filter(|index| *index < vec.len()).ok_or_else(bad_path)?;
+                    &mut vec[index]
+                },
+                _                      => return Err(bad_path())
+            };
+        }
+        match current {
+            JsonValue::Object(obj) => {
+                obj.insert(last, self.value.clone());
+                include::set_origin(obj, last, "the command line");
+            },
+            JsonValue::Array(vec)  => match last.parse::<usize>() {
+                Ok(index) if index < vec.len()  => vec[index] = self.value.clone(),
+                Ok(index) if index == vec.len() => vec.push(self.value.clone()),
+                _                               => return Err(bad_path())
+            },
+            _                      => return Err(bad_path())
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Override {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Override, Error> {
+        let (path, value) = s.split_once('=').ok_or_else(|| ParseError::BadOverride(s.to_string()))?;
+        if path.is_empty() {
+            return Err(ParseError::BadOverride(s.to_string()))
+        }
+        Ok(Override {
+            path:  path.split('.').map(str::to_string).collect(),
+            value: json::parse(value).unwrap_or_else(|_| JsonValue::from(value))
+        })
+    }
+}
+
 impl KeepLimit {
     fn new_vec(json: Option<&json::JsonValue>) -> Result<Vec<KeepLimit>, Error> {
         match json {
             None                         => Ok(vec![]),
             Some(JsonValue::Array(vec))  => {
-                let mut out = vec.iter().map(KeepLimit::new).collect::<Result<Vec<KeepLimit>, Error>>()?;
+                let mut out = vec.iter().enumerate()
+                    .map(|(index, json)| KeepLimit::new(json).map_err(ParseError::at(format!("keep limit[{}]", index))))
+                    .collect::<Result<Vec<KeepLimit>, Error>>()?;
                 out.sort();
                 Ok(out)
             },
@@ -105,15 +438,15 @@ impl KeepLimit {
         match json {
             JsonValue::Object(obj) => Ok(KeepLimit {
                 count:    uint_from_json_prop(obj, "count")?,
-                timespan: KeepLimit::parse_timespan(obj.get("timespan"))?
+                timespan: KeepLimit::parse_timespan(obj.get("timespan"), "timespan")?
             }),
-            _                     => Err(ParseError::NotAnObject("keep limit"))
+            _                     => Err(ParseError::NotAnObject(""))
         }
     }
 
-    fn parse_timespan(json: Option<&json::JsonValue>) -> Result<u64, Error> {
+    fn parse_timespan(json: Option<&json::JsonValue>, prop: &'static str) -> Result<u64, Error> {
         match json {
-            Some(JsonValue::Object(obj)) => {
+            Some(JsonValue::Object(obj)) => (|| {
                 let seconds = uint_from_opt_json_prop(obj, "seconds", 0)?;
                 let minutes = uint_from_opt_json_prop(obj, "minutes", 0)?;
                 let hours   = uint_from_opt_json_prop(obj, "hours", 0)?;
@@ -127,9 +460,9 @@ impl KeepLimit {
                         days * 24 * 60 * 60 +
                         months * 30 * 24 * 60 * 60 +
                         years * 365 * 24 * 60 * 60
-                )},
-            Some(_)                     => Err(ParseError::NotAnObject("timespan")),
-            None                        => Err(ParseError::RequiredPropMissing("timespan"))
+                )})().map_err(ParseError::at(prop)),
+            Some(_)                     => Err(ParseError::NotAnObject(prop)),
+            None                        => Err(ParseError::RequiredPropMissing(prop))
         }
     }
 }
@@ -160,28 +493,42 @@ impl std::cmp::PartialOrd for KeepLimit {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> { Some(self.cmp(other)) }
 }
 
+impl <'a> dyn DirectoryConfig + 'a {
+    /// Backs up `src` between the config's pre- and post-hooks.
+    fn backup_with_hooks(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+                         out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        let env = HookEnv { snapshot: dst, destination: storage.root(), source: Some(src) };
+        self.get_hooks().around(&env, || self.backup(src, ignores, dst, storage, out, history))
+    }
+}
+
 impl dyn DirectoryConfig {
     fn new<'a>(json: &'a JsonValue) -> Result<(Box<dyn DirectoryConfig>, bool), Error> {
         match json {
-            json::JsonValue::Object(obj) => {
-                if let Some(space_mode) = obj.get("space_mode") {
-                    match space_mode.as_str() {
-                        Some("none")     => {
-                            let (config, is_only_basics) = BasicDirectory::new(obj)?;
-                            Ok((Box::new(config), is_only_basics))
-                        }
-                        Some("compress") => { CompressedDirectory::new(obj).map(|config| (config, false)) }
-                        Some("linked")   => { HardLinkedDirectory::new(obj).map(|config| (config, false)) }
-                        Some(typ)        => { Err(ParseError::UnknownOption(typ.to_string())) }
-                        None             => { Err(ParseError::NotAString("space_mode")) }
-                    }
-                }
-                else {
+            json::JsonValue::Object(obj) => <dyn DirectoryConfig>::from_object(obj).map_err(|err| include::attribute(obj, err)),
+            _ => Err(ParseError::NotAnObject(""))
+        }
+    }
+
+    fn from_object(obj: &json::object::Object) -> Result<(Box<dyn DirectoryConfig>, bool), Error> {
+        if let Some(space_mode) = obj.get("space_mode") {
+            match space_mode.as_str() {
+                Some("none")     => {
                     let (config, is_only_basics) = BasicDirectory::new(obj)?;
                     Ok((Box::new(config), is_only_basics))
                 }
-            },
-            _ => Err(ParseError::NotAnObject(""))
+                Some("compress") => { Ok((Box::new(CompressedDirectory::new(obj)?), false)) }
+                Some("linked")   => { Ok((Box::new(HardLinkedDirectory::new(obj)?), false)) }
+                Some("chunked")  => { Ok((Box::new(ChunkedDirectory::new(obj)?), false)) }
+                Some("delta")    => { Ok((Box::new(DeltaDirectory::new(obj)?), false)) }
+                Some("compress_files") => { Ok((Box::new(FileCompressedDirectory::new(obj)?), false)) }
+                Some(typ)        => { Err(ParseError::At("space_mode".to_string(), Box::new(ParseError::UnknownOption(typ.to_string())))) }
+                None             => { Err(ParseError::NotAString("space_mode")) }
+            }
+        }
+        else {
+            let (config, is_only_basics) = BasicDirectory::new(obj)?;
+            Ok((Box::new(config), is_only_basics))
         }
     }
 
@@ -189,8 +536,9 @@ impl dyn DirectoryConfig {
         match json {
             None => Ok((vec![], true)),
             Some(JsonValue::Array(vec)) => {
-                let subconfig_results: Result<Vec<(Box<dyn DirectoryConfig>, bool)>, ParseError> =
-                    vec.iter().map(<dyn DirectoryConfig>::new).collect();
+                let subconfig_results: Result<Vec<(Box<dyn DirectoryConfig>, bool)>, ParseError> = vec.iter().enumerate()
+                    .map(|(index, json)| <dyn DirectoryConfig>::new(json).map_err(ParseError::at(format!("subconfigs[{}]", index))))
+                    .collect();
                 let (configs, is_only_basics): (Vec<Box<dyn DirectoryConfig>>, Vec<bool>) =
                     subconfig_results?.into_iter().unzip();
                 return Ok((configs, is_only_basics.iter().all(|is_only_basic| *is_only_basic)));
@@ -202,48 +550,187 @@ impl dyn DirectoryConfig {
 
 #[derive(Debug)]
 pub struct BasicDirectory {
-    subpath:    PathBuf,
-    subconfigs: Vec<Box<dyn DirectoryConfig>>,
-    filters:    Vec<Filter>,
+    subpath:      PathBuf,
+    subconfigs:   Vec<Box<dyn DirectoryConfig>>,
+    filters:      Vec<Filter>,
+    /// Names of the files, such as `.tsnapshotignore`, whose patterns exclude paths in the
+    /// directory they are in and below.
+    ignore_files: Vec<std::string::String>,
+    deduplicate:  bool,
+    hooks:        Hooks
 }
 
 impl BasicDirectory {
     fn new(obj: &json::object::Object) -> Result<(BasicDirectory, bool), Error> {
         let (subconfigs, is_only_basic) = <dyn DirectoryConfig>::new_vec(obj.get("subconfigs"))?;
         Ok((BasicDirectory {
-            subpath:    PathBuf::from(str_from_json_prop(&obj, "subpath")?),
-            subconfigs: subconfigs,
-            filters:    Filter::new_vec(obj.get("filters"))?
+            subpath:      PathBuf::from(str_from_json_prop(&obj, "subpath")?.into_owned()),
+            subconfigs:   subconfigs,
+            filters:      Filter::new_vec(obj.get("filters"))?,
+            ignore_files: strs_from_opt_json_prop(obj, "ignore_files", &[".tsnapshotignore"])?,
+            deduplicate:  bool_from_opt_json_prop(obj, "deduplicate", false)?,
+            hooks:        hooks_from_json(obj)?
         }, is_only_basic))
     }
+
+    /// For space modes which do not write a plain copy that could be deduplicated.
+    fn without_deduplication(self) -> Result<BasicDirectory, Error> {
+        if self.deduplicate {
+            return Err(ParseError::CannotDeduplicate);
+        }
+        Ok(self)
+    }
 }
 
-impl DirectoryConfig for BasicDirectory {
-    fn backup(&self, src: &Path, dst: &Path, out: &mut dyn BackupOutputStream, last: Option<&Path>)
-              -> Result<(), std::io::Error> {
+impl BasicDirectory {
+    /// Backs up `src`, which is under `root`, the directory this config was asked to back up.
+    /// `ignores` holds the ignore files of the directories between them.
+    #[allow(clippy::too_many_arguments)]
+    fn backup_tree(&self, src: &Path, root: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+                   out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        // Inside a thread pool, copies are written in parallel while everything else, such as
+        // tar archives, is written in walk order by this thread.
+        if rayon::current_thread_index().is_some() {
+            if let Some(shared) = out.as_concurrent() {
+                return self.backup_concurrently(src, root, ignores, dst, storage, shared, history)
+            }
+            log::debug!("Reading metadata for {:?}...", src);
+            let meta = src.symlink_metadata()?;
+            return self.backup_in_order(src, meta, root, ignores, dst, storage, out, history)
+        }
         log::debug!("Reading metadata for {:?}...", src);
         let meta = src.symlink_metadata()?;
-        log::debug!("Checking if {:?} should be filtered...", src);
-        for filter in &self.filters {
-            if filter.matches(src, &meta) {
-                log::debug!("Skipping {:?} because it matches filter {:?}", src, filter);
-                return Ok(())
-            }
+        if self.is_filtered(src, root, ignores, &meta) {
+            return Ok(())
         }
         if let Some(new_config) = self.get_subconfig(src) {
             log::debug!("Backing up {:?} using new config {:?}...",
                         src, new_config.get_subpath());
-            new_config.backup(src, dst, out, last)?;
+            new_config.backup_with_hooks(src, ignores, dst, storage, out, history)?;
         }
         else {
             log::debug!("Sending {:?} to backup stream...", src);
             out.append_file(src)?;
             if meta.file_type().is_dir() {
                 log::debug!("{:?} is directory.  Backing up subdirectories...", src);
+                let ignores = ignores.enter(src, &self.ignore_files)?;
                 for entry_res in fs::read_dir(src)? {
-                    self.backup(&entry_res?.path(), dst, out, last)?;
+                    self.backup_tree(&entry_res?.path(), root, &ignores, dst, storage, out, history)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_filtered(&self, src: &Path, root: &Path, ignores: &IgnoreStack, meta: &fs::Metadata) -> bool {
+        log::debug!("Checking if {:?} should be filtered...", src);
+        if ignores.is_ignored(src, meta.is_dir()) {
+            log::debug!("Skipping {:?} because an ignore file excludes it", src);
+            return true
+        }
+        for filter in &self.filters {
+            if filter.matches(src, root, meta) {
+                log::debug!("Skipping {:?} because it matches filter {:?}", src, filter);
+                return true
+            }
+        }
+        false
+    }
+
+    /// Walks `src` on the current thread pool, appending each path as soon as its parent has been
+    /// appended.  The first error stops new work from being queued and is returned.
+    #[allow(clippy::too_many_arguments)]
+    fn backup_concurrently(&self, src: &Path, root: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+                           out: &dyn ConcurrentOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        let error = Mutex::new(None);
+        rayon::scope(|scope| self.spawn_backup(scope, src.to_path_buf(), root, ignores.clone(), dst, storage, out,
+                                               history, &error));
+        match error.into_inner().unwrap() {
+            Some(err) => Err(err),
+            None      => Ok(())
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn spawn_backup<'s>(&'s self, scope: &rayon::Scope<'s>, src: PathBuf, root: &'s Path, ignores: IgnoreStack,
+                        dst: &'s Path, storage: &'s Arc<dyn Storage>, out: &'s dyn ConcurrentOutputStream,
+                        history: &'s [&'s Path], error: &'s Mutex<Option<std::io::Error>>) {
+        if error.lock().unwrap().is_some() {
+            return
+        }
+        let result = (|| {
+            log::debug!("Reading metadata for {:?}...", src);
+            let meta = src.symlink_metadata()?;
+            if self.is_filtered(&src, root, &ignores, &meta) {
+                return Ok(())
+            }
+            if let Some(new_config) = self.get_subconfig(&src) {
+                log::debug!("Backing up {:?} using new config {:?}...",
+                            src, new_config.get_subpath());
+                return new_config.backup_with_hooks(&src, &ignores, dst, storage, &mut SharedOutputStream(out), history)
+            }
+            log::debug!("Sending {:?} to backup stream...", src);
+            out.append_file_concurrently(&src)?;
+            if meta.file_type().is_dir() {
+                log::debug!("{:?} is directory.  Queueing subdirectories...", src);
+                let ignores = ignores.enter(&src, &self.ignore_files)?;
+                for entry_res in fs::read_dir(&src)? {
+                    let path = entry_res?.path();
+                    let ignores = ignores.clone();
+                    scope.spawn(move |scope| self.spawn_backup(scope, path, root, ignores, dst, storage, out, history, error));
                 }
             }
+            Ok(())
+        })();
+        if let Err(err) = result {
+            error.lock().unwrap().get_or_insert(err);
+        }
+    }
+
+    /// Backs up `src`, whose metadata is `meta`, for a stream which can only be written by one
+    /// thread, in the order it must be written.  The entries of each directory have their metadata
+    /// read together on the current thread pool, so only the directories being walked are held.
+    #[allow(clippy::too_many_arguments)]
+    fn backup_in_order(&self, src: &Path, meta: fs::Metadata, root: &Path, ignores: &IgnoreStack, dst: &Path,
+                       storage: &Arc<dyn Storage>, out: &mut dyn BackupOutputStream, history: &[&Path])
+                       -> Result<(), std::io::Error> {
+        if self.is_filtered(src, root, ignores, &meta) {
+            return Ok(())
+        }
+        if let Some(new_config) = self.get_subconfig(src) {
+            log::debug!("Backing up {:?} using new config {:?}...", src, new_config.get_subpath());
+            return new_config.backup_with_hooks(src, ignores, dst, storage, out, history)
+        }
+        log::debug!("Sending {:?} to backup stream...", src);
+        out.append_file(src)?;
+        if meta.file_type().is_dir() {
+            let ignores = ignores.enter(src, &self.ignore_files)?;
+            let entries = fs::read_dir(src)?.collect::<Result<Vec<fs::DirEntry>, std::io::Error>>()?;
+            let children = entries.par_iter()
+                .map(|entry| {
+                    log::debug!("Reading metadata for {:?}...", entry.path());
+                    entry.path().symlink_metadata().map(|meta| (entry.path(), meta))
+                })
+                .collect::<Result<Vec<(PathBuf, fs::Metadata)>, std::io::Error>>()?;
+            for (path, meta) in children {
+                self.backup_in_order(&path, meta, root, &ignores, dst, storage, out, history)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DirectoryConfig for BasicDirectory {
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        self.backup_tree(src, src, ignores, dst, storage, out, history)?;
+        let output_dir = crate::backup::append_path(dst, src);
+        if self.deduplicate && storage.metadata(&output_dir)?.is_some_and(|meta| meta.is_dir()) {
+            log::debug!("Deduplicating {:?}...", output_dir);
+            // Other configs look after their own output.
+            let stats = dedup::deduplicate(storage.as_ref(), &output_dir, &|path| self.get_subconfig(&src.join(path)).is_some())?;
+            log::info!(target: crate::backup::STATS_TARGET, "Deduplicated {:?}: {} duplicate files linked, {} bytes saved.",
+                       src, stats.linked, stats.bytes_saved);
         }
         Ok(())
     }
@@ -258,70 +745,200 @@ impl DirectoryConfig for BasicDirectory {
             Err(_)               => None
         };
     }
+
+    fn get_hooks(&self) -> &Hooks { &self.hooks }
+
+    fn get_identity_file(&self) -> Option<&Path> {
+        self.subconfigs.iter().find_map(|config| config.get_identity_file())
+    }
 }
 
 #[derive(Debug)]
 struct CompressedDirectory {
-    config:     BasicDirectory,
-    algorithm:  CompressionAlgorithm
+    config:        BasicDirectory,
+    algorithm:     CompressionAlgorithm,
+    archive_mode:  ArchiveMode,
+    full_interval: u64,
+    encryption:    Option<Encryption>,
+    volume_size:   Option<u64>,
+    threads:       u64,
+    detector:      Option<IncompressibleDetector>
 }
 
 impl CompressedDirectory {
-    fn new(obj: &json::object::Object) -> Result<Box<dyn DirectoryConfig>, Error> {
+    fn new(obj: &json::object::Object) -> Result<CompressedDirectory, Error> {
         let (config, is_only_basic) = BasicDirectory::new(obj)?;
+        let config = config.without_deduplication()?;
         if !is_only_basic {
             return Err(ParseError::CannotCompressNonbasic);
         }
         else {
-            return Ok(Box::new(CompressedDirectory {
-                config:     config,
-                algorithm:  str_from_opt_json_prop(&obj, "algorithm", "bzip2")?.parse::<CompressionAlgorithm>()?
-            }));
+            let directory = CompressedDirectory {
+                config:        config,
+                algorithm:     str_from_opt_json_prop(&obj, "algorithm", "bzip2")?.parse::<CompressionAlgorithm>()
+                    .map_err(ParseError::at("algorithm"))?,
+                archive_mode:  str_from_opt_json_prop(&obj, "archive_mode", "full")?.parse::<ArchiveMode>()
+                    .map_err(ParseError::at("archive_mode"))?,
+                full_interval: uint_from_opt_json_prop(obj, "full_interval", 7)?,
+                encryption:    encryption_from_json(obj.get("encryption")).map_err(ParseError::at("encryption"))?,
+                volume_size:   match obj.get("volume_size") {
+                    Some(size) => Some(size.as_u64().filter(|size| *size > 0)
+                                       .ok_or(ParseError::NotAnUnsignedInt("volume_size"))?),
+                    None       => None
+                },
+                threads:       uint_from_opt_json_prop(obj, "threads", 1)?,
+                detector:      incompressible_from_json(obj.get("store_incompressible"))
+                    .map_err(ParseError::at("store_incompressible"))?
+            };
+            let is_identity_missing = directory.encryption.as_ref().is_some_and(|encryption| encryption.identity_file().is_none());
+            if ArchiveMode::Full != directory.archive_mode && is_identity_missing {
+                return Err(ParseError::At("encryption".to_string(), Box::new(ParseError::NoIdentityFile)));
+            }
+            return Ok(directory);
         }
     }
 }
 
 impl DirectoryConfig for CompressedDirectory {
-    fn backup(&self, src: &Path, dst: &Path, _out: &mut dyn BackupOutputStream, last: Option<&Path>)
-              -> Result<(), std::io::Error> {
-        let out_file_name = dst.join(src.parent().unwrap()).into_os_string().into_string()
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              _out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        let archive_name = "tsnapshot-".to_string() + src.file_name().unwrap().to_str().unwrap() +
+            self.algorithm.extension() + if self.encryption.is_some() { crate::encryption::EXTENSION } else { "" };
+        let out_file_name = crate::backup::append_path(dst, src.parent().unwrap()).into_os_string().into_string()
             .map_err(|_os_str| std::io::Error::new(std::io::ErrorKind::InvalidInput,
                                                    "Filename not representable as str."))? +
-            "/tsnapshot-" + src.file_name().unwrap().to_str().unwrap() + self.algorithm.extension();
+            "/" + &archive_name;
         log::debug!("Compressing {:?} into compressed file {:?}", src, out_file_name);
-        log::debug!("Creating output file {:?}...", out_file_name);
-        let out_file = std::fs::File::create(out_file_name)?;
-        log::debug!("Initializing {:?} compressor...", self.algorithm);
-        let mut compressor = self.algorithm.get_writer(out_file);
+        let out_file: Box<dyn Compressor> = match self.volume_size {
+            Some(volume_size) => {
+                log::debug!("Splitting output file {:?} into volumes of {} bytes...", out_file_name, volume_size);
+                Box::new(VolumeWriter::new(storage.clone(), Path::new(&out_file_name), volume_size)?)
+            },
+            None              => {
+                log::debug!("Creating output file {:?}...", out_file_name);
+                storage.create(Path::new(&out_file_name))?
+            }
+        };
+        let out_writer: Box<dyn Compressor> = match &self.encryption {
+            Some(encryption) => {
+                log::debug!("Initializing encryption...");
+                encryption.get_writer(out_file)?
+            },
+            None             => out_file
+        };
+        log::debug!("Initializing {:?} compressor with {} threads...", self.algorithm, self.threads);
+        let mut compressor = self.algorithm.get_writer(out_writer, self.threads as usize)?;
         {
             log::debug!("Creating tar backup stream...");
-            let mut tar_out = TarOutputStream::new(compressor.writer());
-            log::debug!("Continuing backup with tar stream...");
-            self.config.backup(src, dst, &mut tar_out, last)?;
+            let mut tar_out = match &self.detector {
+                Some(detector) => TarOutputStream::with_detector(compressor.as_mut(), detector),
+                None           => TarOutputStream::new(compressor.as_mut())
+            };
+            if ArchiveMode::Full == self.archive_mode {
+                log::debug!("Continuing backup with tar stream...");
+                self.config.backup(src, ignores, dst, storage, &mut tar_out, history)?;
+            }
+            else {
+                let snar_name = SnapshotFile::path_for(&crate::backup::append_path(dst, src.parent().unwrap()), &archive_name);
+                let base = match history.first() {
+                    Some(last) => SnapshotFile::find_base(
+                        storage.as_ref(), &SnapshotFile::path_for(&crate::backup::append_path(last, src.parent().unwrap()), &archive_name),
+                        self.archive_mode, self.full_interval,
+                        &KeyRing::new(self.encryption.as_ref().and_then(|encryption| encryption.identity_file()).map(Path::to_path_buf)))?,
+                    None       => None
+                };
+                log::debug!("Creating listing {:?} against base {:?}...", snar_name, base.as_ref().map(|base| &base.0));
+                let snar_file = storage.create(&snar_name)?;
+                let mut incremental_out = IncrementalOutputStream::new(snar_file, &mut tar_out, &archive_name, base,
+                                                                       self.encryption.as_ref())?;
+                log::debug!("Continuing backup with incremental tar stream...");
+                self.config.backup(src, ignores, dst, storage, &mut incremental_out, history)?;
+                incremental_out.close()?;
+            }
         }
         return compressor.close();
     }
     
     fn get_subpath(&self) -> &Path { return &self.config.get_subpath(); }
     fn get_subconfig(&self, path: &Path) -> Option<&dyn DirectoryConfig> { return self.config.get_subconfig(path) }
+    fn get_hooks(&self) -> &Hooks { self.config.get_hooks() }
+    fn get_identity_file(&self) -> Option<&Path> {
+        self.encryption.as_ref().and_then(Encryption::identity_file)
+    }
+}
+
+#[derive(Debug)]
+struct FileCompressedDirectory {
+    config:     BasicDirectory,
+    algorithm:  CompressionAlgorithm
+}
+
+impl FileCompressedDirectory {
+    fn new(obj: &json::object::Object) -> Result<FileCompressedDirectory, Error> {
+        let (config, is_only_basic) = BasicDirectory::new(obj)?;
+        let config = config.without_deduplication()?;
+        if !is_only_basic {
+            return Err(ParseError::CannotCompressNonbasic);
+        }
+        let algorithm = str_from_opt_json_prop(obj, "algorithm", "zstd")?.parse::<CompressionAlgorithm>()
+            .map_err(ParseError::at("algorithm"))?;
+        if let CompressionAlgorithm::Zip = algorithm {
+            return Err(ParseError::At("algorithm".to_string(),
+                                      Box::new(ParseError::CannotCompressFiles(algorithm.name().to_string()))));
+        }
+        Ok(FileCompressedDirectory {
+            config,
+            algorithm
+        })
+    }
+}
+
+impl DirectoryConfig for FileCompressedDirectory {
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              _out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        log::debug!("Creating {:?} per-file compressed backup stream...", self.algorithm);
+        let mut file_out = FileCompressedOutputStream::new(storage.as_ref(), dst, &self.algorithm);
+        self.config.backup(src, ignores, dst, storage, &mut file_out, history)?;
+        file_out.close()
+    }
+
+    fn get_subpath(&self) -> &Path { self.config.get_subpath() }
+    fn get_subconfig(&self, path: &Path) -> Option<&dyn DirectoryConfig> { self.config.get_subconfig(path) }
+    fn get_hooks(&self) -> &Hooks { self.config.get_hooks() }
+    fn get_identity_file(&self) -> Option<&Path> { self.config.get_identity_file() }
 }
 
 #[derive(Debug)]
 struct HardLinkedDirectory {
     config:           BasicDirectory,
     max_link_count:   u64,
-    detection_method: ChangeDetectionMethod
+    detection_method: ChangeDetectionMethod,
+    link_history:     u64
 }
 
 impl HardLinkedDirectory {
-    fn new(obj: &json::object::Object) -> Result<Box<dyn DirectoryConfig>, Error> {
+    fn new(obj: &json::object::Object) -> Result<HardLinkedDirectory, Error> {
         let (config, _) = BasicDirectory::new(obj)?;
-        return Ok(Box::new(HardLinkedDirectory {
+        return Ok(HardLinkedDirectory {
             config:     config,
             max_link_count:   uint_from_opt_json_prop(obj, "max_link_count", u64::MAX)?,
             detection_method: str_from_opt_json_prop(&obj, "change_detection", "timestamp")?
-                .parse::<ChangeDetectionMethod>()?
-        }));
+                .parse::<ChangeDetectionMethod>().map_err(ParseError::at("change_detection"))?,
+            link_history:     uint_from_opt_json_prop(obj, "link_history", 1)?
+        });
+    }
+}
+
+impl std::str::FromStr for ArchiveMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ArchiveMode, Error> {
+        match s.to_lowercase().as_str() {
+            "full"         => Ok(ArchiveMode::Full),
+            "incremental"  => Ok(ArchiveMode::Incremental),
+            "differential" => Ok(ArchiveMode::Differential),
+            mode           => Err(ParseError::UnknownOption(mode.to_string())),
+        }
     }
 }
 
@@ -338,28 +955,118 @@ impl std::str::FromStr for ChangeDetectionMethod {
 }
 
 impl DirectoryConfig for HardLinkedDirectory {
-    fn backup(&self, src: &Path, dst: &Path, _out: &mut dyn BackupOutputStream, last_opt: Option<&Path>)
-              -> Result<(), std::io::Error>  {
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              _out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
         log::debug!("Creating hard linked backup stream...");
-        if let Some(last) = last_opt {
-            log::debug!("Continuing backup with hard linked stream...");
-            let mut hard_link_out = HardLinkOutputStream::new(dst, last, self.max_link_count, &self.detection_method);
-            self.config.backup(src, dst, &mut hard_link_out, last_opt)
-        }
-        else {
-            log::debug!("No reference directory.  Continuing backup with copy stream...");
-            let mut copy_out = CopyOutputStream::new(dst);
-            self.config.backup(src, dst, &mut copy_out, last_opt)
-        }
+        // 0 links against every snapshot in the catalog.
+        let ref_dirs = match self.link_history {
+            0     => history,
+            count => &history[0..history.len().min(count as usize)]
+        };
+        // Without earlier snapshots everything is copied.
+        log::debug!("Continuing backup with hard linked stream against {} snapshots...", ref_dirs.len());
+        let mut hard_link_out = HardLinkOutputStream::new(storage.as_ref(), dst, ref_dirs, self.max_link_count,
+                                                          &self.detection_method);
+        self.config.backup(src, ignores, dst, storage, &mut hard_link_out, history)?;
+        let stats = hard_link_out.stats();
+        log::info!(target: crate::backup::STATS_TARGET, "Backed up {:?}: {} files hard linked, {} files copied ({} bytes).",
+                   src, stats.linked, stats.copied, stats.copied_bytes);
+        Ok(())
     }
     
     fn get_subpath(&self) -> &Path { return &self.config.get_subpath(); }
     fn get_subconfig(&self, path: &Path) -> Option<&dyn DirectoryConfig> { return self.config.get_subconfig(path); }
+    fn get_hooks(&self) -> &Hooks { self.config.get_hooks() }
+    fn get_identity_file(&self) -> Option<&Path> { self.config.get_identity_file() }
+}
+
+#[derive(Debug)]
+struct ChunkedDirectory {
+    config:     BasicDirectory,
+    chunk_size: u64
+}
+
+impl ChunkedDirectory {
+    fn new(obj: &json::object::Object) -> Result<ChunkedDirectory, Error> {
+        let (config, is_only_basic) = BasicDirectory::new(obj)?;
+        let config = config.without_deduplication()?;
+        if !is_only_basic {
+            return Err(ParseError::CannotChunkNonbasic);
+        }
+        Ok(ChunkedDirectory {
+            config,
+            chunk_size: uint_from_opt_json_prop(obj, "chunk_size", 64 * 1024)?
+        })
+    }
+}
+
+impl DirectoryConfig for ChunkedDirectory {
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              _out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        // The chunk store is shared by every snapshot, so it lives next to them in `destination_dir`.
+        let store = ChunkStore::new(storage.clone(), storage.root().join(chunk::STORE_DIR));
+        let index_file_name = crate::backup::append_path(dst, src.parent().unwrap()).join(
+            "tsnapshot-".to_string() + src.file_name().unwrap().to_str().unwrap() + chunk::INDEX_EXTENSION);
+        log::debug!("Creating chunk index {:?} for {:?}...", index_file_name, src);
+        let index_file = storage.create(&index_file_name)?;
+        let mut chunk_out = ChunkedOutputStream::new(index_file, &store, self.chunk_size)?;
+        log::debug!("Continuing backup with chunked stream...");
+        self.config.backup(src, ignores, dst, storage, &mut chunk_out, history)?;
+        chunk_out.close()
+    }
+
+    fn get_subpath(&self) -> &Path { self.config.get_subpath() }
+    fn get_subconfig(&self, path: &Path) -> Option<&dyn DirectoryConfig> { self.config.get_subconfig(path) }
+    fn get_hooks(&self) -> &Hooks { self.config.get_hooks() }
+    fn get_identity_file(&self) -> Option<&Path> { self.config.get_identity_file() }
 }
 
 #[derive(Debug)]
-enum Filter {
+struct DeltaDirectory {
+    config:        BasicDirectory,
+    min_size:      u64,
+    block_size:    Option<u64>,
+    full_interval: u64
+}
+
+impl DeltaDirectory {
+    fn new(obj: &json::object::Object) -> Result<DeltaDirectory, Error> {
+        let (config, _) = BasicDirectory::new(obj)?;
+        let config = config.without_deduplication()?;
+        Ok(DeltaDirectory {
+            config,
+            min_size:      uint_from_opt_json_prop(obj, "min_size", 1024 * 1024)?,
+            // 0 picks a block size for each file from its size.
+            block_size:    Some(uint_from_opt_json_prop(obj, "block_size", 0)?).filter(|size| 0 < *size),
+            full_interval: uint_from_opt_json_prop(obj, "full_interval", 7)?
+        })
+    }
+}
+
+impl DirectoryConfig for DeltaDirectory {
+    fn backup(&self, src: &Path, ignores: &IgnoreStack, dst: &Path, storage: &Arc<dyn Storage>,
+              _out: &mut dyn BackupOutputStream, history: &[&Path]) -> Result<(), std::io::Error> {
+        log::debug!("Continuing backup with delta stream against {:?}...", history.first());
+        let mut delta_out = DeltaOutputStream::new(storage.as_ref(), dst, history.first().copied(), self.min_size,
+                                                   self.block_size, self.full_interval);
+        self.config.backup(src, ignores, dst, storage, &mut delta_out, history)?;
+        let stats = delta_out.stats();
+        log::info!(target: crate::backup::STATS_TARGET, "Backed up {:?}: {} files hard linked, {} deltas, {} files copied ({} bytes written).",
+                   src, stats.linked, stats.deltas, stats.copied, stats.written_bytes);
+        Ok(())
+    }
+
+    fn get_subpath(&self) -> &Path { self.config.get_subpath() }
+    fn get_subconfig(&self, path: &Path) -> Option<&dyn DirectoryConfig> { self.config.get_subconfig(path) }
+    fn get_hooks(&self) -> &Hooks { self.config.get_hooks() }
+    fn get_identity_file(&self) -> Option<&Path> { self.config.get_identity_file() }
+}
+
+#[derive(Debug)]
+pub(crate) enum Filter {
     Name(Regex),
+    /// `.gitignore` patterns, matched against the path relative to the directory filtered.
+    Glob(Gitignore),
     Size(u64, u64),
     MimeType(Regex),
     Not(Box<Filter>),
@@ -372,61 +1079,479 @@ impl Filter {
     fn new(json: &json::JsonValue) -> Result<Filter, Error> {
         match json {
             JsonValue::Object(obj) => {
-                match str_from_json_prop(&obj, "on")? {
+                match str_from_json_prop(&obj, "on")?.as_ref() {
                     "name" => {
                         let regex_str = str_from_json_prop(&obj, "pattern")?;
-                        Regex::new(regex_str)
+                        Regex::new(&regex_str)
                             .map(|regex| Filter::Name(regex))
                             .map_err(|regex_err| ParseError::BadRegex(regex_str.to_string(), regex_err))
                     },
+                    "glob" => {
+                        let patterns = match obj.get("patterns") {
+                            Some(_) => strs_from_opt_json_prop(obj, "patterns", &[])?,
+                            None    => vec![str_from_json_prop(obj, "pattern")?.into_owned()]
+                        };
+                        exclude::glob_matcher(&patterns)
+                            .map(Filter::Glob)
+                            .map_err(ParseError::BadGlob)
+                    },
                     "size" => Ok(Filter::Size(
                         uint_from_opt_json_prop(&obj, "min", 0)?,
                         uint_from_opt_json_prop(&obj, "max", u64::MAX)?
                     )),
                     "mime" => {
                         let regex_str = str_from_json_prop(&obj, "pattern")?;
-                        Regex::new(regex_str)
+                        Regex::new(&regex_str)
                             .map(|regex| Filter::MimeType(regex))
                             .map_err(|regex_err| ParseError::BadRegex(regex_str.to_string(), regex_err))
                     },
                     "not"  => Ok(Filter::Not(Box::new(
-                        Filter::new(obj.get("filter").ok_or(ParseError::RequiredPropMissing("filter"))?)?
+                        Filter::new(obj.get("filter").ok_or(ParseError::RequiredPropMissing("filter"))?)
+                            .map_err(ParseError::at("filter"))?
                     ))),
                     "and"  => Ok(Filter::And(Filter::new_vec(obj.get("filters"))?)),
                     "or"   => Ok(Filter::Or(Filter::new_vec(obj.get("filters"))?)),
-                    other  => Err(ParseError::UnknownOption(other.to_string()))
+                    other  => Err(ParseError::At("on".to_string(), Box::new(ParseError::UnknownOption(other.to_string()))))
                 }
             },
-            _                      => Err(ParseError::NotAnObject("filter"))
+            _                      => Err(ParseError::NotAnObject(""))
         }
     }
 
-    fn new_vec(json: Option<&json::JsonValue>) -> Result<Vec<Filter>, Error> {
+    pub(crate) fn new_vec(json: Option<&json::JsonValue>) -> Result<Vec<Filter>, Error> {
         match json {
             None                         => Ok(vec![]),
-            Some(JsonValue::Array(vec))  => vec.iter().map(Filter::new).collect(),
+            Some(JsonValue::Array(vec))  => vec.iter().enumerate()
+                .map(|(index, json)| Filter::new(json).map_err(ParseError::at(format!("filters[{}]", index))))
+                .collect(),
             Some(..)                     => Err(ParseError::NotAnArray("filters"))
         }
     }
 
-    fn matches(&self, path: &Path, metadata: &fs::Metadata) -> bool {
+    /// Whether `path`, which is under `root`, the directory being filtered, matches.
+    pub(crate) fn matches(&self, path: &Path, root: &Path, metadata: &fs::Metadata) -> bool {
         match self {
             Filter::Name(regex)     => path.to_str()
                 .map(|path| regex.is_match(path))
                 .unwrap_or(false),
+            Filter::Glob(glob)      => path.strip_prefix(root).ok()
+                .filter(|relative| !relative.as_os_str().is_empty())
+                .map(|relative| glob.matched(relative, metadata.is_dir()).is_ignore())
+                .unwrap_or(false),
             Filter::Size(min, max)  => metadata.len() >= *min && metadata.len() <= *max,
             Filter::MimeType(regex) => mime_guess::from_path(path).first()
                 .map(|mime| regex.is_match(mime.essence_str()))
                 .unwrap_or(false),
-            Filter::Not(filter)     => !filter.matches(path, metadata),
-            Filter::And(filters)    => filters.iter().all(|filter| filter.matches(path, metadata)),
-            Filter::Or(filters)     => filters.iter().any(|filter| filter.matches(path, metadata))
+            Filter::Not(filter)     => !filter.matches(path, root, metadata),
+            Filter::And(filters)    => filters.iter().all(|filter| filter.matches(path, root, metadata)),
+            Filter::Or(filters)     => filters.iter().any(|filter| filter.matches(path, root, metadata))
         }
     }
 }
 
+// ----- Parsed Configuration --------------------------------------------------
+
+/// A config as written.  Nothing it names has been started or looked for yet.
+struct ParsedConfiguration {
+    root_dir_configs: Vec<Box<dyn DirectoryConfig>>,
+    source:           Option<PullSource>,
+    destination:      DestinationSpec,
+    storage:          StorageSpec,
+    verbosity:        log::LevelFilter,
+    name_format:      std::string::String,
+    keep_limit:       Vec<KeepLimit>,
+    replicas:         Vec<ReplicaSpec>,
+    threads:          u64,
+    hooks:            Hooks
+}
+
+enum DestinationSpec {
+    Dir(PathBuf),
+    Pool(PoolSpec)
+}
+
+struct PoolSpec {
+    disks:      Vec<PoolDisk>,
+    state_file: PathBuf,
+    max_age:    Option<u64>
+}
+
+/// How `destination_dir` is reached.  Opening a command starts it.
+enum StorageSpec {
+    Local,
+    Remote(std::string::String),
+    S3(S3Location)
+}
+
+struct ReplicaSpec {
+    name:            std::string::String,
+    destination_dir: PathBuf,
+    storage:         StorageSpec,
+    keep_limit:      Vec<KeepLimit>
+}
+
+impl ParsedConfiguration {
+    fn from_object(obj: &json::object::Object) -> Result<ParsedConfiguration, Error> {
+        let (root_dir_configs, source) = roots_from_json(obj)?;
+        let destination = match pool_from_json(obj)? {
+            Some(pool) => DestinationSpec::Pool(pool),
+            None       => DestinationSpec::Dir(PathBuf::from(str_from_json_prop(obj, "destination_dir")?.into_owned()))
+        };
+        Ok(ParsedConfiguration {
+            root_dir_configs,
+            source,
+            destination,
+            storage:     storage_from_json(obj)?,
+            verbosity:   log_level_from_str(&str_from_opt_json_prop(obj, "verbosity", "warning")?)
+                .map_err(ParseError::at("verbosity"))?,
+            name_format: str_from_opt_json_prop(obj, "name_format", "%Y-%m-%d_%H-%M-%S")?.to_string(),
+            keep_limit:  KeepLimit::new_vec(obj.get("keep limit"))?,
+            replicas:    replicas_from_json(obj.get("replicas"))?,
+            threads:     uint_from_opt_json_prop(obj, "threads", 1)?,
+            hooks:       hooks_from_json(obj)?
+        })
+    }
+
+    /// Looks for the disks of a pool and starts destination commands.
+    fn open(self) -> Result<Configuration, Error> {
+        let (mut destination_dir, pool) = match self.destination {
+            DestinationSpec::Dir(destination_dir) => (destination_dir, None),
+            DestinationSpec::Pool(pool)           => {
+                let pool = pool.probe().map_err(ParseError::at("destination_pool"))?;
+                (pool.current_disk().destination_dir.clone(), Some(pool))
+            }
+        };
+        if self.source.is_some() {
+            // Pulled sources are backed up from inside their mirror.
+            destination_dir = absolute_path(destination_dir)?;
+        }
+        Ok(Configuration {
+            root_dir_configs: self.root_dir_configs,
+            source:           self.source,
+            storage:          self.storage.open(&destination_dir)?,
+            destination_dir,
+            pool,
+            verbosity:        self.verbosity,
+            name_format:      self.name_format,
+            keep_limit:       self.keep_limit,
+            replicas:         self.replicas.into_iter().enumerate()
+                .map(|(index, replica)| replica.open().map_err(ParseError::at(format!("replicas[{}]", index))))
+                .collect::<Result<Vec<Replica>, Error>>()?,
+            threads:          self.threads,
+            hooks:            self.hooks
+        })
+    }
+}
+
+impl PoolSpec {
+    fn probe(self) -> Result<DestinationPool, Error> {
+        Ok(DestinationPool {
+            current:    DestinationPool::find_present(&self.disks).ok_or(ParseError::NoDiskPresent)?,
+            disks:      self.disks,
+            state_file: self.state_file,
+            max_age:    self.max_age
+        })
+    }
+}
+
+impl StorageSpec {
+    fn open(self, destination_dir: &Path) -> Result<Arc<dyn Storage>, Error> {
+        match self {
+            StorageSpec::Local           => Ok(Arc::new(LocalStorage::new(destination_dir.to_path_buf()))),
+            StorageSpec::Remote(command) => RemoteStorage::connect(&command, destination_dir.to_path_buf())
+                .map(|storage| Arc::new(storage) as Arc<dyn Storage>)
+                .map_err(|err| ParseError::IoError(destination_dir.to_path_buf(), err)),
+            StorageSpec::S3(location)    => Ok(Arc::new(S3Storage::new(destination_dir.to_path_buf(), location)))
+        }
+    }
+}
+
+impl ReplicaSpec {
+    fn open(self) -> Result<Replica, Error> {
+        Ok(Replica {
+            name:       self.name,
+            storage:    self.storage.open(&self.destination_dir)?,
+            keep_limit: self.keep_limit
+        })
+    }
+}
+
 // ----- Utility Functions -----------------------------------------------------
 
+/// Without a command or bucket, `destination_dir` is a local directory.  A command is run through
+/// the shell, such as `ssh host tsnapshot --server`, and `destination_dir` is on its end.  In a
+/// bucket, `destination_dir` is the prefix of every key.
+fn storage_from_json(obj: &json::object::Object) -> Result<StorageSpec, Error> {
+    match (obj.get("destination_command"), obj.get("destination_s3")) {
+        (None, None)                               => Ok(StorageSpec::Local),
+        (Some(command), None)                      => str_from_json(command, "destination_command")
+            .map(|command| StorageSpec::Remote(command.into_owned())),
+        (None, Some(JsonValue::Object(s3_obj)))    => (|| {
+            let env_or_prop = |prop: &'static str, var: &str| match s3_obj.get(prop) {
+                Some(value) => str_from_json(value, prop).map(Cow::into_owned),
+                None        => std::env::var(var).map_err(|_| ParseError::RequiredPropMissing(prop))
+            };
+            let part_size = uint_from_opt_json_prop(s3_obj, "part_size", 8 << 20)?;
+            if part_size < s3::MIN_PART_SIZE {
+                return Err(ParseError::PartTooSmall(part_size))
+            }
+            Ok(StorageSpec::S3(S3Location {
+                endpoint:   str_from_json_prop(s3_obj, "endpoint")?.to_string(),
+                bucket:     str_from_json_prop(s3_obj, "bucket")?.to_string(),
+                region:     str_from_opt_json_prop(s3_obj, "region", "us-east-1")?.to_string(),
+                access_key: env_or_prop("access_key", "AWS_ACCESS_KEY_ID")?,
+                secret_key: env_or_prop("secret_key", "AWS_SECRET_ACCESS_KEY")?,
+                part_size,
+            }))
+        })().map_err(ParseError::at("destination_s3")),
+        (None, Some(..))                           => Err(ParseError::NotAnObject("destination_s3")),
+        (Some(..), Some(..))                       => Err(ParseError::ConflictingDestinations)
+    }
+}
+
+/// Each replica names its own destination the same way as the top level, along with a name for the
+/// primary catalog and its own keep limits.
+fn replicas_from_json(json: Option<&JsonValue>) -> Result<Vec<ReplicaSpec>, Error> {
+    lazy_static! {
+        static ref NAME: Regex = Regex::new(r"^[\w.-]+$").unwrap();
+    }
+    match json {
+        None                        => Ok(vec![]),
+        Some(JsonValue::Array(vec)) => vec.iter().enumerate().map(|(index, json)| match json {
+            JsonValue::Object(obj) => (|| {
+                let name = str_from_json_prop(obj, "name")?;
+                if !NAME.is_match(&name) {
+                    return Err(ParseError::BadReplicaName(name.to_string()))
+                }
+                Ok(ReplicaSpec {
+                    name:            name.to_string(),
+                    destination_dir: PathBuf::from(str_from_json_prop(obj, "destination_dir")?.into_owned()),
+                    storage:         storage_from_json(obj)?,
+                    keep_limit:      KeepLimit::new_vec(obj.get("keep limit"))?
+                })
+            })().map_err(ParseError::at(format!("replicas[{}]", index))),
+            _                      => Err(ParseError::At(format!("replicas[{}]", index), Box::new(ParseError::NotAnObject(""))))
+        }).collect(),
+        Some(..)                    => Err(ParseError::NotAnArray("replicas"))
+    }
+}
+
+/// A `destination_pool` stands in for `destination_dir`, which is taken from whichever of its disks
+/// is present.
+fn pool_from_json(obj: &json::object::Object) -> Result<Option<PoolSpec>, Error> {
+    let pool = match obj.get("destination_pool") {
+        None                         => return Ok(None),
+        Some(JsonValue::Object(pool)) => pool,
+        Some(..)                     => return Err(ParseError::NotAnObject("destination_pool"))
+    };
+    if ["destination_dir", "destination_command", "destination_s3"].iter().any(|prop| obj.get(prop).is_some()) {
+        return Err(ParseError::ConflictingDestinations)
+    }
+    pool_from_object(pool).map(Some).map_err(ParseError::at("destination_pool"))
+}
+
+fn pool_from_object(pool: &json::object::Object) -> Result<PoolSpec, Error> {
+    let disks = match pool.get("disks") {
+        Some(JsonValue::Array(vec)) => vec.iter().enumerate().map(|(index, json)| match json {
+            JsonValue::Object(disk) => (|| Ok(PoolDisk {
+                name:            str_from_json_prop(disk, "name")?.to_string(),
+                destination_dir: PathBuf::from(str_from_json_prop(disk, "destination_dir")?.into_owned()),
+                identity:        match (disk.get("marker"), disk.get("uuid")) {
+                    (Some(marker), None) => DiskIdentity::Marker(PathBuf::from(str_from_json(marker, "marker")?.into_owned())),
+                    (None, Some(uuid))   => DiskIdentity::Uuid(str_from_json(uuid, "uuid")?.into_owned()),
+                    (None, None)         => return Err(ParseError::RequiredPropMissing("marker")),
+                    (Some(..), Some(..)) => return Err(ParseError::ConflictingIdentities)
+                }
+            }))().map_err(ParseError::at(format!("disks[{}]", index))),
+            _                       => Err(ParseError::At(format!("disks[{}]", index), Box::new(ParseError::NotAnObject(""))))
+        }).collect::<Result<Vec<PoolDisk>, Error>>()?,
+        Some(..)                    => return Err(ParseError::NotAnArray("disks")),
+        None                        => return Err(ParseError::RequiredPropMissing("disks"))
+    };
+    Ok(PoolSpec {
+        disks,
+        state_file: PathBuf::from(str_from_json_prop(pool, "state_file")?.into_owned()),
+        max_age:    match pool.get("max_age") {
+            Some(max_age) => Some(KeepLimit::parse_timespan(Some(max_age), "max_age")?),
+            None          => None
+        }
+    })
+}
+
+/// The source roots, and where the only root is pulled from, if anywhere.
+type Roots = (Vec<Box<dyn DirectoryConfig>>, Option<PullSource>);
+
+/// Either `root_dir_config` or a list of them in `root_dir_configs`, along with where the only root
+/// is pulled from, if anywhere.  No root may be inside another.
+fn roots_from_json(obj: &json::object::Object) -> Result<Roots, Error> {
+    let roots = match (obj.get("root_dir_config"), obj.get("root_dir_configs")) {
+        (Some(root_json), None)                      => {
+            return (|| Ok((vec![<dyn DirectoryConfig>::new(root_json)?.0], source_from_json(root_json)?)))()
+                .map_err(ParseError::at("root_dir_config"))
+        },
+        (None, Some(JsonValue::Array(roots)))        => roots,
+        (None, Some(..))                             => return Err(ParseError::NotAnArray("root_dir_configs")),
+        (None, None)                                 => return Err(ParseError::RequiredPropMissing("root_dir_config")),
+        (Some(..), Some(..))                         => return Err(ParseError::ConflictingRoots)
+    };
+    if roots.is_empty() {
+        return Err(ParseError::RequiredPropMissing("root_dir_configs"))
+    }
+    if roots.iter().any(|root| root["source"].is_object()) {
+        return Err(ParseError::PullSeveralRoots)
+    }
+    let configs = roots.iter().enumerate()
+        .map(|(index, root_json)| <dyn DirectoryConfig>::new(root_json)
+             .map(|(config, _)| config)
+             .map_err(ParseError::at(format!("root_dir_configs[{}]", index))))
+        .collect::<Result<Vec<Box<dyn DirectoryConfig>>, Error>>()?;
+    // Compared the way they are laid out in a snapshot.
+    let paths = configs.iter()
+        .map(|config| crate::backup::append_path(Path::new(""), config.get_subpath()))
+        .collect::<Vec<PathBuf>>();
+    for (index, path) in paths.iter().enumerate() {
+        if let Some(other) = paths[index + 1..].iter().find(|other| path.starts_with(other) || other.starts_with(path)) {
+            return Err(ParseError::OverlappingRoots(path.clone(), other.clone()))
+        }
+    }
+    Ok((configs, None))
+}
+
+/// A `source` object in the root config pulls it through an agent instead of reading it locally.
+fn source_from_json(json: &JsonValue) -> Result<Option<PullSource>, Error> {
+    let root = match json {
+        JsonValue::Object(root) => root,
+        _                       => return Ok(None)
+    };
+    match root.get("source") {
+        None                         => Ok(None),
+        Some(JsonValue::Object(obj)) => {
+            if Path::new(&*str_from_json_prop(root, "subpath")?).is_absolute() {
+                return Err(ParseError::PullSubpathNotRelative)
+            }
+            (|| {
+                let change_detection = str_from_opt_json_prop(obj, "change_detection", "timestamp")?.to_lowercase();
+                if "timestamp" != change_detection && "checksum" != change_detection {
+                    return Err(ParseError::At("change_detection".to_string(), Box::new(ParseError::UnknownOption(change_detection))))
+                }
+                Ok(Some(PullSource {
+                    command:          str_from_json_prop(obj, "command")?.to_string(),
+                    cache_dir:        absolute_path(PathBuf::from(str_from_json_prop(obj, "cache_dir")?.into_owned()))?,
+                    change_detection,
+                    filters:          root.get("filters").map(|filters| filters.dump()).unwrap_or_else(|| "[]".to_string()),
+                    is_dry_run:       false
+                }))
+            })().map_err(ParseError::at("source"))
+        },
+        Some(..)                     => Err(ParseError::NotAnObject("source"))
+    }
+}
+
+/// `pre_exec` and `post_exec` each list commands, either as a string or as an object with a
+/// `command`, a `timeout` and what to do `on_failure`.
+fn hooks_from_json(obj: &json::object::Object) -> Result<Hooks, Error> {
+    let hooks_from_prop = |prop: &'static str| match obj.get(prop) {
+        None                        => Ok(vec![]),
+        Some(JsonValue::Array(vec)) => vec.iter().enumerate()
+            .map(|(index, json)| hook_from_json(json).map_err(ParseError::at(format!("{}[{}]", prop, index))))
+            .collect::<Result<Vec<Hook>, Error>>(),
+        Some(..)                    => Err(ParseError::NotAnArray(prop))
+    };
+    Ok(Hooks {
+        pre:  hooks_from_prop("pre_exec")?,
+        post: hooks_from_prop("post_exec")?
+    })
+}
+
+fn hook_from_json(json: &JsonValue) -> Result<Hook, Error> {
+    match json {
+        JsonValue::Object(obj) => Ok(Hook {
+            // Left for the shell, since the TSNAPSHOT_* variables only exist once the
+            // hook runs.
+            command:    obj.get("command").ok_or(ParseError::RequiredPropMissing("command"))?
+                .as_str().ok_or(ParseError::NotAString("command"))?.to_string(),
+            timeout:    match obj.get("timeout") {
+                Some(timeout) => Some(std::time::Duration::from_secs(KeepLimit::parse_timespan(Some(timeout), "timeout")?)),
+                None          => None
+            },
+            on_failure: match str_from_opt_json_prop(obj, "on_failure", "abort")?.to_lowercase().as_str() {
+                "abort"    => FailurePolicy::Abort,
+                "continue" => FailurePolicy::Continue,
+                policy     => return Err(ParseError::At("on_failure".to_string(), Box::new(ParseError::UnknownOption(policy.to_string()))))
+            }
+        }),
+        _                      => json.as_str()
+            .map(|command| Hook { command: command.to_string(), timeout: None, on_failure: FailurePolicy::Abort })
+            .ok_or(ParseError::NotAString(""))
+    }
+}
+
+fn absolute_path(path: PathBuf) -> Result<PathBuf, Error> {
+    std::env::current_dir()
+        .map(|dir| dir.join(&path))
+        .map_err(|err| ParseError::IoError(path, err))
+}
+
+fn encryption_from_json(json: Option<&JsonValue>) -> Result<Option<Encryption>, Error> {
+    match json {
+        None                         => Ok(None),
+        Some(JsonValue::Object(obj)) => {
+            let encryption = if let Some(file) = obj.get("recipients_file") {
+                Encryption::from_recipients_file(Path::new(&*str_from_json(file, "recipients_file")?))?
+            }
+            else {
+                Encryption::from_recipients(&[&str_from_json_prop(obj, "recipient")?])?
+            };
+            match obj.get("identity_file") {
+                Some(file) => Ok(Some(encryption.with_identity_file(PathBuf::from(
+                    str_from_json(file, "identity_file")?.into_owned())))),
+                None       => Ok(Some(encryption))
+            }
+        },
+        Some(..)                     => Err(ParseError::NotAnObject(""))
+    }
+}
+
+/// `true` stores incompressible files with the default thresholds.  An object overrides them.
+fn incompressible_from_json(json: Option<&JsonValue>) -> Result<Option<IncompressibleDetector>, Error> {
+    match json {
+        None | Some(JsonValue::Boolean(false)) => Ok(None),
+        Some(JsonValue::Boolean(true))         => Ok(Some(IncompressibleDetector::new(
+            Some(Regex::new(DEFAULT_INCOMPRESSIBLE_MIME).unwrap()), vec![], Some(7.5), 65536, 65536
+        ))),
+        Some(JsonValue::Object(obj))           => {
+            let regex_str = str_from_opt_json_prop(obj, "mime", DEFAULT_INCOMPRESSIBLE_MIME)?;
+            let mime_types = if regex_str.is_empty() {
+                None
+            }
+            else {
+                Some(Regex::new(&regex_str).map_err(|regex_err| ParseError::BadRegex(regex_str.to_string(), regex_err))?)
+            };
+            let extensions = match obj.get("extensions") {
+                None                        => vec![],
+                Some(JsonValue::Array(vec)) => vec.iter()
+                    .map(|extension| str_from_json(extension, "extensions")
+                         .map(|extension| extension.trim_start_matches('.').to_string()))
+                    .collect::<Result<Vec<std::string::String>, Error>>()?,
+                Some(..)                    => return Err(ParseError::NotAnArray("extensions"))
+            };
+            let entropy = match obj.get("entropy") {
+                None                        => Some(7.5),
+                Some(JsonValue::Null)       => None,
+                Some(value)                 => Some(value.as_f64().ok_or(ParseError::NotANumber("entropy"))?)
+            };
+            Ok(Some(IncompressibleDetector::new(
+                mime_types,
+                extensions,
+                entropy,
+                uint_from_opt_json_prop(obj, "sample_size", 65536)?,
+                uint_from_opt_json_prop(obj, "min_size", 65536)?
+            )))
+        },
+        Some(..)                               => Err(ParseError::NotAnObject(""))
+    }
+}
+
 fn log_level_from_str(s: &str) -> Result<log::LevelFilter, Error> {
     match s.to_lowercase().as_str() {
         "silent"  => Ok(log::LevelFilter::Off),
@@ -456,10 +1581,14 @@ fn uint_from_json_prop<'a>(json: &'a json::object::Object, prop: &'static str) -
         .as_u64() .ok_or(ParseError::NotAnUnsignedInt(prop));
 }
 
-fn str_from_json_prop<'a>(json: &'a json::object::Object, prop: &'static str) -> Result<&'a str, Error> {
-    return json
-        .get(prop).ok_or(ParseError::RequiredPropMissing(prop))?
-        .as_str() .ok_or(ParseError::NotAString(prop));
+/// Variables such as `${HOSTNAME}` in the value are filled in.
+fn str_from_json_prop<'a>(json: &'a json::object::Object, prop: &'static str) -> Result<Cow<'a, str>, Error> {
+    str_from_json(json.get(prop).ok_or(ParseError::RequiredPropMissing(prop))?, prop)
+}
+
+/// The string `value` of `prop`, interpolated like `str_from_json_prop`.
+fn str_from_json<'a>(value: &'a JsonValue, prop: &'static str) -> Result<Cow<'a, str>, Error> {
+    interpolate(value.as_str().ok_or(ParseError::NotAString(prop))?).map_err(ParseError::at(prop))
 }
 
 fn uint_from_opt_json_prop<'a>(json: &'a json::object::Object, prop: &'static str, default: u64) -> Result<u64, Error> {
@@ -469,9 +1598,28 @@ fn uint_from_opt_json_prop<'a>(json: &'a json::object::Object, prop: &'static st
         .unwrap_or(Ok(default));
 }
 
-fn str_from_opt_json_prop<'a>(json: &'a json::object::Object, prop: &'static str, default: &'a str) -> Result<&'a str, Error> {
+fn bool_from_opt_json_prop(json: &json::object::Object, prop: &'static str, default: bool) -> Result<bool, Error> {
     return json
         .get(prop)
-        .map(|value| value.as_str().ok_or(ParseError::NotAString(prop)))
+        .map(|value| value.as_bool().ok_or(ParseError::NotABool(prop)))
         .unwrap_or(Ok(default));
 }
+
+/// An array of strings, each interpolated like `str_from_json_prop`.  `default` is used as it is.
+fn strs_from_opt_json_prop(json: &json::object::Object, prop: &'static str, default: &[&str]) -> Result<Vec<std::string::String>, Error> {
+    match json.get(prop) {
+        None                        => Ok(default.iter().map(|value| value.to_string()).collect()),
+        Some(JsonValue::Array(vec)) => vec.iter()
+            .map(|value| str_from_json(value, prop).map(Cow::into_owned))
+            .collect(),
+        Some(..)                    => Err(ParseError::NotAnArray(prop))
+    }
+}
+
+/// Like `str_from_json_prop`.  `default` is used as it is.
+fn str_from_opt_json_prop<'a>(json: &'a json::object::Object, prop: &'static str, default: &'a str) -> Result<Cow<'a, str>, Error> {
+    match json.get(prop) {
+        Some(value) => str_from_json(value, prop),
+        None        => Ok(Cow::Borrowed(default))
+    }
+}
@@ -0,0 +1,136 @@
+use std::path::{Path,PathBuf};
+use std::io::{Error,ErrorKind};
+use crate::compression::{Compressor,CompressorSink};
+
+/// Extension appended to the archive extension of encrypted archives.
+pub const EXTENSION: &str = ".age";
+
+/// Encrypts archives for one or more age recipients.  The stream is authenticated, so any
+/// tampering with the archive is detected when it is decrypted.
+#[derive(Debug)]
+pub struct Encryption {
+    recipients:    Vec<age::x25519::Recipient>,
+    /// Identities which decrypt the listings of earlier incremental archives, when a backup is
+    /// made against them.
+    identity_file: Option<PathBuf>
+}
+
+impl Encryption {
+    pub fn from_recipients(recipients: &[&str]) -> Result<Encryption, crate::config::Error> {
+        Ok(Encryption {
+            recipients:    recipients.iter()
+                .map(|recipient| recipient.parse::<age::x25519::Recipient>()
+                     .map_err(|_| crate::config::ParseError::BadKey(recipient.to_string())))
+                .collect::<Result<Vec<age::x25519::Recipient>, crate::config::Error>>()?,
+            identity_file: None
+        })
+    }
+
+    pub fn with_identity_file(self, identity_file: PathBuf) -> Encryption {
+        Encryption {
+            identity_file: Some(identity_file),
+            ..self
+        }
+    }
+
+    pub fn identity_file(&self) -> Option<&Path> { self.identity_file.as_deref() }
+
+    /// Reads recipients from `path`, one per line.  Blank lines and `#` comments are ignored.
+    pub fn from_recipients_file(path: &Path) -> Result<Encryption, crate::config::Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| crate::config::ParseError::IoError(path.to_path_buf(), err))?;
+        let recipients = contents.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect::<Vec<&str>>();
+        Encryption::from_recipients(&recipients)
+    }
+
+    pub fn get_writer(&self, out_writer: Box<dyn Compressor>) -> Result<Box<dyn Compressor>, Error> {
+        struct AgeCompressor {
+            encryptor: Option<age::stream::StreamWriter<CompressorSink>>
+        }
+        impl Compressor for AgeCompressor {
+            fn writer(&mut self) -> &mut dyn std::io::Write {
+                self.encryptor.as_mut().expect("Encrypted stream used after being closed.")
+            }
+            fn close(&mut self) -> Result<(), Error> {
+                match self.encryptor.take() {
+                    Some(encryptor) => encryptor.finish()?.0.close(),
+                    None            => Ok(())
+                }
+            }
+        }
+        let recipients = self.recipients.iter()
+            .map(|recipient| Box::new(recipient.clone()) as Box<dyn age::Recipient + Send>)
+            .collect();
+        let encryptor = age::Encryptor::with_recipients(recipients)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No recipients to encrypt for."))?
+            .wrap_output(CompressorSink(out_writer))
+            .map_err(|err| Error::other(err.to_string()))?;
+        Ok(Box::new(AgeCompressor {
+            encryptor: Some(encryptor)
+        }))
+    }
+}
+
+/// Identities used to decrypt archives.  The key file is only read, or asked for, when the first
+/// encrypted archive is found.
+pub struct KeyRing {
+    key_file:   Option<PathBuf>,
+    identities: std::cell::OnceCell<Vec<age::x25519::Identity>>
+}
+
+impl KeyRing {
+    /// Without a `key_file`, the user is asked for one on standard input.
+    pub fn new(key_file: Option<PathBuf>) -> KeyRing {
+        KeyRing {
+            key_file,
+            identities: std::cell::OnceCell::new()
+        }
+    }
+
+    fn identities(&self) -> Result<&Vec<age::x25519::Identity>, Error> {
+        if let Some(identities) = self.identities.get() {
+            return Ok(identities)
+        }
+        let key_file = match &self.key_file {
+            Some(key_file) => key_file.clone(),
+            None           => {
+                use std::io::Write;
+                eprint!("Key file for encrypted archives: ");
+                std::io::stderr().flush()?;
+                let mut line = std::string::String::new();
+                std::io::stdin().read_line(&mut line)?;
+                PathBuf::from(line.trim())
+            }
+        };
+        log::info!("Reading identities from {:?}...", key_file);
+        let identities = age::IdentityFile::from_buffer(std::io::BufReader::new(std::fs::File::open(&key_file)?))?
+            .into_identities()
+            .into_iter()
+            .map(|entry| match entry {
+                age::IdentityFileEntry::Native(identity) => identity
+            })
+            .collect::<Vec<age::x25519::Identity>>();
+        if identities.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("No identities in {:?}.", key_file)))
+        }
+        Ok(self.identities.get_or_init(|| identities))
+    }
+
+    /// Wraps an encrypted archive in a reader that fails if the archive was tampered with.
+    pub fn decrypt(&self, input: Box<dyn std::io::Read>) -> Result<Box<dyn std::io::Read>, Error> {
+        let to_io_error = |err: age::DecryptError| Error::new(ErrorKind::InvalidData, err);
+        match age::Decryptor::new(input).map_err(to_io_error)? {
+            age::Decryptor::Recipients(decryptor) => {
+                let identities = self.identities()?;
+                Ok(Box::new(decryptor
+                            .decrypt(identities.iter().map(|identity| identity as &dyn age::Identity))
+                            .map_err(to_io_error)?))
+            },
+            age::Decryptor::Passphrase(..) => Err(Error::new(ErrorKind::InvalidData,
+                                                              "Passphrase encrypted archives are not supported."))
+        }
+    }
+}
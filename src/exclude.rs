@@ -0,0 +1,71 @@
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::io::{Error,ErrorKind};
+use ignore::Match;
+use ignore::gitignore::{Gitignore,GitignoreBuilder};
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// The ignore files of every directory above some point in a walk, such as `.tsnapshotignore`.
+/// Their patterns follow the rules of `.gitignore`: a pattern containing a slash is anchored to
+/// the directory of its file, `!` re-includes what an earlier pattern excluded, and a file deeper
+/// in the tree overrides the ones above it.
+#[derive(Clone,Debug,Default)]
+pub struct IgnoreStack {
+    /// Outermost first.
+    files: Vec<Arc<Gitignore>>
+}
+
+impl IgnoreStack {
+    /// Adds the ignore files named `names` in `dir`, of which later names win.  Unless any exist,
+    /// the stack is only cloned.
+    pub fn enter(&self, dir: &Path, names: &[std::string::String]) -> Result<IgnoreStack, Error> {
+        let paths = names.iter()
+            .map(|name| dir.join(name))
+            .filter(|path| path.is_file())
+            .collect::<Vec<PathBuf>>();
+        if paths.is_empty() {
+            return Ok(self.clone())
+        }
+        let mut builder = GitignoreBuilder::new(dir);
+        for path in paths {
+            log::debug!("Reading ignore file {:?}...", path);
+            // Like git, a bad line only loses that pattern rather than the whole file.
+            if let Some(err) = builder.add(&path) {
+                log::warn!("Ignoring part of {:?}: {}", path, err);
+            }
+        }
+        let gitignore = builder.build()
+            .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Bad ignore file in {:?}: {}", dir, err)))?;
+        let mut files = self.files.clone();
+        files.push(Arc::new(gitignore));
+        Ok(IgnoreStack { files })
+    }
+
+    /// Whether the innermost pattern matching `path` excludes it.  `path` must be under the
+    /// directory of every file in the stack.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gitignore in self.files.iter().rev() {
+            match gitignore.matched(path, is_dir) {
+                Match::None         => continue,
+                Match::Ignore(_)    => return true,
+                Match::Whitelist(_) => return false
+            }
+        }
+        false
+    }
+}
+
+// ----- Public Interface ------------------------------------------------------
+
+/// Reads `.gitignore`-style `patterns`, matched against paths relative to the directory they
+/// filter.
+pub fn glob_matcher<S: AsRef<str>>(patterns: &[S]) -> Result<Gitignore, ignore::Error> {
+    // A root of "." stops the matcher stripping anything from the paths it is given,
+    // since they are already relative.
+    let mut builder = GitignoreBuilder::new(".");
+    for pattern in patterns {
+        builder.add_line(None, pattern.as_ref())?;
+    }
+    builder.build()
+}
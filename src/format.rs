@@ -0,0 +1,90 @@
+use std::path::Path;
+use json::JsonValue;
+use yaml_rust2::{Yaml,YamlLoader};
+use crate::config::ParseError;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// A language config files can be written in.  Each is read into the same tree of values, so a
+/// config means the same thing whichever one it is written in.
+#[derive(Clone,Copy,Debug,Eq,PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml
+}
+
+type Error = ParseError;
+
+impl Format {
+    /// Picks the format of `path` by its extension.  Anything but `.toml`, `.yaml` and `.yml` is
+    /// read as JSON.
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase).as_deref() {
+            Some("toml")         => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _                    => Format::Json
+        }
+    }
+
+    pub fn parse(self, contents: &str) -> Result<JsonValue, Error> {
+        match self {
+            Format::Json => json::parse(contents).map_err(ParseError::JsonError),
+            Format::Toml => contents.parse::<toml::Value>().map(from_toml).map_err(ParseError::TomlError),
+            Format::Yaml => {
+                let mut documents = YamlLoader::load_from_str(contents).map_err(ParseError::YamlError)?;
+                if 1 < documents.len() {
+                    return Err(ParseError::UnsupportedValue("more than one YAML document".to_string()))
+                }
+                documents.pop().map(from_yaml).unwrap_or(Ok(JsonValue::Null))
+            }
+        }
+    }
+}
+
+// ----- Implementation --------------------------------------------------------
+
+fn from_toml(value: toml::Value) -> JsonValue {
+    match value {
+        toml::Value::String(string)     => JsonValue::from(string),
+        toml::Value::Integer(integer)   => JsonValue::from(integer),
+        toml::Value::Float(float)       => JsonValue::from(float),
+        toml::Value::Boolean(boolean)   => JsonValue::from(boolean),
+        // Nothing in a config takes a date, so one is kept as written.
+        toml::Value::Datetime(datetime) => JsonValue::from(datetime.to_string()),
+        toml::Value::Array(vec)         => JsonValue::Array(vec.into_iter().map(from_toml).collect()),
+        toml::Value::Table(table)       => {
+            let mut obj = JsonValue::new_object();
+            for (key, value) in table {
+                obj[key.as_str()] = from_toml(value);
+            }
+            obj
+        }
+    }
+}
+
+fn from_yaml(value: Yaml) -> Result<JsonValue, Error> {
+    match value {
+        Yaml::String(string)   => Ok(JsonValue::from(string)),
+        Yaml::Integer(integer) => Ok(JsonValue::from(integer)),
+        Yaml::Real(real)       => real.parse::<f64>()
+            .map(JsonValue::from)
+            .map_err(|_| ParseError::UnsupportedValue(format!("YAML number {:?}", real))),
+        Yaml::Boolean(boolean) => Ok(JsonValue::from(boolean)),
+        Yaml::Null             => Ok(JsonValue::Null),
+        Yaml::Array(vec)       => vec.into_iter().map(from_yaml).collect::<Result<Vec<JsonValue>, Error>>().map(JsonValue::Array),
+        Yaml::Hash(hash)       => {
+            let mut obj = JsonValue::new_object();
+            for (key, value) in hash {
+                let key = match key {
+                    Yaml::String(key) => key,
+                    other             => return Err(ParseError::UnsupportedValue(format!("YAML key {:?}", other)))
+                };
+                obj[key.as_str()] = from_yaml(value)?;
+            }
+            Ok(obj)
+        },
+        Yaml::Alias(_)         => Err(ParseError::UnsupportedValue("YAML alias".to_string())),
+        Yaml::BadValue         => Err(ParseError::UnsupportedValue("bad YAML value".to_string()))
+    }
+}
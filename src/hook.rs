@@ -0,0 +1,158 @@
+use std::path::Path;
+use std::process::{Command,ExitStatus};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{Duration,Instant};
+use std::io::{Error,ErrorKind};
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Commands run before and after backing something up, such as to stop a service or dump a
+/// database and then undo it.
+#[derive(Debug,Default)]
+pub struct Hooks {
+    pub pre:  Vec<Hook>,
+    pub post: Vec<Hook>
+}
+
+/// A command run through the shell.
+#[derive(Debug)]
+pub struct Hook {
+    pub command:    std::string::String,
+    /// The command is killed once it has run this long.
+    pub timeout:    Option<Duration>,
+    pub on_failure: FailurePolicy
+}
+
+/// What a failed hook does to the backup.
+#[derive(Debug,Clone,Copy,Eq,PartialEq)]
+pub enum FailurePolicy {
+    /// A pre-hook stops the backup.  A post-hook of the whole backup fails it, though the snapshot
+    /// is kept and catalogued.  A post-hook of a directory config fails the backup like any other
+    /// error while taking the snapshot.
+    Abort,
+    /// The failure is only warned about.
+    Continue
+}
+
+/// What hooks are told about the backup they run around, through `TSNAPSHOT_*` environment
+/// variables.
+pub struct HookEnv<'a> {
+    /// The snapshot being taken.
+    pub snapshot:    &'a Path,
+    /// Where every snapshot is kept.
+    pub destination: &'a Path,
+    /// The directory backed up, for the hooks of a directory config.
+    pub source:      Option<&'a Path>
+}
+
+/// Set for a dry run, in which hooks are only logged.
+static IS_DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(is_dry_run: bool) {
+    IS_DRY_RUN.store(is_dry_run, Ordering::Relaxed);
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool { self.pre.is_empty() && self.post.is_empty() }
+
+    /// Runs the pre-hooks, then `body`, then the post-hooks.  Post-hooks run even if a pre-hook
+    /// or `body` failed, so whatever the pre-hooks did is always undone.  The first error is
+    /// returned.
+    pub fn around<T, F: FnOnce() -> Result<T, Error>>(&self, env: &HookEnv, body: F) -> Result<T, Error> {
+        self.around_keeping(env, body).and_then(|(value, post_result)| post_result.map(|()| value))
+    }
+
+    /// Like `around`, except a failed post-hook does not undo `body`.  Its value is returned
+    /// along with the result of the post-hooks.
+    pub fn around_keeping<T, F: FnOnce() -> Result<T, Error>>(&self, env: &HookEnv, body: F)
+                                                              -> Result<(T, Result<(), Error>), Error> {
+        if self.is_empty() {
+            return body().map(|value| (value, Ok(())))
+        }
+        let result = Hooks::run_all(&self.pre, env, None).and_then(|()| body());
+        let status = if result.is_ok() { "success" } else { "failure" };
+        let post_result = Hooks::run_all(&self.post, env, Some(status));
+        result.map(|value| (value, post_result))
+    }
+
+    /// Runs every hook in `hooks`.  Pre-hooks stop at the first one which aborts, but post-hooks
+    /// all run regardless.
+    fn run_all(hooks: &[Hook], env: &HookEnv, status: Option<&str>) -> Result<(), Error> {
+        let mut result = Ok(());
+        for hook in hooks {
+            match hook.run(env, status) {
+                Ok(())                                                 => (),
+                Err(err) if FailurePolicy::Continue == hook.on_failure => {
+                    log::warn!("Continuing despite hook failure: {}", err);
+                },
+                Err(err)                                               => {
+                    log::error!("{}", err);
+                    if status.is_none() {
+                        return Err(err)
+                    }
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Hook {
+    /// Runs the command, telling it about the backup through `env`.  `status` is the outcome of
+    /// the backup, which post-hooks are told.
+    fn run(&self, env: &HookEnv, status: Option<&str>) -> Result<(), Error> {
+        if IS_DRY_RUN.load(Ordering::Relaxed) {
+            log::info!("Would run hook {:?}.", self.command);
+            return Ok(())
+        }
+        log::info!("Running hook {:?}...", self.command);
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&self.command)
+            .env("TSNAPSHOT_PHASE", if status.is_some() { "post" } else { "pre" })
+            .env("TSNAPSHOT_SNAPSHOT", env.snapshot)
+            .env("TSNAPSHOT_DESTINATION", env.destination);
+        if let Some(source) = env.source {
+            command.env("TSNAPSHOT_SOURCE", source);
+        }
+        if let Some(status) = status {
+            command.env("TSNAPSHOT_STATUS", status);
+        }
+        let mut child = command.spawn()
+            .map_err(|err| Error::new(err.kind(), format!("Hook {:?} failed to start: {}", self.command, err)))?;
+        let exit_status = match self.timeout {
+            None          => child.wait()?,
+            Some(timeout) => {
+                let deadline = Instant::now() + timeout;
+                loop {
+                    if let Some(exit_status) = child.try_wait()? {
+                        break exit_status
+                    }
+                    if deadline <= Instant::now() {
+                        // Killing `sh` leaves whatever it started running, but at
+                        // least the backup is not held up by it.
+                        child.kill()?;
+                        child.wait()?;
+                        return Err(Error::new(ErrorKind::TimedOut, format!("Hook {:?} timed out after {:?}.",
+                                                                           self.command, timeout)))
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+            }
+        };
+        check_status(&self.command, exit_status)
+    }
+}
+
+// ----- Implementation --------------------------------------------------------
+
+fn check_status(command: &str, exit_status: ExitStatus) -> Result<(), Error> {
+    if exit_status.success() {
+        Ok(())
+    }
+    else {
+        Err(Error::other(format!("Hook {:?} failed with {}.", command, exit_status)))
+    }
+}
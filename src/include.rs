@@ -0,0 +1,221 @@
+use std::path::{Path,PathBuf};
+use std::fs;
+use json::JsonValue;
+use json::object::Object;
+use crate::config::ParseError;
+use crate::format::Format;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Names the files the top-level config or a directory config is layered on top of, in order.
+/// `include` is another name for `extends`.
+pub const EXTENDS_KEYS: [&str; 2] = ["extends", "include"];
+
+/// Lists the properties whose values replace those in the extended files instead of being merged
+/// with them.
+pub const REPLACE_KEY: &str = "replace";
+
+/// Records which file defined each property of an object, under its key, and the file the object
+/// itself came from, under "".  No config file can contain this key by accident.
+const ORIGIN_KEY: &str = "\u{0}origin";
+
+type Error = ParseError;
+
+/// Reads the config in `path` along with every file it extends.
+///
+/// Extended files are merged in order, then the extending object on top of them.  Objects are
+/// merged property by property and arrays are appended, unless the property is listed in
+/// `replace`.  Anything else is replaced.
+pub fn load(path: &Path) -> Result<JsonValue, Error> {
+    load_file(path, Kind::Top, &mut vec![])
+}
+
+/// Like `load`, for a config which was not read from a file.  The files it extends are found
+/// relative to the working directory.
+pub fn resolve(json: JsonValue) -> Result<JsonValue, Error> {
+    resolve_value(json, Path::new(""), Kind::Top, &mut vec![])
+}
+
+/// Names the file which defined the value `err` is about, if it is known.
+pub fn attribute(obj: &Object, err: ParseError) -> ParseError {
+    let prop = match &err {
+        ParseError::InFile(..)                           => return err,
+        ParseError::At(_, inner) if is_attributed(inner) => return err,
+        // Only the property of `obj` the error is inside of has its origin here.
+        ParseError::At(key, _)                           => key.split('[').next().unwrap_or("").to_string(),
+        // Missing properties and bad values are blamed on the object.
+        other                                            => other.prop().unwrap_or("").to_string()
+    };
+    let file = obj.get(ORIGIN_KEY)
+        .and_then(|origin| origin[prop.as_str()].as_str().or_else(|| origin[""].as_str()))
+        .map(PathBuf::from);
+    match file {
+        Some(file) => ParseError::InFile(file, Box::new(err)),
+        None       => err
+    }
+}
+
+/// Whether `key` is bookkeeping added while loading rather than a property of the config.
+pub fn is_internal(key: &str) -> bool {
+    key == ORIGIN_KEY
+}
+
+/// Records that `key` in `obj` was set by `origin`, such as the command line.
+pub fn set_origin(obj: &mut Object, key: &str, origin: &str) {
+    if let Some(JsonValue::Object(origins)) = obj.get_mut(ORIGIN_KEY) {
+        origins.insert(key, JsonValue::from(origin));
+    }
+}
+
+// ----- Implementation --------------------------------------------------------
+
+/// What an object with an `extends` property is, which decides where to look for nested ones.
+#[derive(Clone,Copy)]
+enum Kind {
+    Top,
+    Directory
+}
+
+fn load_file(path: &Path, kind: Kind, stack: &mut Vec<PathBuf>) -> Result<JsonValue, Error> {
+    let io_err = |err| ParseError::IoError(path.to_path_buf(), err);
+    let canonical = fs::canonicalize(path).map_err(io_err)?;
+    if stack.contains(&canonical) {
+        return Err(ParseError::IncludeCycle(path.to_path_buf()))
+    }
+    log::debug!("Reading config {:?}...", path);
+    let contents = fs::read_to_string(path).map_err(io_err)?;
+    let mut json = Format::of(path).parse(&contents)
+        .map_err(|err| ParseError::InFile(path.to_path_buf(), Box::new(err)))?;
+    annotate(&mut json, path.to_str().unwrap_or("?"));
+    stack.push(canonical);
+    let result = resolve_value(json, path.parent().unwrap_or(Path::new("")), kind, stack);
+    stack.pop();
+    result
+}
+
+/// Resolves the `extends` property of `json` and of every directory config nested in it.
+/// Relative paths are found in `dir`, where the file holding `json` is.
+fn resolve_value(json: JsonValue, dir: &Path, kind: Kind, stack: &mut Vec<PathBuf>) -> Result<JsonValue, Error> {
+    let mut obj = match json {
+        JsonValue::Object(obj) => obj,
+        // Anything else is rejected once the merged config is parsed.
+        other                  => return Ok(other)
+    };
+    let nested_keys: &[&str] = match kind {
+        Kind::Top       => &["root_dir_config", "root_dir_configs"],
+        Kind::Directory => &["subconfigs"]
+    };
+    for key in nested_keys {
+        match obj.get_mut(key) {
+            Some(JsonValue::Array(nodes)) => {
+                for node in nodes.iter_mut() {
+                    *node = resolve_value(std::mem::replace(node, JsonValue::Null), dir, Kind::Directory, stack)?;
+                }
+            },
+            Some(node)                    => {
+                *node = resolve_value(std::mem::replace(node, JsonValue::Null), dir, Kind::Directory, stack)?;
+            },
+            None                          => ()
+        }
+    }
+    let mut bases = vec![];
+    for key in EXTENDS_KEYS {
+        let paths = match obj.get(key) {
+            None                        => continue,
+            Some(JsonValue::Array(vec)) => vec.iter()
+                .map(|path| path.as_str().ok_or(ParseError::NotAString(key)))
+                .collect::<Result<Vec<&str>, Error>>(),
+            Some(path)                  => path.as_str().map(|path| vec![path]).ok_or(ParseError::NotAString(key))
+        };
+        bases.extend(paths.map_err(|err| attribute(&obj, err))?.into_iter().map(|path| dir.join(path)));
+        obj.remove(key);
+    }
+    let mut merged: Option<Object> = None;
+    for base_path in bases {
+        let base = match load_file(&base_path, kind, stack)? {
+            JsonValue::Object(base) => base,
+            _                       => return Err(ParseError::InFile(base_path, Box::new(ParseError::NotAnObject(""))))
+        };
+        merged = Some(match merged {
+            Some(merged) => merge(merged, base)?,
+            None         => base
+        });
+    }
+    // `replace` is kept until a merge uses it, since this object may itself be merged
+    // over a directory config extended by its parent.
+    match merged {
+        Some(merged) => merge(merged, obj).map(JsonValue::Object),
+        None         => Ok(JsonValue::Object(obj))
+    }
+}
+
+/// Layers `over` on top of `base`.
+fn merge(mut base: Object, mut over: Object) -> Result<Object, Error> {
+    let replaced = match over.remove(REPLACE_KEY) {
+        None                        => vec![],
+        Some(JsonValue::Array(vec)) => vec.iter()
+            .map(|key| key.as_str().map(str::to_string).ok_or(ParseError::NotAString(REPLACE_KEY)))
+            .collect::<Result<Vec<std::string::String>, Error>>()
+            .map_err(|err| attribute(&over, err))?,
+        Some(..)                    => return Err(attribute(&over, ParseError::NotAnArray(REPLACE_KEY)))
+    };
+    let over_origin = over.remove(ORIGIN_KEY);
+    let mut origin = base.remove(ORIGIN_KEY).unwrap_or_else(JsonValue::new_object);
+    let keys = over.iter().map(|(key, _)| key.to_string()).collect::<Vec<std::string::String>>();
+    for key in keys {
+        let value = over.remove(&key).unwrap();
+        let is_replaced = replaced.contains(&key);
+        let merged = match (base.remove(&key), value) {
+            (Some(JsonValue::Object(base_value)), JsonValue::Object(value)) if !is_replaced => {
+                JsonValue::Object(merge(base_value, value)?)
+            },
+            (Some(JsonValue::Array(mut base_value)), JsonValue::Array(value)) if !is_replaced => {
+                base_value.extend(value);
+                JsonValue::Array(base_value)
+            },
+            (_, value)                                                                     => value
+        };
+        base.insert(&key, merged);
+        // Without an origin, such as in a config given as a string, nothing is known.
+        match over_origin.as_ref().map(|over_origin| &over_origin[key.as_str()]) {
+            Some(file) if !file.is_null() => origin[key.as_str()] = file.clone(),
+            _                             => { origin.remove(&key); }
+        }
+    }
+    match over_origin.as_ref().map(|over_origin| &over_origin[""]) {
+        Some(file) if !file.is_null() => origin[""] = file.clone(),
+        _                             => { origin.remove(""); }
+    }
+    base.insert(ORIGIN_KEY, origin);
+    Ok(base)
+}
+
+/// Records `file` as the origin of every property in `json`.
+fn annotate(json: &mut JsonValue, file: &str) {
+    match json {
+        JsonValue::Object(obj) => {
+            let mut origin = JsonValue::new_object();
+            origin[""] = JsonValue::from(file);
+            for (key, value) in obj.iter_mut() {
+                origin[key] = JsonValue::from(file);
+                annotate(value, file);
+            }
+            obj.insert(ORIGIN_KEY, origin);
+        },
+        JsonValue::Array(vec)  => {
+            for value in vec.iter_mut() {
+                annotate(value, file);
+            }
+        },
+        _                      => ()
+    }
+}
+
+/// Whether some file has already been named for `err`.
+fn is_attributed(err: &ParseError) -> bool {
+    match err {
+        ParseError::InFile(..)   => true,
+        ParseError::At(_, inner) => is_attributed(inner),
+        _                        => false
+    }
+}
@@ -0,0 +1,67 @@
+use std::borrow::Cow;
+use std::fs;
+use crate::config::ParseError;
+
+// ----- Public Interface ------------------------------------------------------
+
+/// Replaces each `${NAME}` in `value` with the environment variable `NAME`, or `${NAME:-default}`
+/// with `default` if it is unset or empty.  `HOSTNAME`, `USER` and `HOME` are filled in from the
+/// system when they are not in the environment.  `$${` stands for `${` itself.
+pub fn interpolate(value: &str) -> Result<Cow<'_, str>, ParseError> {
+    if !value.contains("${") {
+        return Ok(Cow::Borrowed(value))
+    }
+    let mut out = std::string::String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue
+        }
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or_else(|| ParseError::BadInterpolation(value.to_string()))?;
+        let expression = &rest[start + 2..start + end];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None                  => (expression, None)
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || '_' == c) {
+            return Err(ParseError::BadInterpolation(value.to_string()))
+        }
+        match (lookup(name), default) {
+            (Some(value), Some(default)) if value.is_empty() => out.push_str(default),
+            (Some(value), _)                                 => out.push_str(&value),
+            (None, Some(default))                            => out.push_str(default),
+            (None, None)                                     => return Err(ParseError::UndefinedVariable(name.to_string()))
+        }
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(Cow::Owned(out))
+}
+
+// ----- Implementation --------------------------------------------------------
+
+/// The environment wins over what the system says, so a fact can be overridden for one run.
+fn lookup(name: &str) -> Option<std::string::String> {
+    if let Ok(value) = std::env::var(name) {
+        return Some(value)
+    }
+    match name {
+        // Shells set HOSTNAME without exporting it, so it is rarely in the environment.
+        "HOSTNAME" => fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| fs::read_to_string("/etc/hostname"))
+            .ok()
+            .map(|hostname| hostname.trim().to_string()),
+        "USER"     => users::get_current_username().and_then(|name| name.into_string().ok()),
+        "HOME"     => {
+            use users::os::unix::UserExt;
+            users::get_user_by_uid(users::get_current_uid())
+                .and_then(|user| user.home_dir().to_str().map(str::to_string))
+        },
+        _          => None
+    }
+}
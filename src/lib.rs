@@ -7,6 +7,20 @@ extern crate static_assertions;
 extern crate lazy_static;
 
 pub mod config;
+pub mod check;
+pub mod include;
+pub mod format;
+pub mod interpolate;
 pub mod backup;
 pub mod catalog;
 pub mod compression;
+pub mod encryption;
+pub mod volume;
+pub mod storage;
+pub mod remote;
+pub mod agent;
+pub mod s3;
+pub mod replica;
+pub mod pool;
+pub mod hook;
+pub mod exclude;
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::fs;
+use std::io::{BufRead,BufReader,Error,ErrorKind};
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Disks taking turns as the destination, such as USB disks rotated offsite.  Whichever one is
+/// plugged in is used, and each keeps its own catalog.
+#[derive(Debug)]
+pub struct DestinationPool {
+    pub disks:      Vec<PoolDisk>,
+    /// Index of the disk in use.
+    pub current:    usize,
+    /// Local file recording when each disk was last backed up to, since the others are not around
+    /// to ask.
+    pub state_file: PathBuf,
+    /// Disks not backed up to for longer than this many seconds are warned about.
+    pub max_age:    Option<u64>
+}
+
+#[derive(Debug)]
+pub struct PoolDisk {
+    pub name:            std::string::String,
+    pub destination_dir: PathBuf,
+    pub identity:        DiskIdentity
+}
+
+/// How a disk is told apart from whatever is mounted in its place, or the empty mount point.
+#[derive(Debug)]
+pub enum DiskIdentity {
+    /// A file which only exists on this disk, relative to `destination_dir`.
+    Marker(PathBuf),
+    /// The UUID of the filesystem `destination_dir` must be on.
+    Uuid(std::string::String)
+}
+
+impl DestinationPool {
+    /// The first of `disks` that is present.
+    pub fn find_present(disks: &[PoolDisk]) -> Option<usize> {
+        disks.iter().position(|disk| {
+            let is_present = disk.is_present();
+            log::debug!("Disk {:?} is {}present.", disk.name, if is_present { "" } else { "not " });
+            is_present
+        })
+    }
+
+    pub fn current_disk(&self) -> &PoolDisk { &self.disks[self.current] }
+
+    /// Records that the current disk was just backed up to.
+    pub fn record_refresh(&self) -> Result<(), Error> {
+        let mut state = self.read_state()?;
+        state.insert(self.current_disk().name.clone(), chrono::offset::Utc::now().timestamp());
+        let mut contents = std::string::String::new();
+        for disk in &self.disks {
+            if let Some(timestamp) = state.get(&disk.name) {
+                contents += &format!("{} {}\n", disk.name, timestamp);
+            }
+        }
+        if let Some(parent) = self.state_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.state_file, contents)
+    }
+
+    /// Warns about every other disk not backed up to within `max_age`.
+    pub fn warn_stale(&self) -> Result<(), Error> {
+        let max_age = match self.max_age {
+            Some(max_age) => max_age as i64,
+            None          => return Ok(())
+        };
+        let state = self.read_state()?;
+        let now = chrono::offset::Utc::now().timestamp();
+        for disk in &self.disks {
+            if disk.name == self.current_disk().name {
+                continue
+            }
+            match state.get(&disk.name) {
+                Some(timestamp) if now - timestamp <= max_age => (),
+                Some(timestamp) => log::warn!("Disk {:?} was last backed up to {} seconds ago.  Time to swap it in.",
+                                              disk.name, now - timestamp),
+                None            => log::warn!("Disk {:?} has never been backed up to.", disk.name)
+            }
+        }
+        Ok(())
+    }
+
+    fn read_state(&self) -> Result<HashMap<std::string::String, i64>, Error> {
+        let file = match fs::File::open(&self.state_file) {
+            Ok(file)                                    => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(err)                                    => return Err(err)
+        };
+        let mut state = HashMap::new();
+        for line_res in BufReader::new(file).lines() {
+            let line = line_res?;
+            let (name, timestamp) = line.rsplit_once(' ')
+                .and_then(|(name, timestamp)| Some((name, timestamp.parse::<i64>().ok()?)))
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData,
+                                          format!("Malformed line {:?} in {:?}.", line, self.state_file)))?;
+            state.insert(name.to_string(), timestamp);
+        }
+        Ok(state)
+    }
+}
+
+impl PoolDisk {
+    pub fn is_present(&self) -> bool {
+        match &self.identity {
+            DiskIdentity::Marker(marker) => self.destination_dir.join(marker).exists(),
+            DiskIdentity::Uuid(uuid)     => {
+                use std::os::unix::fs::MetadataExt;
+                // The by-uuid link points at the device node, whose device number is
+                // what files on that filesystem report as their own.
+                match (fs::metadata(Path::new("/dev/disk/by-uuid").join(uuid)), fs::metadata(&self.destination_dir)) {
+                    (Ok(device), Ok(dir)) => device.rdev() == dir.dev(),
+                    _                     => false
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,566 @@
+use std::path::{Component,Path,PathBuf};
+use std::io::{BufReader,BufWriter,Read,Seek,SeekFrom,Write,Error,ErrorKind};
+use std::process::{Child,Command,Stdio};
+use std::sync::{Arc,Mutex};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{Duration,SystemTime};
+use crate::compression::Compressor;
+use crate::storage::{LocalStorage,Object,ObjectKind,ObjectMeta,Storage};
+
+// ----- Protocol --------------------------------------------------------------
+
+// Every message is a frame: a big endian u32 length followed by that many bytes.
+// Requests start with one of the operations below, responses with a status.  Paths and data are
+// sent as a u32 length followed by the bytes.  The client sends `HELLO` first, naming the
+// destination directory the server works in, and `QUIT` last.
+
+/// Bumped whenever a frame changes.
+const VERSION: u32 = 1;
+
+const HELLO:           u8 = 0;
+const METADATA:        u8 = 1;
+const READ_DIR:        u8 = 2;
+const READ:            u8 = 3;
+const READ_LINK:       u8 = 4;
+const CREATE_DIR:      u8 = 5;
+const CREATE_DIR_ALL:  u8 = 6;
+const CREATE:          u8 = 7;
+const APPEND:          u8 = 8;
+const CLOSE:           u8 = 9;
+const SYMLINK:         u8 = 10;
+const HARD_LINK:       u8 = 11;
+const SET_PERMISSIONS: u8 = 12;
+const SET_METADATA:    u8 = 13;
+const RENAME:          u8 = 14;
+const REMOVE_FILE:     u8 = 15;
+const REMOVE_DIR:      u8 = 16;
+const REMOVE_DIR_ALL:  u8 = 17;
+const QUIT:            u8 = 18;
+
+pub(crate) const STATUS_OK:    u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// Most file data sent in one frame.
+const MAX_DATA: usize = 1 << 20;
+/// Largest frame either side accepts.
+const MAX_FRAME: usize = MAX_DATA + (1 << 16);
+
+/// Errors the other side needs to tell apart, such as a missing catalog.  Anything else is sent as
+/// `ErrorKind::Other` with its message.
+const ERROR_KINDS: [ErrorKind; 8] = [
+    ErrorKind::Other,
+    ErrorKind::NotFound,
+    ErrorKind::PermissionDenied,
+    ErrorKind::AlreadyExists,
+    ErrorKind::InvalidInput,
+    ErrorKind::InvalidData,
+    ErrorKind::UnexpectedEof,
+    ErrorKind::DirectoryNotEmpty,
+];
+
+pub(crate) fn write_frame(output: &mut dyn Write, frame: &[u8]) -> Result<(), Error> {
+    output.write_all(&(frame.len() as u32).to_be_bytes())?;
+    output.write_all(frame)?;
+    output.flush()
+}
+
+/// Reads the next frame, or `None` if the other side hung up between frames.
+pub(crate) fn read_frame(input: &mut dyn Read) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0u8; 4];
+    match input.read_exact(&mut len) {
+        Ok(())                                             => (),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err)                                           => return Err(err)
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Frame of {} bytes is too large.", len)))
+    }
+    let mut frame = vec![0u8; len];
+    input.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Response to a request which failed with `err`.
+pub(crate) fn error_response(err: &Error) -> FrameWriter {
+    let kind = ERROR_KINDS.iter().position(|kind| *kind == err.kind()).unwrap_or(0);
+    FrameWriter::new(STATUS_ERROR).u8(kind as u8).bytes(err.to_string().as_bytes())
+}
+
+/// The body of a successful response, or the error the other side sent instead.
+pub(crate) fn check_response(mut response: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let mut reader = FrameReader::new(&response);
+    match reader.u8()? {
+        STATUS_OK => {
+            response.remove(0);
+            Ok(response)
+        },
+        _         => {
+            let kind = ERROR_KINDS.get(reader.u8()? as usize).copied().unwrap_or(ErrorKind::Other);
+            Err(Error::new(kind, std::string::String::from_utf8_lossy(reader.bytes()?).into_owned()))
+        }
+    }
+}
+
+/// Builds the body of a frame.
+pub(crate) struct FrameWriter(pub(crate) Vec<u8>);
+
+impl FrameWriter {
+    pub(crate) fn new(tag: u8) -> FrameWriter { FrameWriter(vec![tag]) }
+
+    pub(crate) fn u8(mut self, value: u8) -> FrameWriter { self.0.push(value); self }
+
+    pub(crate) fn u32(mut self, value: u32) -> FrameWriter { self.0.extend_from_slice(&value.to_be_bytes()); self }
+
+    pub(crate) fn u64(mut self, value: u64) -> FrameWriter { self.0.extend_from_slice(&value.to_be_bytes()); self }
+
+    pub(crate) fn bytes(self, value: &[u8]) -> FrameWriter {
+        let mut res = self.u32(value.len() as u32);
+        res.0.extend_from_slice(value);
+        res
+    }
+
+    pub(crate) fn path(self, value: &Path) -> FrameWriter {
+        use std::os::unix::ffi::OsStrExt;
+        self.bytes(value.as_os_str().as_bytes())
+    }
+
+    pub(crate) fn meta(self, meta: &ObjectMeta) -> FrameWriter {
+        let kind = match meta.kind {
+            ObjectKind::File      => 0,
+            ObjectKind::Directory => 1,
+            ObjectKind::Symlink   => 2,
+            ObjectKind::Special   => 3
+        };
+        let res = match meta.modified.and_then(|mtime| mtime.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+            Some(mtime) => self.u8(kind).u64(meta.len).u8(1).u64(mtime.as_secs()).u32(mtime.subsec_nanos()),
+            None        => self.u8(kind).u64(meta.len).u8(0)
+        };
+        res.u32(meta.mode).u32(meta.uid).u32(meta.gid).u64(meta.dev).u64(meta.ino).u64(meta.nlink)
+    }
+}
+
+/// Takes apart the body of a frame.
+pub(crate) struct FrameReader<'a> {
+    data: &'a [u8],
+}
+
+impl <'a> FrameReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> FrameReader<'a> { FrameReader { data } }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.data.len() < len {
+            return Err(Error::new(ErrorKind::InvalidData, "Frame ended early."))
+        }
+        let (res, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(res)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Error> { Ok(self.take(1)?[0]) }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, Error> { Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap())) }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, Error> { Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap())) }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub(crate) fn path(&mut self) -> Result<PathBuf, Error> {
+        use std::os::unix::ffi::OsStrExt;
+        Ok(PathBuf::from(std::ffi::OsStr::from_bytes(self.bytes()?)))
+    }
+
+    pub(crate) fn meta(&mut self) -> Result<ObjectMeta, Error> {
+        let kind = match self.u8()? {
+            0 => ObjectKind::File,
+            1 => ObjectKind::Directory,
+            2 => ObjectKind::Symlink,
+            _ => ObjectKind::Special
+        };
+        let len = self.u64()?;
+        let modified = match self.u8()? {
+            0 => None,
+            _ => Some(SystemTime::UNIX_EPOCH + Duration::new(self.u64()?, self.u32()?))
+        };
+        Ok(ObjectMeta {
+            kind,
+            len,
+            modified,
+            mode:  self.u32()?,
+            uid:   self.u32()?,
+            gid:   self.u32()?,
+            dev:   self.u64()?,
+            ino:   self.u64()?,
+            nlink: self.u64()?
+        })
+    }
+}
+
+// ----- Client ----------------------------------------------------------------
+
+/// A destination on the far end of a command, such as `ssh host tsnapshot --server`, which is
+/// started when the configuration is read and spoken to over its stdin and stdout.
+#[derive(Debug)]
+pub struct RemoteStorage {
+    root:       PathBuf,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl RemoteStorage {
+    /// Runs `command` through the shell and asks it to work in `root`.
+    pub fn connect(command: &str, root: PathBuf) -> Result<RemoteStorage, Error> {
+        log::info!("Starting remote destination {:?}...", command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = Box::new(BufReader::new(child.stdout.take().unwrap()));
+        let output = Box::new(BufWriter::new(child.stdin.take().unwrap()));
+        RemoteStorage::over(input, output, Some(child), root)
+    }
+
+    /// Speaks to a server which is already reading `output` and writing `input`.
+    pub fn over(input: Box<dyn Read + Send>, output: Box<dyn Write + Send>, child: Option<Child>, root: PathBuf)
+                -> Result<RemoteStorage, Error> {
+        let connection = Connection {
+            input,
+            output,
+            child,
+        };
+        let storage = RemoteStorage {
+            root,
+            connection: Arc::new(Mutex::new(connection)),
+        };
+        storage.request(FrameWriter::new(HELLO).u32(VERSION).path(&storage.root))?;
+        Ok(storage)
+    }
+
+    fn request(&self, request: FrameWriter) -> Result<Vec<u8>, Error> {
+        self.connection.lock().unwrap().request(request)
+    }
+
+    fn request_path(&self, tag: u8, path: &Path) -> Result<Vec<u8>, Error> {
+        self.request(FrameWriter::new(tag).path(path))
+    }
+}
+
+impl Storage for RemoteStorage {
+    fn root(&self) -> &Path { &self.root }
+
+    fn metadata(&self, path: &Path) -> Result<Option<ObjectMeta>, Error> {
+        let response = self.request_path(METADATA, path)?;
+        let mut reader = FrameReader::new(&response);
+        match reader.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(reader.meta()?))
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, Error> {
+        let response = self.request_path(READ_DIR, path)?;
+        let mut reader = FrameReader::new(&response);
+        (0 .. reader.u32()?).map(|_| reader.path()).collect()
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Object>, Error> {
+        let len = match self.metadata(path)? {
+            Some(meta) => meta.len,
+            None       => return Err(Error::new(ErrorKind::NotFound, format!("{:?} does not exist.", path)))
+        };
+        Ok(Box::new(BufReader::with_capacity(MAX_DATA, RemoteObject {
+            connection: self.connection.clone(),
+            path: path.to_path_buf(),
+            position: 0,
+            len,
+        })))
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf, Error> {
+        FrameReader::new(&self.request_path(READ_LINK, path)?).path()
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> { self.request_path(CREATE_DIR, path).map(drop) }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> { self.request_path(CREATE_DIR_ALL, path).map(drop) }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn Compressor>, Error> {
+        let handle = FrameReader::new(&self.request_path(CREATE, path)?).u64()?;
+        Ok(Box::new(RemoteFile {
+            connection: self.connection.clone(),
+            handle,
+            buffer: Vec::with_capacity(MAX_DATA),
+        }))
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<(), Error> {
+        self.request(FrameWriter::new(SYMLINK).path(target).path(link)).map(drop)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> Result<(), Error> {
+        self.request(FrameWriter::new(HARD_LINK).path(original).path(link)).map(drop)
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        self.request(FrameWriter::new(SET_PERMISSIONS).path(path).u32(mode)).map(drop)
+    }
+
+    fn set_metadata(&self, path: &Path, meta: &ObjectMeta) -> Result<(), Error> {
+        self.request(FrameWriter::new(SET_METADATA).path(path).meta(meta)).map(drop)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        self.request(FrameWriter::new(RENAME).path(from).path(to)).map(drop)
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> { self.request_path(REMOVE_FILE, path).map(drop) }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), Error> { self.request_path(REMOVE_DIR, path).map(drop) }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error> { self.request_path(REMOVE_DIR_ALL, path).map(drop) }
+}
+
+/// One request at a time is sent to the server, whichever thread it comes from.
+struct Connection {
+    input:  Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+    child:  Option<Child>,
+}
+
+impl Connection {
+    fn request(&mut self, request: FrameWriter) -> Result<Vec<u8>, Error> {
+        write_frame(&mut self.output, &request.0)?;
+        check_response(read_frame(&mut self.input)?
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Remote destination hung up."))?)
+    }
+}
+
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection").field("child", &self.child).finish()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Err(err) = self.request(FrameWriter::new(QUIT)) {
+            log::warn!("Remote destination did not shut down cleanly: {:?}", err);
+        }
+        if let Some(child) = self.child.as_mut() {
+            match child.wait() {
+                Ok(status) if !status.success() => log::warn!("Remote destination exited with {}.", status),
+                Ok(_)                           => (),
+                Err(err)                        => log::warn!("Failed to wait for remote destination: {:?}", err)
+            }
+        }
+    }
+}
+
+/// Reads a remote file one frame at a time, wherever it is seeked to.
+struct RemoteObject {
+    connection: Arc<Mutex<Connection>>,
+    path:       PathBuf,
+    position:   u64,
+    len:        u64,
+}
+
+impl Read for RemoteObject {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() || self.position >= self.len {
+            return Ok(0)
+        }
+        let request = FrameWriter::new(READ)
+            .path(&self.path)
+            .u64(self.position)
+            .u32(std::cmp::min(buf.len(), MAX_DATA) as u32);
+        let response = self.connection.lock().unwrap().request(request)?;
+        let data = FrameReader::new(&response).bytes()?;
+        buf[.. data.len()].copy_from_slice(data);
+        self.position += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl Seek for RemoteObject {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let position = match pos {
+            SeekFrom::Start(offset)   => Some(offset),
+            SeekFrom::End(offset)     => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset)
+        };
+        self.position = position
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Seek before the start of the file."))?;
+        Ok(self.position)
+    }
+}
+
+/// Sends a new remote file in frames of up to `MAX_DATA` bytes.
+struct RemoteFile {
+    connection: Arc<Mutex<Connection>>,
+    handle:     u64,
+    buffer:     Vec<u8>,
+}
+
+impl RemoteFile {
+    fn send(&mut self) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            let request = FrameWriter::new(APPEND).u64(self.handle).bytes(&self.buffer);
+            self.connection.lock().unwrap().request(request)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Write for RemoteFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let len = std::cmp::min(buf.len(), MAX_DATA - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[.. len]);
+        if self.buffer.len() == MAX_DATA {
+            self.send()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> { self.send() }
+}
+
+impl Compressor for RemoteFile {
+    fn writer(&mut self) -> &mut dyn Write { self }
+
+    fn close(&mut self) -> Result<(), Error> {
+        self.send()?;
+        self.connection.lock().unwrap().request(FrameWriter::new(CLOSE).u64(self.handle)).map(drop)
+    }
+}
+
+// ----- Server ----------------------------------------------------------------
+
+/// Answers requests from a `RemoteStorage` on `input` and `output` until it quits or hangs up.
+pub fn serve(input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+    let mut server = Server {
+        storage: None,
+        files: HashMap::new(),
+        next_handle: 0,
+    };
+    while let Some(request) = read_frame(input)? {
+        let is_quit = request.first() == Some(&QUIT);
+        let response = match server.handle(&request) {
+            Ok(response) => response,
+            Err(err)     => error_response(&err)
+        };
+        write_frame(output, &response.0)?;
+        if is_quit {
+            break
+        }
+    }
+    Ok(())
+}
+
+struct Server {
+    storage:     Option<LocalStorage>,
+    files:       HashMap<u64, Box<dyn Compressor>>,
+    next_handle: u64,
+}
+
+impl Server {
+    fn handle(&mut self, request: &[u8]) -> Result<FrameWriter, Error> {
+        let mut reader = FrameReader::new(request);
+        let tag = reader.u8()?;
+        let ok = FrameWriter::new(STATUS_OK);
+        if HELLO == tag {
+            let version = reader.u32()?;
+            if VERSION != version {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("Protocol version {} is not supported, expected {}.", version, VERSION)))
+            }
+            let root = reader.path()?;
+            log::info!("Serving {:?}...", root);
+            self.storage = Some(LocalStorage::new(root));
+            return Ok(ok)
+        }
+        else if QUIT == tag {
+            return Ok(ok)
+        }
+        let storage = self.storage.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Expected a greeting first."))?;
+        match tag {
+            METADATA        => match storage.metadata(&checked_path(storage, &mut reader)?)? {
+                Some(meta) => Ok(ok.u8(1).meta(&meta)),
+                None       => Ok(ok.u8(0))
+            },
+            READ_DIR        => {
+                let paths = storage.read_dir(&checked_path(storage, &mut reader)?)?;
+                Ok(paths.iter().fold(ok.u32(paths.len() as u32), |res, path| res.path(path)))
+            },
+            READ            => {
+                let mut file = storage.open(&checked_path(storage, &mut reader)?)?;
+                file.seek(SeekFrom::Start(reader.u64()?))?;
+                let len = std::cmp::min(reader.u32()? as usize, MAX_DATA);
+                let mut data = Vec::with_capacity(len);
+                file.take(len as u64).read_to_end(&mut data)?;
+                Ok(ok.bytes(&data))
+            },
+            READ_LINK       => Ok(ok.path(&storage.read_link(&checked_path(storage, &mut reader)?)?)),
+            CREATE_DIR      => storage.create_dir(&checked_path(storage, &mut reader)?).map(|()| ok),
+            CREATE_DIR_ALL  => storage.create_dir_all(&checked_path(storage, &mut reader)?).map(|()| ok),
+            CREATE          => {
+                let file = storage.create(&checked_path(storage, &mut reader)?)?;
+                let handle = self.next_handle;
+                self.next_handle += 1;
+                self.files.insert(handle, file);
+                Ok(ok.u64(handle))
+            },
+            APPEND          => {
+                let file = self.files.get_mut(&reader.u64()?)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Unknown file handle."))?;
+                file.writer().write_all(reader.bytes()?)?;
+                Ok(ok)
+            },
+            CLOSE           => {
+                let mut file = self.files.remove(&reader.u64()?)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Unknown file handle."))?;
+                file.close().map(|()| ok)
+            },
+            SYMLINK         => {
+                // The target is only the contents of the link, so it may point anywhere.
+                let target = reader.path()?;
+                storage.symlink(&target, &checked_path(storage, &mut reader)?).map(|()| ok)
+            },
+            HARD_LINK       => {
+                let original = checked_path(storage, &mut reader)?;
+                storage.hard_link(&original, &checked_path(storage, &mut reader)?).map(|()| ok)
+            },
+            SET_PERMISSIONS => {
+                let path = checked_path(storage, &mut reader)?;
+                storage.set_permissions(&path, reader.u32()?).map(|()| ok)
+            },
+            SET_METADATA    => {
+                let path = checked_path(storage, &mut reader)?;
+                storage.set_metadata(&path, &reader.meta()?).map(|()| ok)
+            },
+            RENAME          => {
+                let from = checked_path(storage, &mut reader)?;
+                storage.rename(&from, &checked_path(storage, &mut reader)?).map(|()| ok)
+            },
+            REMOVE_FILE     => storage.remove_file(&checked_path(storage, &mut reader)?).map(|()| ok),
+            REMOVE_DIR      => storage.remove_dir(&checked_path(storage, &mut reader)?).map(|()| ok),
+            REMOVE_DIR_ALL  => storage.remove_dir_all(&checked_path(storage, &mut reader)?).map(|()| ok),
+            _               => Err(Error::new(ErrorKind::InvalidInput, format!("Unknown request {}.", tag)))
+        }
+    }
+}
+
+/// Reads a path, refusing anything outside of the destination.
+fn checked_path(storage: &LocalStorage, reader: &mut FrameReader) -> Result<PathBuf, Error> {
+    let path = reader.path()?;
+    if !path.starts_with(storage.root()) || path.components().any(|component| Component::ParentDir == component) {
+        return Err(Error::new(ErrorKind::PermissionDenied, format!("{:?} is outside of {:?}.", path, storage.root())))
+    }
+    Ok(path)
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::sync::Arc;
+use std::io::Error;
+use crate::backup::{self,delta,incremental};
+use crate::backup::chunk::{self,ChunkStore};
+use crate::catalog::BackupCatalog;
+use crate::config::{KeepLimit,ParseError};
+use crate::storage::{ObjectKind,ObjectMeta,Storage};
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Another destination every new snapshot is copied to, so a second copy exists without backing up
+/// the source twice.  It keeps its own catalog and expires snapshots by its own keep limits.
+#[derive(Debug)]
+pub struct Replica {
+    /// Names the replica in the primary catalog.
+    pub name:       std::string::String,
+    pub storage:    Arc<dyn Storage>,
+    pub keep_limit: Vec<KeepLimit>
+}
+
+/// What `Replica::replicate` did.
+#[derive(Debug,Default)]
+pub struct ReplicaStats {
+    /// Snapshots copied, including any older ones the new snapshot depends on.
+    pub snapshots:    u64,
+    pub linked:       u64,
+    pub copied:       u64,
+    pub copied_bytes: u64
+}
+
+impl Replica {
+    /// Copies `snapshot` out of `source`, whose catalog is `source_catalog`, then removes whatever the
+    /// replica's keep limits no longer need.  Files unchanged since the replica's most recent
+    /// snapshot are hard linked to it.  Snapshots that `snapshot` is based on are copied first if
+    /// the replica does not have them yet.
+    pub fn replicate(&self, source: &Arc<dyn Storage>, source_catalog: &BackupCatalog, snapshot: &Path,
+                     name_format: &str) -> Result<ReplicaStats, ParseError> {
+        let io_err = |path: &Path| { let path = path.to_path_buf(); move |err| ParseError::IoError(path, err) };
+        let storage = self.storage.as_ref();
+        let mut catalog = BackupCatalog::load(storage, name_format)?;
+        let mut stats = ReplicaStats::default();
+        self.copy_snapshot(source, source_catalog, snapshot, &mut catalog, &mut stats)
+            .map_err(io_err(snapshot))?;
+        catalog.clean(storage, &self.keep_limit).map_err(io_err(storage.root()))?;
+        let chunk_store = ChunkStore::new(self.storage.clone(), storage.root().join(chunk::STORE_DIR));
+        chunk_store.collect_garbage(&catalog.paths()).map_err(io_err(storage.root()))?;
+        catalog.save(storage).map_err(io_err(storage.root()))?;
+        Ok(stats)
+    }
+
+    fn copy_snapshot(&self, source: &Arc<dyn Storage>, source_catalog: &BackupCatalog, snapshot: &Path,
+                     catalog: &mut BackupCatalog, stats: &mut ReplicaStats) -> Result<(), Error> {
+        let storage = self.storage.as_ref();
+        let dst = self.replica_path(source.as_ref(), snapshot);
+        if catalog.timestamp(&dst).is_some() {
+            log::debug!("{:?} already has {:?}.", self.name, snapshot);
+            return Ok(())
+        }
+        for base in backup::dependencies(source.as_ref(), snapshot)? {
+            let base_snapshot = match base.strip_prefix(source.root()).ok().and_then(|path| path.components().next()) {
+                Some(name) => source.root().join(name),
+                None       => continue
+            };
+            if base_snapshot != snapshot {
+                self.copy_snapshot(source, source_catalog, &base_snapshot, catalog, stats)?;
+            }
+        }
+        if storage.exists(&dst)? {
+            log::warn!("Removing incomplete copy {:?} from an earlier run...", dst);
+            storage.remove_dir_all(&dst)?;
+        }
+        let previous = catalog.most_recent().map(|path| path.to_path_buf());
+        log::info!("Replicating {:?} to {:?} against {:?}...", snapshot, dst, previous);
+        let copier = Copier {
+            source:   source.as_ref(),
+            replica:  self,
+            previous: previous.as_ref().map(|previous| (self.source_path(source.as_ref(), previous), previous.as_path())),
+            links:    HashMap::new()
+        };
+        copier.copy(snapshot, &dst, stats)?;
+        let source_chunks = ChunkStore::new(source.clone(), source.root().join(chunk::STORE_DIR));
+        let chunks = ChunkStore::new(self.storage.clone(), storage.root().join(chunk::STORE_DIR));
+        let copied_chunks = source_chunks.copy_to(snapshot, &chunks)?;
+        if 0 < copied_chunks {
+            log::info!("Copied {} chunks to {:?}.", copied_chunks, self.name);
+        }
+        let timestamp = source_catalog.timestamp(snapshot).unwrap_or_else(|| chrono::offset::Utc::now().timestamp());
+        catalog.insert(&dst, timestamp);
+        stats.snapshots += 1;
+        Ok(())
+    }
+
+    /// Where `path`, somewhere in `source`, is copied to.
+    fn replica_path(&self, source: &dyn Storage, path: &Path) -> PathBuf {
+        match path.strip_prefix(source.root()) {
+            Ok(relative) => self.storage.root().join(relative),
+            Err(_)       => path.to_path_buf()
+        }
+    }
+
+    /// Where `path`, somewhere in the replica, was copied from.
+    fn source_path(&self, source: &dyn Storage, path: &Path) -> PathBuf {
+        match path.strip_prefix(self.storage.root()) {
+            Ok(relative) => source.root().join(relative),
+            Err(_)       => path.to_path_buf()
+        }
+    }
+}
+
+// ----- Implementation --------------------------------------------------------
+
+struct Copier<'a> {
+    source:   &'a dyn Storage,
+    replica:  &'a Replica,
+    /// The replica's most recent snapshot, as it is named in the source and in the replica.
+    previous: Option<(PathBuf, &'a Path)>,
+    /// Copies of files with several links in the source, by device and inode.
+    links:    HashMap<(u64, u64), PathBuf>
+}
+
+impl <'a> Copier<'a> {
+    fn copy(mut self, src: &Path, dst: &Path, stats: &mut ReplicaStats) -> Result<(), Error> {
+        let root = src.to_path_buf();
+        self.copy_tree(&root, src, dst, stats)
+    }
+
+    fn copy_tree(&mut self, root: &Path, src: &Path, dst: &Path, stats: &mut ReplicaStats) -> Result<(), Error> {
+        let storage = self.replica.storage.as_ref();
+        let meta = match self.source.metadata(src)? {
+            Some(meta) => meta,
+            None       => return Ok(())
+        };
+        match meta.kind {
+            ObjectKind::Directory => {
+                storage.create_dir(dst)?;
+                for entry in self.source.read_dir(src)? {
+                    let name = entry.file_name().unwrap().to_os_string();
+                    self.copy_tree(root, &entry, &dst.join(name), stats)?;
+                }
+                // Written last, since adding entries changes it.
+                storage.set_metadata(dst, &meta)?;
+            },
+            ObjectKind::Symlink   => storage.symlink(&self.source.read_link(src)?, dst)?,
+            ObjectKind::File      => {
+                let relative = src.strip_prefix(root).unwrap();
+                if let Some(original) = self.find_link(relative, &meta)? {
+                    log::debug!("{:?} is unchanged.  Linking to {:?}...", src, original);
+                    storage.hard_link(&original, dst)?;
+                    stats.linked += 1;
+                    return Ok(())
+                }
+                let rebase = |base: &Path| self.replica.replica_path(self.source, base);
+                if incremental::is_listing(src) {
+                    incremental::copy_rebased(self.source, src, storage, dst, &rebase)?;
+                }
+                else if delta::is_delta(self.source, src) {
+                    delta::copy_rebased(self.source, src, storage, dst, &rebase)?;
+                }
+                else {
+                    let mut output = storage.create(dst)?;
+                    std::io::copy(&mut self.source.open(src)?, output.writer())?;
+                    output.close()?;
+                }
+                storage.set_metadata(dst, &meta)?;
+                stats.copied += 1;
+                stats.copied_bytes += meta.len;
+                if 1 < meta.nlink {
+                    self.links.insert((meta.dev, meta.ino), dst.to_path_buf());
+                }
+            },
+            ObjectKind::Special   => log::warn!("Skipping special file {:?}.", src)
+        }
+        Ok(())
+    }
+
+    /// A copy of `relative` already in the replica, either earlier in this snapshot or in the
+    /// previous one, which the new copy can be a link to.
+    fn find_link(&self, relative: &Path, meta: &ObjectMeta) -> Result<Option<PathBuf>, Error> {
+        if 1 < meta.nlink {
+            if let Some(original) = self.links.get(&(meta.dev, meta.ino)) {
+                return Ok(Some(original.clone()))
+            }
+        }
+        let (previous_src, previous_dst) = match &self.previous {
+            Some(previous) => previous,
+            None           => return Ok(None)
+        };
+        let other_dst = previous_dst.join(relative);
+        let other_meta = match self.replica.storage.metadata(&other_dst)? {
+            Some(other_meta) if other_meta.is_file() => other_meta,
+            _                                        => return Ok(None)
+        };
+        // Linked snapshots share inodes with the snapshot the replica copied last time.
+        let is_same_inode = 1 < meta.nlink && self.source.metadata(&previous_src.join(relative))?
+            .is_some_and(|other_src| other_src.dev == meta.dev && other_src.ino == meta.ino);
+        let is_same_file = other_meta.len == meta.len && meta.modified.is_some() &&
+            other_meta.modified == meta.modified && other_meta.mode & 0o7777 == meta.mode & 0o7777;
+        // Deltas and listings name their base, so their copies differ from the originals.
+        let is_rebased = incremental::is_listing(relative) || delta::is_delta(self.source, &previous_src.join(relative));
+        if (is_same_inode || is_same_file) && !is_rebased {
+            return Ok(Some(other_dst))
+        }
+        Ok(None)
+    }
+}
@@ -0,0 +1,293 @@
+use std::path::{Path,PathBuf};
+use std::fs;
+use std::io::{Read,Seek,Error,ErrorKind};
+use std::time::SystemTime;
+use crate::compression::Compressor;
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Where snapshots, the catalog and everything shared between snapshots are kept.  Paths are
+/// given in full, underneath `root`, the way they are written into the catalog.
+///
+/// Everything that reads or writes `destination_dir` goes through this trait, so another kind of
+/// destination only needs another implementation.
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// Directory holding the catalog and every snapshot.
+    fn root(&self) -> &Path;
+
+    /// Metadata of `path` itself, not what it links to, or `None` if there is nothing there.
+    fn metadata(&self, path: &Path) -> Result<Option<ObjectMeta>, Error>;
+    /// Full paths of everything in the directory `path`, in no particular order.
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, Error>;
+    fn open(&self, path: &Path) -> Result<Box<dyn Object>, Error>;
+    fn read_link(&self, path: &Path) -> Result<PathBuf, Error>;
+
+    /// Creates a directory, such as a new snapshot.  Fails if it already exists.
+    fn create_dir(&self, path: &Path) -> Result<(), Error>;
+    /// Creates `path` and any missing parents.
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error>;
+    /// Creates or replaces `path`.  It is only complete once the writer is closed.
+    fn create(&self, path: &Path) -> Result<Box<dyn Compressor>, Error>;
+    fn symlink(&self, target: &Path, link: &Path) -> Result<(), Error>;
+    fn hard_link(&self, original: &Path, link: &Path) -> Result<(), Error>;
+    fn set_permissions(&self, path: &Path, mode: u32) -> Result<(), Error>;
+    /// Applies the permissions, modification time and, where allowed, owner in `meta`.
+    fn set_metadata(&self, path: &Path, meta: &ObjectMeta) -> Result<(), Error>;
+
+    /// Replaces `to` with `from` in one step.
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error>;
+    fn remove_file(&self, path: &Path) -> Result<(), Error>;
+    /// Removes the empty directory `path`.
+    fn remove_dir(&self, path: &Path) -> Result<(), Error>;
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error>;
+
+    fn exists(&self, path: &Path) -> Result<bool, Error> {
+        Ok(self.metadata(path)?.is_some())
+    }
+
+    fn read_to_string(&self, path: &Path) -> Result<std::string::String, Error> {
+        let mut string = std::string::String::new();
+        self.open(path)?.read_to_string(&mut string)?;
+        Ok(string)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let mut file = self.create(path)?;
+        std::io::Write::write_all(file.writer(), data)?;
+        file.close()
+    }
+}
+
+/// A stored file opened for reading.
+pub trait Object: Read + Seek + Send {}
+
+impl <T: Read + Seek + Send> Object for T {}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum ObjectKind {
+    File,
+    Directory,
+    Symlink,
+    /// Devices, FIFOs and sockets.
+    Special
+}
+
+/// What a `Storage` knows about a path.  Backends without hard links or owners report every
+/// object as its own inode with a single link.
+#[derive(Debug,Clone)]
+pub struct ObjectMeta {
+    pub kind:     ObjectKind,
+    pub len:      u64,
+    pub modified: Option<SystemTime>,
+    pub mode:     u32,
+    pub uid:      u32,
+    pub gid:      u32,
+    pub dev:      u64,
+    pub ino:      u64,
+    pub nlink:    u64
+}
+
+impl ObjectMeta {
+    pub fn is_file(&self) -> bool { ObjectKind::File == self.kind }
+    pub fn is_dir(&self) -> bool { ObjectKind::Directory == self.kind }
+    pub fn is_symlink(&self) -> bool { ObjectKind::Symlink == self.kind }
+}
+
+impl From<&fs::Metadata> for ObjectMeta {
+    fn from(meta: &fs::Metadata) -> ObjectMeta {
+        use std::os::unix::fs::MetadataExt;
+        let file_type = meta.file_type();
+        ObjectMeta {
+            kind:     if file_type.is_symlink() { ObjectKind::Symlink }
+                      else if file_type.is_dir() { ObjectKind::Directory }
+                      else if file_type.is_file() { ObjectKind::File }
+                      else { ObjectKind::Special },
+            len:      meta.len(),
+            modified: meta.modified().ok(),
+            mode:     meta.mode(),
+            uid:      meta.uid(),
+            gid:      meta.gid(),
+            dev:      meta.dev(),
+            ino:      meta.ino(),
+            nlink:    meta.nlink()
+        }
+    }
+}
+
+/// Keeps everything in a mounted directory.
+#[derive(Debug)]
+pub struct LocalStorage {
+    root: PathBuf
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> LocalStorage {
+        LocalStorage {
+            root
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn root(&self) -> &Path { &self.root }
+
+    fn metadata(&self, path: &Path) -> Result<Option<ObjectMeta>, Error> {
+        match symlink_metadata(path) {
+            Ok(meta)                                      => Ok(Some(ObjectMeta::from(&meta))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err)                                      => Err(err)
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, Error> {
+        fs::read_dir(path)?.map(|entry_res| entry_res.map(|entry| entry.path())).collect()
+    }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Object>, Error> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf, Error> { fs::read_link(path) }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> { fs::create_dir(path) }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> { fs::create_dir_all(path) }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn Compressor>, Error> {
+        Ok(Box::new(fs::File::create(path)?))
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<(), Error> { symlink::symlink_auto(target, link) }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> Result<(), Error> { fs::hard_link(original, link) }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    fn set_metadata(&self, path: &Path, meta: &ObjectMeta) -> Result<(), Error> { set_metadata(path, meta) }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> { fs::rename(from, to) }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> { fs::remove_file(path) }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), Error> { fs::remove_dir(path) }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error> { fs::remove_dir_all(path) }
+}
+
+/// Reads from another storage but only logs what would be written to it, for a dry run.
+#[derive(Debug)]
+pub struct DryRunStorage {
+    inner: std::sync::Arc<dyn Storage>
+}
+
+impl DryRunStorage {
+    pub fn new(inner: std::sync::Arc<dyn Storage>) -> DryRunStorage {
+        DryRunStorage {
+            inner
+        }
+    }
+}
+
+impl Storage for DryRunStorage {
+    fn root(&self) -> &Path { self.inner.root() }
+
+    fn metadata(&self, path: &Path) -> Result<Option<ObjectMeta>, Error> { self.inner.metadata(path) }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>, Error> { self.inner.read_dir(path) }
+
+    fn open(&self, path: &Path) -> Result<Box<dyn Object>, Error> { self.inner.open(path) }
+
+    fn read_link(&self, path: &Path) -> Result<PathBuf, Error> { self.inner.read_link(path) }
+
+    fn create_dir(&self, path: &Path) -> Result<(), Error> {
+        log::info!("Would create directory {:?}.", path);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        log::debug!("Would create directories {:?}.", path);
+        Ok(())
+    }
+
+    fn create(&self, path: &Path) -> Result<Box<dyn Compressor>, Error> {
+        log::info!("Would write {:?}.", path);
+        Ok(Box::new(std::io::sink()))
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> Result<(), Error> {
+        log::info!("Would link {:?} to {:?}.", link, target);
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> Result<(), Error> {
+        log::info!("Would hard link {:?} to {:?}.", link, original);
+        Ok(())
+    }
+
+    fn set_permissions(&self, _path: &Path, _mode: u32) -> Result<(), Error> { Ok(()) }
+
+    fn set_metadata(&self, _path: &Path, _meta: &ObjectMeta) -> Result<(), Error> { Ok(()) }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        log::debug!("Would rename {:?} to {:?}.", from, to);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> Result<(), Error> {
+        log::info!("Would remove {:?}.", path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> Result<(), Error> {
+        log::info!("Would remove {:?}.", path);
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> Result<(), Error> {
+        log::info!("Would remove {:?}.", path);
+        Ok(())
+    }
+}
+
+/// Applies `meta` to a local file, such as one being restored.
+pub fn set_metadata(path: &Path, meta: &ObjectMeta) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+    // Only root may give files away, so keep going as whoever is running the backup.
+    if let Err(err) = std::os::unix::fs::chown(path, Some(meta.uid), Some(meta.gid)) {
+        log::debug!("Could not change owner of {:?}: {:?}", path, err);
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(meta.mode & 0o7777))?;
+    match meta.modified {
+        Some(mtime) => filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime)),
+        None        => Ok(())
+    }
+}
+
+// ----- Temporary Utility Functions -------------------------------------------
+
+/* [tflucke] 2022-01-16: Rust uses statx to get path metadata, but fakechroot
+ * cannot wrap it yet.  Until that is supported, we need to open the file
+ * to get any metadata during a test.
+ */
+#[cfg(debug_assertions)]
+#[inline]
+fn symlink_metadata(path: &Path) -> Result<fs::Metadata, Error> {
+    // Opening a link would follow it, so links are still read with statx.
+    if fs::read_link(path).is_ok() {
+        return path.symlink_metadata()
+    }
+    match fs::OpenOptions::new().read(true).open(path) {
+        Ok(file)                                              => file.metadata(),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => path.symlink_metadata(),
+        Err(err)                                              => Err(err)
+    }
+}
+
+#[cfg(not(debug_assertions))]
+#[inline]
+fn symlink_metadata(path: &Path) -> Result<fs::Metadata, Error> {
+    path.symlink_metadata()
+}
@@ -0,0 +1,161 @@
+use std::path::{Path,PathBuf};
+use std::io::{Read,Write,Error,ErrorKind};
+use std::sync::Arc;
+use crate::compression::Compressor;
+use crate::storage::{Object,Storage};
+
+/// Suffix of the file holding the number of volumes an archive was split into.
+const COUNT_EXTENSION: &str = ".volumes";
+
+// ----- Public Data Structures ------------------------------------------------
+
+/// Splits an archive into numbered volumes of at most `volume_size` bytes each, named
+/// `<archive>.001`, `<archive>.002`, ...  Always writes at least the first volume.  Once closed,
+/// the number of volumes is written to `<archive>.volumes`.
+pub struct VolumeWriter {
+    storage:      Arc<dyn Storage>,
+    archive_path: PathBuf,
+    volume_size:  u64,
+    volume:       u32,
+    written:      u64,
+    output:       Box<dyn Compressor>
+}
+
+impl VolumeWriter {
+    pub fn new(storage: Arc<dyn Storage>, archive_path: &Path, volume_size: u64) -> Result<VolumeWriter, Error> {
+        if 0 == volume_size {
+            return Err(Error::new(ErrorKind::InvalidInput, "Volume size must be positive."))
+        }
+        let output = VolumeWriter::create_volume(storage.as_ref(), archive_path, 1)?;
+        Ok(VolumeWriter {
+            storage,
+            archive_path: archive_path.to_path_buf(),
+            volume_size,
+            volume:       1,
+            written:      0,
+            output
+        })
+    }
+
+    fn create_volume(storage: &dyn Storage, archive_path: &Path, volume: u32) -> Result<Box<dyn Compressor>, Error> {
+        let path = volume_path(archive_path, volume);
+        log::debug!("Creating volume {:?}...", path);
+        storage.create(&path)
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.written >= self.volume_size {
+            self.output.close()?;
+            self.volume += 1;
+            self.written = 0;
+            self.output = VolumeWriter::create_volume(self.storage.as_ref(), &self.archive_path, self.volume)?;
+        }
+        let len = buf.len().min((self.volume_size - self.written) as usize);
+        let written = self.output.writer().write(&buf[0..len])?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.output.writer().flush()
+    }
+}
+
+impl Compressor for VolumeWriter {
+    fn writer(&mut self) -> &mut dyn Write {
+        self
+    }
+    fn close(&mut self) -> Result<(), Error> {
+        self.output.close()?;
+        // The count is only known at the end, when the first volume is already written.
+        // Without it, a missing last volume would look like a shorter archive.
+        let mut count = self.storage.create(&count_path(&self.archive_path))?;
+        writeln!(count.writer(), "{}", self.volume)?;
+        count.close()
+    }
+}
+
+/// Path of the `volume`th volume of `archive_path`.
+pub fn volume_path(archive_path: &Path, volume: u32) -> PathBuf {
+    let mut name = archive_path.as_os_str().to_os_string();
+    name.push(format!(".{:03}", volume));
+    PathBuf::from(name)
+}
+
+/// Path of the file recording how many volumes `archive_path` was split into.
+pub fn count_path(archive_path: &Path) -> PathBuf {
+    let mut name = archive_path.as_os_str().to_os_string();
+    name.push(COUNT_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// The archive name of a volume count file name.
+pub fn split_count_name(name: &str) -> Option<&str> {
+    name.strip_suffix(COUNT_EXTENSION)
+}
+
+/// Splits a volume file name into its archive name and volume number.
+pub fn split_volume_name(name: &str) -> Option<(&str, u32)> {
+    let (archive_name, volume) = name.rsplit_once('.')?;
+    if volume.len() >= 3 && volume.chars().all(|c| c.is_ascii_digit()) {
+        volume.parse().ok().map(|volume| (archive_name, volume))
+    }
+    else {
+        None
+    }
+}
+
+/// Opens `archive_path`, or if it was split, every one of its volumes in order as one stream.  Fails
+/// if any volume is missing.
+pub fn open_archive(storage: &dyn Storage, archive_path: &Path) -> Result<Box<dyn Read>, Error> {
+    let count_path = count_path(archive_path);
+    if !storage.exists(&count_path)? {
+        if !storage.exists(archive_path)? && storage.exists(&volume_path(archive_path, 1))? {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("{:?} is missing, so the volumes of {:?} may be incomplete.",
+                                          count_path, archive_path)))
+        }
+        return Ok(Box::new(storage.open(archive_path)?))
+    }
+    let mut contents = std::string::String::new();
+    storage.open(&count_path)?.read_to_string(&mut contents)?;
+    let count = contents.trim().parse::<u32>()
+        .map_err(|err| Error::new(ErrorKind::InvalidData, format!("Bad volume count in {:?}: {}", count_path, err)))?;
+    let volumes = (1..=count).map(|volume| {
+        let path = volume_path(archive_path, volume);
+        if !storage.exists(&path)? {
+            return Err(Error::new(ErrorKind::NotFound, format!("Volume {:?} of {} is missing.", path, count)))
+        }
+        log::debug!("Found volume {:?}...", path);
+        storage.open(&path)
+    }).collect::<Result<Vec<Box<dyn Object>>, Error>>()?;
+    let mut volumes = volumes.into_iter();
+    Ok(Box::new(VolumeReader {
+        input:   volumes.next(),
+        volumes
+    }))
+}
+
+// ----- Volume Reading Implementation -----------------------------------------
+
+/// Reads the volumes of an archive one after another.
+struct VolumeReader {
+    input:   Option<Box<dyn Object>>,
+    volumes: std::vec::IntoIter<Box<dyn Object>>
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while let Some(input) = &mut self.input {
+            let len = input.read(buf)?;
+            if 0 < len || buf.is_empty() {
+                return Ok(len)
+            }
+            log::debug!("Continuing with the next volume...");
+            self.input = self.volumes.next();
+        }
+        Ok(0)
+    }
+}
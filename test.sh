@@ -38,9 +38,15 @@ for test in tests/*; do
         cp $tsnapshot_srcs/$app $test_dir/usr/local/bin/
     done
     # Run the test in a fake root environment with a fake timestamp
-    if faketime '2000-01-01 00:00:00' fakechroot chroot $test_dir /do-test.sh; then
+    faketime '2000-01-01 00:00:00' fakechroot chroot $test_dir /do-test.sh
+    status=$?
+    # A test exits with 77 when something it needs is not installed.
+    if [ $status -eq 0 ]; then
         echo "\e[1m\e[32m[Success]\e[0m"
         success="y"
+    elif [ $status -eq 77 ]; then
+        echo "\e[1m\e[33m[Skipped]\e[0m"
+        success="y"
     else
         echo "\e[1m\e[31m[Failure]\e[0m"
         echo "Saving generated files in '$test_dir'"
@@ -0,0 +1,14 @@
+#!/bin/sh
+
+# Several blocks' worth, so each thread gets one.
+seq 1 400000 > /home/tflucke/numbers.txt
+for algorithm in gzip bzip2 zstd; do
+    mkdir -p /mnt/backup/$algorithm /mnt/decoded/$algorithm
+    tsnapshot --destination /mnt/backup/$algorithm --set "root_dir_config.algorithm=\"$algorithm\"" \
+              /etc/blockcompressConfig.json || exit 1
+    # Blocks compressed apart still make one stream the usual tools can decode.
+    archive=$(echo /mnt/backup/$algorithm/*/tsnapshot-home.tar.*)
+    $algorithm -dc "$archive" | tar -xf - -C /mnt/decoded/$algorithm || exit 1
+    diff -r /home /mnt/decoded/$algorithm/home || exit 1
+done
+rm /home/tflucke/numbers.txt
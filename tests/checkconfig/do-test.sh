@@ -0,0 +1,23 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/
+# Likely mistakes are warned about without failing.
+tsnapshot check-config /etc/checkconfigConfig.json > /mnt/log.txt || exit 1
+grep -q '^warning: keep_limit: unknown property, which is ignored.  Did you mean "keep limit"?' /mnt/log.txt || exit 1
+grep -q '^warning: root_dir_config.subconfigs\[0\].filters\[1\]: can never match' /mnt/log.txt || exit 1
+grep -q '^warning: root_dir_config.subconfigs\[0\].filters\[2\]: can never match' /mnt/log.txt || exit 1
+grep -q '^warning: root_dir_config.subconfigs\[1\]: is inside subconfigs\[0\]' /mnt/log.txt || exit 1
+grep -q 'filters\[0\]' /mnt/log.txt && exit 1
+# Errors name the JSON path of the bad value.
+tsnapshot check-config --set 'root_dir_config.subconfigs.0.filters.1.pattern=7' /etc/checkconfigConfig.json > /mnt/log.txt && exit 1
+grep -q '^error: .*checkconfigConfig.json: root_dir_config.subconfigs\[0\].filters\[1\].pattern: expected a string' /mnt/log.txt || exit 1
+tsnapshot check-config --set 'root_dir_config.subconfigs.1.algorithm=lzma' /etc/checkconfigConfig.json > /mnt/log.txt && exit 1
+grep -q '^error: the command line: root_dir_config.subconfigs\[1\].algorithm: unknown option "lzma"' /mnt/log.txt || exit 1
+# Checking neither starts the destination command nor needs a disk of the pool to be plugged in.
+tsnapshot check-config /etc/checkconfigRemoteConfig.json > /mnt/log.txt || exit 1
+[ ! -e /mnt/started ] || exit 1
+tsnapshot check-config /etc/checkconfigPoolConfig.json > /mnt/log.txt || exit 1
+tsnapshot /etc/checkconfigPoolConfig.json && exit 1
+# Nothing was backed up.
+[ -z "$(ls /mnt/backup)" ]
+exit $?
@@ -0,0 +1,12 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+# Special files are skipped rather than failing the backup.
+mkfifo /home/tflucke/fifo
+tsnapshot /etc/chunkedConfig.json || exit 1
+rm /home/tflucke/fifo
+# File contents belong in the shared chunk store, not the snapshot.
+[ $(find /mnt/backup/chunks -type f | wc -l) -gt 0 ] || exit 1
+tsnapshot-restore /etc/chunkedConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,29 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+# A dry run writes nothing.
+tsnapshot --dry-run --destination /mnt/backup /etc/cliConfig.json > /mnt/log.txt || exit 1
+grep -q 'Would write' /mnt/log.txt || exit 1
+[ -z "$(ls -A /mnt/backup)" ] || exit 1
+# Properties which do not exist cannot be overridden.
+tsnapshot --set root_dir_config.subconfigs.3=1 /etc/cliConfig.json && exit 1
+tsnapshot -q --destination /mnt/backup --name-format 'snap-%s' --set root_dir_config.space_mode=linked \
+          /etc/cliConfig.json || exit 1
+[ ! -e /mnt/elsewhere ] || exit 1
+snapshot=$(basename /mnt/backup/snap-*)
+[ -e "/mnt/backup/$snapshot/home/tflucke/documents/hello.txt" ] || exit 1
+tsnapshot-restore --dry-run --destination /mnt/backup /etc/cliConfig.json /mnt/restore "$snapshot" || exit 1
+[ -z "$(ls -A /mnt/restore)" ] || exit 1
+tsnapshot-restore -v --destination /mnt/backup /etc/cliConfig.json /mnt/restore "$snapshot" || exit 1
+diff -r /home /mnt/restore/home || exit 1
+# A dry run of a pulled source neither creates nor updates its mirror.
+mkdir -p /mnt/pulled/
+tsnapshot --dry-run /etc/cliPullConfig.json > /mnt/log.txt || exit 1
+[ ! -e /mnt/cache ] || exit 1
+tsnapshot -q /etc/cliPullConfig.json || exit 1
+cp /mnt/cache/home/tflucke/documents/hello.txt /mnt/hello.txt
+echo "Hello again" >> /home/tflucke/documents/hello.txt
+tsnapshot --dry-run /etc/cliPullConfig.json > /mnt/log.txt || exit 1
+grep -qF 'Would fetch "home/tflucke/documents/hello.txt"' /mnt/log.txt || exit 1
+cmp /mnt/cache/home/tflucke/documents/hello.txt /mnt/hello.txt
+exit $?
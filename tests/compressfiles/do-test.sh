@@ -0,0 +1,21 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+# Zip archives rather than compresses, so it cannot hold single files.
+tsnapshot check-config --set 'root_dir_config.algorithm="zip"' /etc/compressfilesConfig.json > /mnt/log.txt && exit 1
+grep -q 'root_dir_config.algorithm: compress_files cannot compress single files with zip' /mnt/log.txt || exit 1
+chmod 640 /home/tflucke/documents/hello.txt
+touch -d '2001-02-03 04:05:06' /home/tflucke/documents/hello.txt /home/tflucke/images
+# Special files are skipped rather than failing the backup.
+mkfifo /home/tflucke/fifo
+tsnapshot /etc/compressfilesConfig.json || exit 1
+rm /home/tflucke/fifo
+snapshot=$(echo /mnt/backup/*/home)
+[ -e "$snapshot/tflucke/documents/hello.txt.gz" ] || exit 1
+[ "$(gzip -dc "$snapshot/tflucke/documents/hello.txt.gz")" = "$(cat /home/tflucke/documents/hello.txt)" ] || exit 1
+[ ! -e "$snapshot/tflucke/fifo" ] || exit 1
+tsnapshot-restore /etc/compressfilesConfig.json /mnt/restore || exit 1
+diff -r /home /mnt/restore/home || exit 1
+for file in tflucke/documents/hello.txt tflucke/images tflucke/images/dog.jpg; do
+    [ "$(stat -c '%a %Y' /home/$file)" = "$(stat -c '%a %Y' "/mnt/restore/home/$file")" ] || exit 1
+done
@@ -0,0 +1,12 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot --stats /etc/dedupConfig.json > /mnt/log.txt || exit 1
+grep -q 'Deduplicated "home": 1 duplicate files linked' /mnt/log.txt || exit 1
+# Both copies of the picture share one file in the backup.
+if [ "$(stat -c %h /mnt/backup/*/home/tflucke/pets/dog.jpg)" != 2 ]; then
+    exit 1
+fi
+tsnapshot-restore /etc/dedupConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,16 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/deltaConfig.json
+# Backup names only have a resolution of one second.
+sleep 1
+printf 'A few new bytes' | dd of=/home/tflucke/images/beemovie.pdf bs=1 seek=1000 conv=notrunc
+tsnapshot --stats /etc/deltaConfig.json > /mnt/log.txt || exit 1
+grep -q 'Backed up "home": .* 1 deltas' /mnt/log.txt || exit 1
+# Only the changed file is stored as a delta.
+if [ ! -f /mnt/backup/*/home/tflucke/images/beemovie.pdf.tsnapshot-delta ]; then
+    exit 1
+fi
+tsnapshot-restore /etc/deltaConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
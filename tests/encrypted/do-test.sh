@@ -0,0 +1,33 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/encryptedConfig.json
+# Only the encrypted archive may be written.
+for archive in /mnt/backup/*/tsnapshot-home.tar.bz2.age; do
+    [ "$(head -c 21 $archive)" = "age-encryption.org/v1" ] || exit 1
+done
+echo /etc/backup-key.txt | tsnapshot-restore /etc/encryptedConfig.json /mnt/restore
+diff -r /home /mnt/restore/home || exit 1
+# Restores can run unattended with the key file on the command line.
+mkdir -p /mnt/restore-key-file/
+tsnapshot-restore --key-file /etc/backup-key.txt /etc/encryptedConfig.json /mnt/restore-key-file < /dev/null || exit 1
+diff -r /home /mnt/restore-key-file/home || exit 1
+# The listings of incremental archives name every file, so they are encrypted too.
+mkdir -p /mnt/incremental/ /mnt/restore-incremental/
+tsnapshot --set 'root_dir_config.encryption={"recipients_file": "/etc/backup-recipients.txt"}' \
+    /etc/encryptedIncrementalConfig.json > /mnt/log.txt 2>&1 && exit 1
+grep -q 'identity_file' /mnt/log.txt || exit 1
+tsnapshot /etc/encryptedIncrementalConfig.json || exit 1
+# Backup names only have a resolution of one second.
+sleep 1
+echo "Appended after the full backup." >> /home/tflucke/documents/hello.txt
+tsnapshot /etc/encryptedIncrementalConfig.json || exit 1
+for listing in /mnt/incremental/*/tsnapshot-home.snar; do
+    [ "$(head -n 1 $listing)" = "tsnapshot-snar 1 age" ] || exit 1
+    grep -q 'hello.txt' $listing && exit 1
+done
+[ "$(grep -c '^base /' /mnt/incremental/*/tsnapshot-home.snar | grep -c ':1$')" = 1 ] || exit 1
+# The config's identity_file is used without asking for a key.
+tsnapshot-restore /etc/encryptedIncrementalConfig.json /mnt/restore-incremental < /dev/null || exit 1
+diff -r /home /mnt/restore-incremental/home
+exit $?
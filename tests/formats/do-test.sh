@@ -0,0 +1,22 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+# Values are checked the same whichever format they are written in.
+tsnapshot /etc/formatsBadConfig.yaml > /mnt/log.txt && exit 1
+grep -q 'formatsBadConfig.yaml: root_dir_config.filters\[0\].min: expected a non-negative integer' /mnt/log.txt || exit 1
+tsnapshot /etc/formatsConfig.toml || exit 1
+sleep 1
+tsnapshot /etc/formatsConfig.toml || exit 1
+sleep 1
+tsnapshot /etc/formatsConfig.toml || exit 1
+[ ! -e /mnt/elsewhere ] || exit 1
+# The keep limit from the TOML file only left two snapshots.
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 2 ] || exit 1
+# The YAML filter and the JSON subconfig were both used.
+latest="$(ls -d /mnt/backup/2* | tail -n 1)"
+[ -e "$latest/home/tflucke/images/dog.jpg" ] || exit 1
+[ ! -e "$latest/home/tflucke/images/beemovie.pdf" ] || exit 1
+[ -e "$latest/home/tflucke/tsnapshot-documents.tar.gz" ] || exit 1
+tsnapshot-restore /etc/formatsConfig.toml /mnt/restore
+diff -r -x beemovie.pdf /home /mnt/restore/home
+exit $?
@@ -0,0 +1,28 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/hooksConfig.json || exit 1
+[ "$(head -n 1 /mnt/hooks.log)" = "pre $(ls /mnt/backup | grep -v catalog)" ] || exit 1
+[ "$(tail -n 1 /mnt/hooks.log)" = "post success" ] || exit 1
+# The dump was backed up, then cleaned up.
+[ -e /mnt/backup/*/home/tflucke/documents/dump.sql ] || exit 1
+[ ! -e /home/tflucke/documents/dump.sql ] || exit 1
+# A failed pre-hook aborts the backup, but post-hooks still run.
+sleep 1
+tsnapshot --set 'pre_exec.1={"command": "exit 3"}' /etc/hooksConfig.json > /mnt/log.txt && exit 1
+[ "$(tail -n 1 /mnt/hooks.log)" = "post failure" ] || exit 1
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 1 ] || exit 1
+# Hooks which are allowed to fail only warn, even when they time out.
+sleep 1
+tsnapshot --set 'pre_exec.1={"command": "sleep 5", "timeout": {"seconds": 1}, "on_failure": "continue"}' /etc/hooksConfig.json > /mnt/log.txt || exit 1
+grep -q 'timed out' /mnt/log.txt || exit 1
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 2 ] || exit 1
+# A failed post-hook fails the run, but the snapshot it follows is complete, so it is catalogued.
+sleep 1
+tsnapshot --set 'post_exec.1={"command": "exit 4"}' /etc/hooksConfig.json > /mnt/log.txt 2>&1 && exit 1
+grep -q 'Post-hook failed' /mnt/log.txt || exit 1
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 3 ] || exit 1
+grep -q "$(ls /mnt/backup | grep -v catalog | tail -n 1)" /mnt/backup/catalog.txt || exit 1
+tsnapshot-restore /etc/hooksConfig.json /mnt/restore
+diff -r -x dump.sql /home /mnt/restore/home
+exit $?
@@ -0,0 +1,28 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/ /mnt/expected/
+# Made here, since the repository's own git would ignore it.
+mkdir -p /home/tflucke/project/target/
+echo "binary" > /home/tflucke/project/target/main
+cp -r /home /mnt/expected/
+rm -r /mnt/expected/home/tflucke/documents/draft.tmp /mnt/expected/home/tflucke/documents/report.pdf \
+   /mnt/expected/home/tflucke/build /mnt/expected/home/tflucke/project/target
+tsnapshot /etc/ignoreConfig.json || exit 1
+tsnapshot-restore /etc/ignoreConfig.json /mnt/restore || exit 1
+diff -r /mnt/expected/home /mnt/restore/home || exit 1
+# Walking in parallel reads the same ignore files.
+sleep 1
+tsnapshot --set 'threads=4' /etc/ignoreConfig.json || exit 1
+latest=$(ls -d /mnt/backup/2* | tail -n 1)
+diff -r /mnt/expected/home "$latest/home" || exit 1
+# Ignore files above a subconfig still apply inside it, though its own filters are its own.
+mkdir -p /mnt/subconfig/
+rm -r /mnt/expected/home
+cp -r /home /mnt/expected/
+rm -r /mnt/expected/home/tflucke/documents/draft.tmp /mnt/expected/home/tflucke/build /mnt/expected/home/tflucke/project/target
+tsnapshot /etc/ignoreSubconfigConfig.json || exit 1
+diff -r /mnt/expected/home /mnt/subconfig/2*/home || exit 1
+# A bad pattern is reported against the filter it is in.
+tsnapshot --set 'root_dir_config.filters.0={"on": "glob", "pattern": "[z-a]"}' /etc/ignoreConfig.json > /mnt/log.txt 2>&1 && exit 1
+grep -q 'filters\[0\]: bad glob pattern' /mnt/log.txt
+exit $?
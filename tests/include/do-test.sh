@@ -0,0 +1,17 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/includeCycleConfig.json > /mnt/log.txt && exit 1
+grep -q 'extends itself' /mnt/log.txt || exit 1
+# Errors name the file the bad value came from.
+tsnapshot /etc/includeBadConfig.json > /mnt/log.txt && exit 1
+grep -q 'includeBadConfig.json: threads: expected a non-negative integer' /mnt/log.txt || exit 1
+tsnapshot /etc/includeConfig.json || exit 1
+[ ! -e /mnt/elsewhere ] || exit 1
+# The base filter was replaced and the included subconfig kept.
+[ -e /mnt/backup/*/home/tflucke/images/dog.jpg ] || exit 1
+[ ! -e /mnt/backup/*/home/tflucke/images/beemovie.pdf ] || exit 1
+[ -e /mnt/backup/*/home/tflucke/tsnapshot-documents.tar.gz ] || exit 1
+tsnapshot-restore /etc/includeConfig.json /mnt/restore
+diff -r -x beemovie.pdf /home /mnt/restore/home
+exit $?
@@ -0,0 +1,30 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/ /mnt/compressed/
+tsnapshot /etc/incompressibleConfig.json || exit 1
+tsnapshot-restore /etc/incompressibleConfig.json /mnt/restore || exit 1
+diff -r /home /mnt/restore/home || exit 1
+# The PDF shrinks by over 10kB under gzip, so storing it leaves the archive that much larger than
+# compressing everything does.
+tsnapshot --destination /mnt/compressed --set root_dir_config.store_incompressible=false \
+          /etc/incompressibleConfig.json || exit 1
+stored=$(cat /mnt/backup/*/tsnapshot-home.tar.gz | wc -c)
+compressed=$(cat /mnt/compressed/*/tsnapshot-home.tar.gz | wc -c)
+[ "$stored" -gt $((compressed + 10000)) ] || exit 1
+# bzip2 has no stored level, so the PDF goes in a zstd frame, with one thread and with several.
+zstd_frames() {
+    od -An -tx1 -v "$1" | tr -d ' \n' | grep -o 28b52ffd | wc -l
+}
+for threads in 1 4; do
+    bzip2="--set root_dir_config.algorithm=bzip2 --set root_dir_config.threads=$threads"
+    mkdir -p /mnt/bzip2-$threads/stored /mnt/bzip2-$threads/compressed /mnt/bzip2-$threads/restore
+    tsnapshot --destination /mnt/bzip2-$threads/stored $bzip2 /etc/incompressibleConfig.json || exit 1
+    tsnapshot-restore --destination /mnt/bzip2-$threads/stored $bzip2 \
+                      /etc/incompressibleConfig.json /mnt/bzip2-$threads/restore || exit 1
+    diff -r /home /mnt/bzip2-$threads/restore/home || exit 1
+    tsnapshot --destination /mnt/bzip2-$threads/compressed $bzip2 \
+              --set root_dir_config.store_incompressible=false /etc/incompressibleConfig.json || exit 1
+    [ "$(zstd_frames /mnt/bzip2-$threads/stored/*/tsnapshot-home.tar.bz2)" = 1 ] || exit 1
+    [ "$(zstd_frames /mnt/bzip2-$threads/compressed/*/tsnapshot-home.tar.bz2)" = 0 ] || exit 1
+done
+exit 0
@@ -0,0 +1,12 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/incrementalConfig.json
+# Backup names only have a resolution of one second.
+sleep 1
+echo "Appended after the full backup." >> /home/tflucke/documents/hello.txt
+rm /home/tflucke/documents/notes.txt
+tsnapshot /etc/incrementalConfig.json
+tsnapshot-restore /etc/incrementalConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
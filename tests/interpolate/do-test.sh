@@ -0,0 +1,19 @@
+#!/bin/sh
+
+# The hostname is known even when the shell did not export it.
+host="$(cat /proc/sys/kernel/hostname)"
+mkdir -p "/mnt/backup/$host/" /mnt/restore/
+# Variables without a default must be set.
+tsnapshot /etc/interpolateConfig.json > /mnt/log.txt && exit 1
+grep -q 'root_dir_config.filters\[0\].pattern: ${SKIPPED_EXTENSION} is not set' /mnt/log.txt || exit 1
+export SKIPPED_EXTENSION=pdf
+# Strings in arrays are filled in too.
+export IGNORE_FILE=.backupignore
+tsnapshot /etc/interpolateConfig.json || exit 1
+[ -e "/mnt/backup/$host/catalog.txt" ] || exit 1
+[ -e /mnt/backup/$host/*/home/tflucke/images/dog.jpg ] || exit 1
+[ ! -e /mnt/backup/$host/*/home/tflucke/images/beemovie.pdf ] || exit 1
+[ ! -e /mnt/backup/$host/*/home/tflucke/documents/hello.txt ] || exit 1
+tsnapshot-restore /etc/interpolateConfig.json /mnt/restore
+diff -r -x beemovie.pdf -x hello.txt /home /mnt/restore/home
+exit $?
@@ -0,0 +1,19 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+for run in 1 2 3; do
+    echo "Backup $run" > /home/tflucke/documents/hello.txt
+    tsnapshot /etc/keeplimitConfig.json || exit 1
+    # Backup names only have a resolution of one second.
+    sleep 1
+done
+# The newest backup is kept, along with one at least two seconds older.  The other is deleted.
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 2 ] || exit 1
+[ "$(ls -d /mnt/backup/*/home | wc -l)" = 2 ] || exit 1
+# The catalog lists the newest backup first, which is the one restored.
+newest=$(head -n 1 /mnt/backup/catalog.txt | cut -d ' ' -f 2)
+oldest=$(tail -n 1 /mnt/backup/catalog.txt | cut -d ' ' -f 2)
+[ "$newest" -gt "$oldest" ] || exit 1
+tsnapshot-restore /etc/keeplimitConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,36 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+# Special files are skipped rather than failing the backup.
+mkfifo /home/tflucke/fifo
+tsnapshot /etc/linkedConfig.json || exit 1
+rm /home/tflucke/fifo
+# Backup names only have a resolution of one second.
+sleep 1
+mv /home/tflucke/documents/hello.txt /hello.txt
+tsnapshot /etc/linkedConfig.json > /mnt/log.txt || exit 1
+# Statistics are not warnings, so they only show with --stats.
+grep -q 'WARN\|Backed up' /mnt/log.txt && exit 1
+sleep 1
+mv /hello.txt /home/tflucke/documents/hello.txt
+tsnapshot --stats /etc/linkedConfig.json > /mnt/log.txt || exit 1
+grep -q 'Backed up "home": 3 files hard linked, 0 files copied (0 bytes)' /mnt/log.txt || exit 1
+# The restored file is linked to the copy in the first backup.
+for copy in /mnt/backup/*/home/tflucke/documents/hello.txt; do
+    if [ "$(stat -c %h "$copy")" != 2 ]; then
+        exit 1
+    fi
+done
+# A link_history of 0 searches every snapshot, including those older than the last 3.
+sleep 1
+mv /home/tflucke/documents/hello.txt /hello.txt
+for run in 1 2 3; do
+    tsnapshot /etc/linkedConfig.json || exit 1
+    sleep 1
+done
+mv /hello.txt /home/tflucke/documents/hello.txt
+tsnapshot --stats --set root_dir_config.link_history=0 /etc/linkedConfig.json > /mnt/log.txt || exit 1
+grep -q 'Backed up "home": 3 files hard linked, 0 files copied (0 bytes)' /mnt/log.txt || exit 1
+tsnapshot-restore /etc/linkedConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
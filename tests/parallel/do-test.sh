@@ -0,0 +1,7 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/parallelConfig.json
+tsnapshot-restore /etc/parallelConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,31 @@
+#!/bin/sh
+
+mkdir -p /mnt/red/backup/ /mnt/blue/backup/ /mnt/shelf/ /mnt/restore/
+# Without either disk, there is nowhere to back up to.
+tsnapshot /etc/poolConfig.json && exit 1
+touch /mnt/red/backup/.tsnapshot-red
+tsnapshot /etc/poolConfig.json > /mnt/log.txt || exit 1
+grep -q 'Disk "blue" has never been backed up to' /mnt/log.txt || exit 1
+# Swap the disks.
+mv /mnt/red/backup /mnt/shelf/red
+mkdir -p /mnt/red/backup/
+touch /mnt/blue/backup/.tsnapshot-blue
+sleep 2
+tsnapshot /etc/poolConfig.json > /mnt/log.txt || exit 1
+grep -q 'Disk "red" was last backed up to' /mnt/log.txt || exit 1
+[ -e /mnt/blue/backup/catalog.txt ] || exit 1
+# Swap them back.  The red disk links against its own latest snapshot.
+rm -rf /mnt/blue/backup
+mkdir -p /mnt/blue/backup/
+rmdir /mnt/red/backup
+mv /mnt/shelf/red /mnt/red/backup
+tsnapshot /etc/poolConfig.json || exit 1
+[ "$(wc -l < /mnt/red/backup/catalog.txt)" = 2 ] || exit 1
+for copy in /mnt/red/backup/*/home/tflucke/images/beemovie.pdf; do
+    if [ "$(stat -c %h "$copy")" != 2 ]; then
+        exit 1
+    fi
+done
+tsnapshot-restore /etc/poolConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,27 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/cache/ /mnt/restore/
+# Entries the agent cannot read are skipped rather than failing the pull.
+mkdir /home/tflucke/secret && chmod 000 /home/tflucke/secret
+echo "Private" > /home/tflucke/secret.txt && chmod 000 /home/tflucke/secret.txt
+tsnapshot /etc/pullConfig.json 2> /mnt/err.txt || exit 1
+if [ "$(id -u)" != 0 ]; then
+    grep -q 'Agent skipped "home/tflucke/secret"' /mnt/err.txt || exit 1
+    grep -q 'Agent skipped "home/tflucke/secret.txt"' /mnt/err.txt || exit 1
+    [ ! -e /mnt/cache/home/tflucke/secret ] || exit 1
+fi
+chmod 755 /home/tflucke/secret && rmdir /home/tflucke/secret
+rm -f /home/tflucke/secret.txt
+# Filters are applied by the agent, so filtered files never reach the mirror.
+[ ! -e /mnt/cache/home/tflucke/images/dog.jpg ] || exit 1
+inode=$(stat -c %i /mnt/cache/home/tflucke/images/beemovie.pdf)
+# Backup names only have a resolution of one second.
+sleep 1
+echo "Hello again" >> /home/tflucke/documents/hello.txt
+tsnapshot /etc/pullConfig.json
+# Unchanged files are not sent again.
+[ "$(stat -c %i /mnt/cache/home/tflucke/images/beemovie.pdf)" = "$inode" ] || exit 1
+tsnapshot-restore /etc/pullConfig.json /mnt/restore
+# The filtered file is not in the backup either.
+diff -r -x dog.jpg /home /mnt/restore/home
+exit $?
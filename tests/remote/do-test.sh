@@ -0,0 +1,17 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/remoteConfig.json
+# Backup names only have a resolution of one second.
+sleep 1
+tsnapshot /etc/remoteConfig.json
+# The server writes the catalog and links unchanged files to the first backup.
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 2 ] || exit 1
+for copy in /mnt/backup/*/home/tflucke/documents/hello.txt; do
+    if [ "$(stat -c %h "$copy")" != 2 ]; then
+        exit 1
+    fi
+done
+tsnapshot-restore /etc/remoteConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
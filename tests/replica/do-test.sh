@@ -0,0 +1,22 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/replica/ /mnt/restore/
+tsnapshot /etc/replicaConfig.json || exit 1
+# Backup names only have a resolution of one second.
+sleep 1
+echo "Hello again" >> /home/tflucke/documents/hello.txt
+tsnapshot /etc/replicaConfig.json || exit 1
+sleep 1
+tsnapshot /etc/replicaConfig.json || exit 1
+# Every snapshot reached the replica, which only keeps two of them.
+[ "$(grep -c ' offsite=ok$' /mnt/backup/catalog.txt)" = 3 ] || exit 1
+[ "$(wc -l < /mnt/replica/catalog.txt)" = 2 ] || exit 1
+# Unchanged files are linked to the replica's previous snapshot.
+for copy in /mnt/replica/*/home/tflucke/images/beemovie.pdf; do
+    if [ "$(stat -c %h "$copy")" != 2 ]; then
+        exit 1
+    fi
+done
+tsnapshot-restore /etc/replicaRestoreConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,18 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/ /mnt/srv/data/reports/
+echo "Quarterly numbers" > /mnt/srv/data/reports/q1.txt
+echo "Scratch" > /mnt/srv/data/reports/q1.tmp
+# Roots may not overlap.
+tsnapshot --set 'root_dir_configs.2={"subpath": "home/tflucke"}' /etc/rootsConfig.json > /mnt/log.txt && exit 1
+grep -q 'overlap' /mnt/log.txt || exit 1
+tsnapshot /etc/rootsConfig.json || exit 1
+# Both roots are in the one snapshot and catalog entry.
+[ "$(wc -l < /mnt/backup/catalog.txt)" = 1 ] || exit 1
+tsnapshot-restore /etc/rootsConfig.json /mnt/restore || exit 1
+diff -r /home /mnt/restore/home || exit 1
+# Absolute roots are restored underneath the target.
+data="/mnt/restore$(realpath /mnt/srv/data)"
+rm /mnt/srv/data/reports/q1.tmp
+diff -r /mnt/srv/data "$data"
+exit $?
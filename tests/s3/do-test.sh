@@ -0,0 +1,33 @@
+#!/bin/sh
+
+# Runs against a local MinIO server, so it is reported as skipped, rather than passed, without one.
+if ! which minio mc > /dev/null 2> /dev/null; then
+    echo "minio and mc are required for this test.  Skipping."
+    exit 77
+fi
+mkdir -p /mnt/minio/ /mnt/restore/
+minio server /mnt/minio --address 127.0.0.1:9000 > /dev/null 2>&1 &
+minio_pid=$!
+trap 'kill $minio_pid' EXIT
+for i in $(seq 30); do
+    mc alias set local http://127.0.0.1:9000 minioadmin minioadmin > /dev/null 2>&1 && break
+    sleep 1
+done
+mc mb local/tsnapshot || exit 1
+# Large enough to be uploaded in parts.
+head -c 12582912 /dev/urandom > /home/tflucke/random.bin
+tsnapshot /etc/s3Config.json || exit 1
+# Backup names only have a resolution of one second.
+sleep 1
+echo "Hello again" >> /home/tflucke/documents/hello.txt
+tsnapshot /etc/s3Config.json || exit 1
+sleep 1
+tsnapshot /etc/s3Config.json || exit 1
+# The oldest backup is pruned from the bucket.
+[ "$(mc cat local/tsnapshot/backups/catalog.txt | wc -l)" = 2 ] || exit 1
+[ "$(mc ls local/tsnapshot/backups/ | grep -c /)" = 2 ] || exit 1
+# The catalog is rebuilt from a listing of the bucket when it is lost.
+mc rm local/tsnapshot/backups/catalog.txt || exit 1
+tsnapshot-restore /etc/s3Config.json /mnt/restore || exit 1
+diff -r /home /mnt/restore/home
+exit $?
@@ -1,7 +1,10 @@
 #!/bin/sh
 
 mkdir -p /mnt/backup/ /mnt/restore/
-tsnapshot /etc/simpleConfig.json
+# Special files are skipped rather than failing the backup.
+mkfifo /home/tflucke/fifo
+tsnapshot /etc/simpleConfig.json || exit 1
+rm /home/tflucke/fifo
 #find / -name catalog.txt
 tsnapshot-restore /etc/simpleConfig.json /mnt/restore
 diff -r /home /mnt/restore/home
@@ -0,0 +1,15 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/timestampConfig.json || exit 1
+# Backup names only have a resolution of one second.
+sleep 1
+# The size stays the same, so only the modification time shows the change.
+echo "Hello again" > /home/tflucke/documents/hello.txt
+tsnapshot /etc/timestampConfig.json || exit 1
+# The unchanged file is linked to the first backup, and the changed one is copied.
+[ "$(stat -c %h /mnt/backup/*/home/tflucke/images/dog.jpg | sort -u)" = 2 ] || exit 1
+[ "$(stat -c %h /mnt/backup/*/home/tflucke/documents/hello.txt | sort -u)" = 1 ] || exit 1
+tsnapshot-restore /etc/timestampConfig.json /mnt/restore
+diff -r /home /mnt/restore/home
+exit $?
@@ -0,0 +1,27 @@
+#!/bin/sh
+
+mkdir -p /mnt/backup/ /mnt/restore/
+tsnapshot /etc/volumesConfig.json || exit 1
+archive=$(echo /mnt/backup/*/tsnapshot-home.tar.gz.volumes)
+archive=${archive%.volumes}
+[ "$(cat "$archive.volumes")" = "4" ] || exit 1
+[ -e "$archive.004" ] || exit 1
+tsnapshot-restore /etc/volumesConfig.json /mnt/restore || exit 1
+diff -r /home /mnt/restore/home || exit 1
+# A missing volume fails the restore, whether or not it is the last one.
+mv "$archive.004" /mnt/volume.004
+rm -rf /mnt/restore/*
+tsnapshot-restore /etc/volumesConfig.json /mnt/restore && exit 1
+mv /mnt/volume.004 "$archive.004"
+mv "$archive.002" /mnt/volume.002
+rm -rf /mnt/restore/*
+tsnapshot-restore /etc/volumesConfig.json /mnt/restore && exit 1
+mv /mnt/volume.002 "$archive.002"
+# So does a missing count, which is written last.
+mv "$archive.volumes" /mnt/volumes
+rm -rf /mnt/restore/*
+tsnapshot-restore /etc/volumesConfig.json /mnt/restore && exit 1
+mv /mnt/volumes "$archive.volumes"
+tsnapshot-restore /etc/volumesConfig.json /mnt/restore || exit 1
+diff -r /home /mnt/restore/home
+exit $?